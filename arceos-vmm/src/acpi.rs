@@ -0,0 +1,324 @@
+//! Builds the ACPI tables describing the virtual platform in guest memory.
+//! (ref: ACPI Specification 6.5, Chapter 5)
+
+use alloc::vec::Vec;
+
+use axerrno::{ax_err, AxResult};
+use axvm::GuestPhysAddr;
+
+use crate::config::VmConfig;
+use crate::device_emu::{
+    PM1A_CNT_BLK, PM1A_EVT_BLK, PM_TMR_BLK, RESET_REG_PORT, RESET_VALUE, SCI_IRQ, SLP_TYP_S5,
+};
+
+/// The ACPI tables are placed in the BIOS read-only memory area, where the
+/// guest searches for the RSDP.
+const BIOS_AREA_END: GuestPhysAddr = 0x10_0000;
+
+const OEM_ID: &[u8; 6] = b"ARCEOS";
+const OEM_TABLE_ID: &[u8; 8] = b"AXVM    ";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: &[u8; 4] = b"AXVM";
+const CREATOR_REVISION: u32 = 1;
+
+/// Size of the ACPI 2.0+ RSDP, the ACPI 1.0 RSDP is the first 20 bytes.
+pub const RSDP_SIZE: usize = 36;
+pub const RSDP_V1_SIZE: usize = 20;
+
+const LOCAL_APIC_BASE: u32 = 0xfee0_0000;
+/// Vendor 8086, legacy replacement capable, 64-bit counter, 3 timers.
+const HPET_EVENT_TIMER_BLOCK_ID: u32 = 0x8086_a201;
+/// Minimum clock ticks for the periodic mode without lost interrupts.
+const HPET_MIN_CLOCK_TICK: u16 = 0x80;
+
+/// The platform described by the ACPI tables.
+pub struct AcpiPlatform {
+    pub num_vcpus: usize,
+    pub hpet_base: Option<GuestPhysAddr>,
+    /// PCI Express ECAM base, start and end bus numbers.
+    pub pci_ecam: Option<(GuestPhysAddr, u8, u8)>,
+}
+
+impl AcpiPlatform {
+    /// The platform described by the VM configuration.
+    pub fn from_config(config: &VmConfig) -> Self {
+        Self {
+            num_vcpus: config.vcpus,
+            hpet_base: config.platform.hpet,
+            pci_ecam: config.platform.pci_ecam,
+        }
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)))
+}
+
+/// Generic Address Structure of a system I/O register. (Section 5.2.3.2)
+fn io_gas(port: u16, bit_width: u8) -> [u8; 12] {
+    let mut gas = [0; 12];
+    gas[0] = 1; // system I/O space
+    gas[1] = bit_width;
+    gas[3] = match bit_width {
+        8 => 1,
+        16 => 2,
+        32 => 3,
+        _ => 4,
+    };
+    gas[4..].copy_from_slice(&(port as u64).to_le_bytes());
+    gas
+}
+
+/// Generic Address Structure of a system memory register.
+fn mmio_gas(addr: GuestPhysAddr, bit_width: u8) -> [u8; 12] {
+    let mut gas = [0; 12];
+    gas[1] = bit_width;
+    gas[4..].copy_from_slice(&(addr as u64).to_le_bytes());
+    gas
+}
+
+/// A table under construction, starting with the system description table header.
+struct Table(Vec<u8>);
+
+impl Table {
+    fn new(signature: &[u8; 4], revision: u8) -> Self {
+        let mut bytes = Vec::with_capacity(256);
+        bytes.extend_from_slice(signature);
+        bytes.extend_from_slice(&[0; 4]); // length
+        bytes.push(revision);
+        bytes.push(0); // checksum
+        bytes.extend_from_slice(OEM_ID);
+        bytes.extend_from_slice(OEM_TABLE_ID);
+        bytes.extend_from_slice(&OEM_REVISION.to_le_bytes());
+        bytes.extend_from_slice(CREATOR_ID);
+        bytes.extend_from_slice(&CREATOR_REVISION.to_le_bytes());
+        Self(bytes)
+    }
+
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    /// Fill in the length and checksum.
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.0.len() as u32;
+        self.0[4..8].copy_from_slice(&len.to_le_bytes());
+        self.0[9] = checksum(&self.0);
+        self.0
+    }
+}
+
+/// Lays out the tables in a contiguous guest memory area.
+struct TableArea {
+    base: GuestPhysAddr,
+    bytes: Vec<u8>,
+}
+
+impl TableArea {
+    /// Append a table aligned to `align`, returns its guest physical address.
+    fn add(&mut self, table: &[u8], align: usize) -> GuestPhysAddr {
+        let offset = self.bytes.len().next_multiple_of(align);
+        self.bytes.resize(offset, 0);
+        self.bytes.extend_from_slice(table);
+        self.base + offset
+    }
+}
+
+/// Definition block with the `\_S5` object for the soft off state.
+fn build_dsdt() -> Vec<u8> {
+    let mut dsdt = Table::new(b"DSDT", 2);
+    // Name (_S5, Package (0x04) { SLP_TYP_S5, SLP_TYP_S5, Zero, Zero })
+    dsdt.bytes(&[0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04])
+        .bytes(&[0x0a, SLP_TYP_S5, 0x0a, SLP_TYP_S5, 0x00, 0x00]);
+    dsdt.finish()
+}
+
+/// Firmware ACPI Control Structure. (Section 5.2.10)
+fn build_facs() -> Vec<u8> {
+    let mut facs = Vec::with_capacity(64);
+    facs.extend_from_slice(b"FACS");
+    facs.extend_from_slice(&64u32.to_le_bytes());
+    facs.resize(32, 0); // hardware signature, waking vectors, global lock, flags
+    facs.push(2); // version
+    facs.resize(64, 0);
+    facs
+}
+
+/// Fixed ACPI Description Table, pointing at the emulated PM registers. (Section 5.2.9)
+fn build_fadt(facs: GuestPhysAddr, dsdt: GuestPhysAddr) -> Vec<u8> {
+    const FLAG_WBINVD: u32 = 1;
+    const FLAG_PROC_C1: u32 = 1 << 2;
+    const FLAG_PWR_BUTTON: u32 = 1 << 4;
+    const FLAG_SLP_BUTTON: u32 = 1 << 5;
+    const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+    const BOOT_ARCH_LEGACY_DEVICES: u16 = 1;
+    const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+    const RTC_CENTURY_REG: u8 = 0x32;
+
+    let mut fadt = Table::new(b"FACP", 6);
+    fadt.u32(facs as u32)
+        .u32(dsdt as u32)
+        .u8(0) // reserved
+        .u8(0) // preferred PM profile: unspecified
+        .u16(SCI_IRQ as u16)
+        .u32(0) // SMI_CMD: ACPI mode is always enabled
+        .bytes(&[0; 4]) // ACPI_ENABLE, ACPI_DISABLE, S4BIOS_REQ, PSTATE_CNT
+        .u32(PM1A_EVT_BLK as u32)
+        .u32(0) // PM1b_EVT_BLK
+        .u32(PM1A_CNT_BLK as u32)
+        .u32(0) // PM1b_CNT_BLK
+        .u32(0) // PM2_CNT_BLK
+        .u32(PM_TMR_BLK as u32)
+        .u32(0) // GPE0_BLK
+        .u32(0) // GPE1_BLK
+        .bytes(&[4, 2, 0, 4]) // PM1_EVT_LEN, PM1_CNT_LEN, PM2_CNT_LEN, PM_TMR_LEN
+        .bytes(&[0; 4]) // GPE0_BLK_LEN, GPE1_BLK_LEN, GPE1_BASE, CST_CNT
+        .u16(101) // P_LVL2_LAT: C2 not supported
+        .u16(1001) // P_LVL3_LAT: C3 not supported
+        .u16(0) // FLUSH_SIZE
+        .u16(0) // FLUSH_STRIDE
+        .bytes(&[0, 0, 0, 0]) // DUTY_OFFSET, DUTY_WIDTH, DAY_ALRM, MON_ALRM
+        .u8(RTC_CENTURY_REG)
+        .u16(BOOT_ARCH_LEGACY_DEVICES | BOOT_ARCH_VGA_NOT_PRESENT)
+        .u8(0) // reserved
+        .u32(FLAG_WBINVD | FLAG_PROC_C1 | FLAG_PWR_BUTTON | FLAG_SLP_BUTTON | FLAG_RESET_REG_SUP)
+        .bytes(&io_gas(RESET_REG_PORT, 8))
+        .u8(RESET_VALUE)
+        .u16(0) // ARM_BOOT_ARCH
+        .u8(5) // FADT minor version
+        .u64(facs as u64)
+        .u64(dsdt as u64)
+        .bytes(&io_gas(PM1A_EVT_BLK, 32))
+        .bytes(&[0; 12]) // X_PM1b_EVT_BLK
+        .bytes(&io_gas(PM1A_CNT_BLK, 16))
+        .bytes(&[0; 12]) // X_PM1b_CNT_BLK
+        .bytes(&[0; 12]) // X_PM2_CNT_BLK
+        .bytes(&io_gas(PM_TMR_BLK, 32))
+        .bytes(&[0; 12]) // X_GPE0_BLK
+        .bytes(&[0; 12]) // X_GPE1_BLK
+        .bytes(&[0; 12]) // SLEEP_CONTROL_REG
+        .bytes(&[0; 12]) // SLEEP_STATUS_REG
+        .bytes(b"ARCEOSVM"); // hypervisor vendor identity
+    fadt.finish()
+}
+
+/// Multiple APIC Description Table. (Section 5.2.12)
+fn build_madt(platform: &AcpiPlatform) -> Vec<u8> {
+    const PCAT_COMPAT: u32 = 1;
+    const LAPIC_ENABLED: u32 = 1;
+
+    // There is no IOAPIC, the emulated devices interrupt through the virtual
+    // 8259 PICs, which are connected to LINT0 of the local APICs.
+    let mut madt = Table::new(b"APIC", 5);
+    madt.u32(LOCAL_APIC_BASE).u32(PCAT_COMPAT);
+    for id in 0..platform.num_vcpus {
+        // processor local APIC, the ACPI processor UID is the same as the APIC ID
+        madt.bytes(&[0, 8, id as u8, id as u8]).u32(LAPIC_ENABLED);
+    }
+    // LINT1 of all processors is connected to NMI
+    madt.bytes(&[4, 6, 0xff]).u16(0).u8(1);
+    madt.finish()
+}
+
+/// IA-PC High Precision Event Timer Table.
+fn build_hpet(base: GuestPhysAddr) -> Vec<u8> {
+    let mut hpet = Table::new(b"HPET", 1);
+    hpet.u32(HPET_EVENT_TIMER_BLOCK_ID)
+        .bytes(&mmio_gas(base, 64))
+        .u8(0) // HPET number
+        .u16(HPET_MIN_CLOCK_TICK)
+        .u8(0); // no page protection
+    hpet.finish()
+}
+
+/// PCI Express memory mapped configuration space base address description table.
+fn build_mcfg(ecam_base: GuestPhysAddr, start_bus: u8, end_bus: u8) -> Vec<u8> {
+    let mut mcfg = Table::new(b"MCFG", 1);
+    mcfg.u64(0) // reserved
+        .u64(ecam_base as u64)
+        .u16(0) // PCI segment group
+        .u8(start_bus)
+        .u8(end_bus)
+        .u32(0); // reserved
+    mcfg.finish()
+}
+
+/// Root System Description Pointer. (Section 5.2.5.3)
+fn build_rsdp(rsdt: GuestPhysAddr, xsdt: GuestPhysAddr) -> [u8; RSDP_SIZE] {
+    let mut rsdp = [0; RSDP_SIZE];
+    rsdp[0..8].copy_from_slice(b"RSD PTR ");
+    rsdp[9..15].copy_from_slice(OEM_ID);
+    rsdp[15] = 2; // revision
+    rsdp[16..20].copy_from_slice(&(rsdt as u32).to_le_bytes());
+    rsdp[20..24].copy_from_slice(&(RSDP_SIZE as u32).to_le_bytes());
+    rsdp[24..32].copy_from_slice(&(xsdt as u64).to_le_bytes());
+    rsdp[8] = checksum(&rsdp[..RSDP_V1_SIZE]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+/// Build the ACPI tables at `base` in the BIOS area, with the RSDP at the
+/// beginning. Returns the bytes to be copied to guest memory.
+pub fn build_acpi_tables(platform: &AcpiPlatform, base: GuestPhysAddr) -> AxResult<Vec<u8>> {
+    if base % 16 != 0 || !(0xe_0000..BIOS_AREA_END).contains(&base) {
+        return ax_err!(
+            InvalidInput,
+            "RSDP must be 16-byte aligned in the BIOS area"
+        );
+    }
+    let mut area = TableArea {
+        base,
+        bytes: Vec::new(),
+    };
+    area.add(&[0; RSDP_SIZE], 16); // reserved for the RSDP
+
+    let facs = area.add(&build_facs(), 64);
+    let dsdt = area.add(&build_dsdt(), 8);
+    let mut tables = Vec::new();
+    tables.push(area.add(&build_fadt(facs, dsdt), 8));
+    tables.push(area.add(&build_madt(platform), 8));
+    if let Some(hpet_base) = platform.hpet_base {
+        tables.push(area.add(&build_hpet(hpet_base), 8));
+    }
+    if let Some((ecam_base, start_bus, end_bus)) = platform.pci_ecam {
+        tables.push(area.add(&build_mcfg(ecam_base, start_bus, end_bus), 8));
+    }
+
+    let mut rsdt = Table::new(b"RSDT", 1);
+    let mut xsdt = Table::new(b"XSDT", 1);
+    for &table in &tables {
+        rsdt.u32(table as u32);
+        xsdt.u64(table as u64);
+    }
+    let rsdt = area.add(&rsdt.finish(), 8);
+    let xsdt = area.add(&xsdt.finish(), 8);
+    area.bytes[..RSDP_SIZE].copy_from_slice(&build_rsdp(rsdt, xsdt));
+
+    if base + area.bytes.len() > BIOS_AREA_END {
+        return ax_err!(NoMemory, "ACPI tables exceed the BIOS area");
+    }
+    debug!(
+        "ACPI tables built at [{:#x}, {:#x})",
+        base,
+        base + area.bytes.len()
+    );
+    Ok(area.bytes)
+}
@@ -0,0 +1,165 @@
+//! ELF32/ELF64 guest image loader, segments are placed at their physical
+//! addresses. (ref: System V ABI, Chapter 4 and 5)
+
+use alloc::{format, vec::Vec};
+
+use axerrno::{ax_err, AxResult};
+use axvm::GuestPhysAddr;
+
+use super::{read_u16, read_u32, read_u64, MemoryMapEntry};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_386: u16 = 3;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+
+/// A loadable segment described by a program header.
+#[derive(Debug)]
+struct Segment {
+    offset: usize,
+    vaddr: usize,
+    paddr: GuestPhysAddr,
+    file_size: usize,
+    mem_size: usize,
+}
+
+/// Whether the image starts with the ELF magic.
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(ELF_MAGIC)
+}
+
+/// Parses the ELF header and the `PT_LOAD` program headers, returns the
+/// entry point and the loadable segments.
+fn parse_elf(image: &[u8]) -> AxResult<(usize, Vec<Segment>)> {
+    if !is_elf(image) || image.len() < 0x40 {
+        return ax_err!(InvalidData, "not an ELF image");
+    }
+    let class = image[4];
+    if image[5] != ELFDATA2LSB {
+        return ax_err!(Unsupported, "big-endian ELF images");
+    }
+    let (e_type, machine) = (read_u16(image, 0x10), read_u16(image, 0x12));
+    if e_type != ET_EXEC || !matches!(machine, EM_386 | EM_X86_64) {
+        return ax_err!(
+            Unsupported,
+            format!("ELF type {} for machine {}", e_type, machine)
+        );
+    }
+    let (entry, phoff, phentsize, phnum, min_phentsize) = match class {
+        ELFCLASS32 => (
+            read_u32(image, 0x18) as usize,
+            read_u32(image, 0x1c) as usize,
+            read_u16(image, 0x2a) as usize,
+            read_u16(image, 0x2c) as usize,
+            0x20,
+        ),
+        ELFCLASS64 => (
+            read_u64(image, 0x18) as usize,
+            read_u64(image, 0x20) as usize,
+            read_u16(image, 0x36) as usize,
+            read_u16(image, 0x38) as usize,
+            0x38,
+        ),
+        _ => return ax_err!(InvalidData, format!("invalid ELF class {}", class)),
+    };
+
+    if phentsize < min_phentsize {
+        return ax_err!(
+            InvalidData,
+            format!("invalid ELF program header size {:#x}", phentsize)
+        );
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = i
+            .checked_mul(phentsize)
+            .and_then(|off| phoff.checked_add(off))
+            .and_then(|start| image.get(start..start.checked_add(phentsize)?));
+        let Some(ph) = ph else {
+            return ax_err!(InvalidData, "ELF program headers exceed the image");
+        };
+        if read_u32(ph, 0) != PT_LOAD {
+            continue;
+        }
+        let seg = if class == ELFCLASS32 {
+            Segment {
+                offset: read_u32(ph, 4) as usize,
+                vaddr: read_u32(ph, 8) as usize,
+                paddr: read_u32(ph, 12) as usize,
+                file_size: read_u32(ph, 16) as usize,
+                mem_size: read_u32(ph, 20) as usize,
+            }
+        } else {
+            Segment {
+                offset: read_u64(ph, 8) as usize,
+                vaddr: read_u64(ph, 16) as usize,
+                paddr: read_u64(ph, 24) as usize,
+                file_size: read_u64(ph, 32) as usize,
+                mem_size: read_u64(ph, 40) as usize,
+            }
+        };
+        let in_image = seg
+            .offset
+            .checked_add(seg.file_size)
+            .is_some_and(|end| end <= image.len());
+        // the end addresses are computed when loading
+        let in_address_space = seg.paddr.checked_add(seg.mem_size).is_some()
+            && seg.vaddr.checked_add(seg.mem_size).is_some();
+        if seg.file_size > seg.mem_size || !in_image || !in_address_space {
+            return ax_err!(InvalidData, format!("invalid ELF segment {:#x?}", seg));
+        }
+        segments.push(seg);
+    }
+    if segments.is_empty() {
+        return ax_err!(InvalidData, "no loadable ELF segments");
+    }
+    Ok((entry, segments))
+}
+
+/// Loads the `PT_LOAD` segments of the ELF image at their physical addresses,
+/// which must be in guest RAM, and zero-fills the BSS. Returns the physical
+/// entry point and the end address of the loaded segments.
+pub fn load_elf(map: &[MemoryMapEntry], image: &[u8]) -> AxResult<(GuestPhysAddr, GuestPhysAddr)> {
+    let (entry, segments) = parse_elf(image)?;
+    // validate all segments before loading any of them
+    if let Some(seg) = segments
+        .iter()
+        .find(|seg| !super::ram_contains(map, seg.paddr, seg.mem_size))
+    {
+        return ax_err!(
+            InvalidInput,
+            format!(
+                "ELF segment {:#x}..{:#x} is not in guest RAM",
+                seg.paddr,
+                seg.paddr + seg.mem_size
+            )
+        );
+    }
+
+    let mut end = 0;
+    for seg in &segments {
+        let data = &image[seg.offset..seg.offset + seg.file_size];
+        super::write_guest(map, seg.paddr, data)?;
+        super::zero_guest(map, seg.paddr + seg.file_size, seg.mem_size - seg.file_size)?;
+        debug!("ELF segment loaded: {:#x?}", seg);
+        end = end.max(seg.paddr + seg.mem_size);
+    }
+
+    // `e_entry` is a virtual address, translate it by the segment containing it
+    let entry_paddr = segments
+        .iter()
+        .find(|seg| (seg.vaddr..seg.vaddr + seg.mem_size).contains(&entry))
+        .map_or(entry, |seg| entry - seg.vaddr + seg.paddr);
+    info!(
+        "ELF image loaded, {} segments end at {:#x}, entry {:#x}",
+        segments.len(),
+        end,
+        entry_paddr
+    );
+    Ok((entry_paddr, end))
+}
@@ -0,0 +1,268 @@
+//! Linux x86 boot protocol, the kernel is entered at the 64-bit entry point.
+//! (ref: https://www.kernel.org/doc/html/latest/arch/x86/boot.html)
+
+use alloc::format;
+
+use axerrno::{ax_err, AxResult};
+use axvm::{arch::GeneralRegisters, GuestPhysAddr};
+use memory_addr::{align_down_4k, align_up, PAGE_SIZE_4K};
+
+use super::{read_u16, read_u32, read_u64};
+use super::{BootInfoBuf, BootState, EntryMode, MemoryMapEntry, MemoryType};
+
+const BOOT_FLAG: u16 = 0xaa55;
+const HEADER_MAGIC: &[u8; 4] = b"HdrS";
+/// The 64-bit entry point requires boot protocol 2.12 or later.
+const MIN_PROTOCOL_VERSION: u16 = 0x020c;
+/// Bit 0 of `xloadflags`: the kernel has the legacy 64-bit entry point at 0x200.
+const XLF_KERNEL_64: u16 = 1;
+/// Bit 0 of `loadflags`: the protected-mode code is loaded at 0x100000.
+const LOADED_HIGH: u8 = 1;
+const STARTUP_64_OFFSET: usize = 0x200;
+const TYPE_OF_LOADER_UNDEFINED: u8 = 0xff;
+const DEFAULT_SETUP_SECTS: usize = 4;
+const SECTOR_SIZE: usize = 512;
+const MAX_E820_ENTRIES: usize = 128;
+
+// Offsets of the fields in `struct boot_params`, the "zero page".
+const BP_ACPI_RSDP_ADDR: usize = 0x070;
+const BP_EXT_RAMDISK_IMAGE: usize = 0x0c0;
+const BP_EXT_RAMDISK_SIZE: usize = 0x0c4;
+const BP_EXT_CMD_LINE_PTR: usize = 0x0c8;
+const BP_E820_ENTRIES: usize = 0x1e8;
+const BP_E820_TABLE: usize = 0x2d0;
+const BP_SIZE: usize = 0x1000;
+
+// Offsets of the fields in the setup header, relative to the zero page.
+const HDR_START: usize = 0x1f1;
+const HDR_SETUP_SECTS: usize = 0x1f1;
+const HDR_BOOT_FLAG: usize = 0x1fe;
+const HDR_JUMP: usize = 0x200;
+const HDR_HEADER: usize = 0x202;
+const HDR_VERSION: usize = 0x206;
+const HDR_TYPE_OF_LOADER: usize = 0x210;
+const HDR_LOADFLAGS: usize = 0x211;
+const HDR_CODE32_START: usize = 0x214;
+const HDR_RAMDISK_IMAGE: usize = 0x218;
+const HDR_RAMDISK_SIZE: usize = 0x21c;
+const HDR_CMD_LINE_PTR: usize = 0x228;
+const HDR_INITRD_ADDR_MAX: usize = 0x22c;
+const HDR_KERNEL_ALIGNMENT: usize = 0x230;
+const HDR_RELOCATABLE_KERNEL: usize = 0x234;
+const HDR_XLOADFLAGS: usize = 0x236;
+const HDR_CMDLINE_SIZE: usize = 0x238;
+const HDR_PREF_ADDRESS: usize = 0x258;
+const HDR_INIT_SIZE: usize = 0x260;
+
+/// Segment selectors required by the boot protocol.
+const BOOT_CS: u16 = 0x10;
+const BOOT_DS: u16 = 0x18;
+const BOOT_GDT: [u64; 4] = [
+    0,
+    0,
+    0x00af_9b00_0000_ffff, // 0x10: 64-bit code, exec/read
+    0x00cf_9300_0000_ffff, // 0x18: data, read/write
+];
+
+/// The page table identity maps the first 4 GiB with 2M pages.
+const IDENT_MAP_GIB: usize = 4;
+const PTE_PRESENT_WRITABLE: u64 = 0b11;
+const PTE_HUGE_PAGE: u64 = 1 << 7;
+
+/// A parsed bzImage.
+pub struct LinuxImage<'a> {
+    image: &'a [u8],
+    /// The setup header, from offset 0x1f1 to its end.
+    header: &'a [u8],
+    version: u16,
+    setup_size: usize,
+}
+
+impl<'a> LinuxImage<'a> {
+    /// Parses and validates the setup header of the bzImage.
+    pub fn parse(image: &'a [u8]) -> AxResult<Self> {
+        if image.len() < HDR_INIT_SIZE + 4
+            || read_u16(image, HDR_BOOT_FLAG) != BOOT_FLAG
+            || &image[HDR_HEADER..HDR_HEADER + 4] != HEADER_MAGIC
+        {
+            return ax_err!(InvalidData, "not a bzImage");
+        }
+        let version = read_u16(image, HDR_VERSION);
+        if version < MIN_PROTOCOL_VERSION {
+            return ax_err!(
+                Unsupported,
+                format!("Linux boot protocol {:#x} is too old", version)
+            );
+        }
+        if read_u16(image, HDR_XLOADFLAGS) & XLF_KERNEL_64 == 0 {
+            return ax_err!(Unsupported, "the kernel has no 64-bit entry point");
+        }
+        if image[HDR_LOADFLAGS] & LOADED_HIGH == 0 {
+            return ax_err!(Unsupported, "zImage kernels are not supported");
+        }
+        let setup_sects = match image[HDR_SETUP_SECTS] as usize {
+            0 => DEFAULT_SETUP_SECTS,
+            n => n,
+        };
+        let setup_size = (setup_sects + 1) * SECTOR_SIZE;
+        // the byte at 0x201 is the length of the header after the jump instruction
+        let header_end = HDR_HEADER + image[HDR_JUMP + 1] as usize;
+        if setup_size >= image.len() || header_end > setup_size {
+            return ax_err!(InvalidData, "invalid bzImage setup size");
+        }
+        Ok(Self {
+            image,
+            header: &image[HDR_START..header_end],
+            version,
+            setup_size,
+        })
+    }
+
+    fn kernel(&self) -> &'a [u8] {
+        &self.image[self.setup_size..]
+    }
+
+    fn field_u32(&self, offset: usize) -> u32 {
+        read_u32(self.image, offset)
+    }
+
+    /// The memory needed by the kernel in place, including the decompression.
+    fn init_size(&self) -> usize {
+        (self.field_u32(HDR_INIT_SIZE) as usize).max(self.kernel().len())
+    }
+
+    /// Chooses where to load the protected-mode kernel: the preferred address
+    /// if it is in RAM, otherwise the lowest aligned RAM above 1 MiB if the
+    /// kernel is relocatable.
+    fn load_address(&self, map: &[MemoryMapEntry]) -> AxResult<GuestPhysAddr> {
+        let size = self.init_size();
+        let pref = read_u64(self.image, HDR_PREF_ADDRESS) as usize;
+        if super::ram_contains(map, pref, size) {
+            return Ok(pref);
+        }
+        if self.image[HDR_RELOCATABLE_KERNEL] == 0 {
+            return ax_err!(NoMemory, "no RAM at the preferred address of the kernel");
+        }
+        let align = (self.field_u32(HDR_KERNEL_ALIGNMENT) as usize).max(PAGE_SIZE_4K);
+        map.iter()
+            .filter(|e| e.kind == MemoryType::Ram)
+            .map(|e| (align_up(e.base.max(super::HIGH_MEMORY_START), align), e))
+            .find(|&(addr, e)| addr + size <= e.base + e.size)
+            .map(|(addr, _)| addr)
+            .ok_or(axerrno::AxError::NoMemory)
+    }
+
+    fn cmdline_size(&self) -> usize {
+        self.field_u32(HDR_CMDLINE_SIZE) as usize
+    }
+}
+
+/// Builds the identity-mapped page table in `buf`, returns its root address.
+fn build_page_table(buf: &mut BootInfoBuf) -> GuestPhysAddr {
+    let pml4 = buf.alloc(PAGE_SIZE_4K, PAGE_SIZE_4K);
+    let pdpt = buf.alloc(PAGE_SIZE_4K, PAGE_SIZE_4K);
+    buf.write_u64(pml4, buf.gpa(pdpt) as u64 | PTE_PRESENT_WRITABLE);
+    for i in 0..IDENT_MAP_GIB {
+        let pd = buf.alloc(PAGE_SIZE_4K, PAGE_SIZE_4K);
+        buf.write_u64(pdpt + i * 8, buf.gpa(pd) as u64 | PTE_PRESENT_WRITABLE);
+        for j in 0..512 {
+            let paddr = ((i << 30) | (j << 21)) as u64;
+            buf.write_u64(pd + j * 8, paddr | PTE_PRESENT_WRITABLE | PTE_HUGE_PAGE);
+        }
+    }
+    buf.gpa(pml4)
+}
+
+/// Loads the kernel and the optional initramfs into guest memory, and builds
+/// the zero page, the page table and the GDT at `base`. Returns the vCPU
+/// state to enter the 64-bit entry point with RSI pointing to the zero page.
+pub fn load_linux(
+    map: &[MemoryMapEntry],
+    linux: &LinuxImage,
+    initrd: Option<&[u8]>,
+    cmdline: &str,
+    rsdp: GuestPhysAddr,
+    base: GuestPhysAddr,
+) -> AxResult<BootState> {
+    let load_addr = linux.load_address(map)?;
+    super::write_guest(map, load_addr, linux.kernel())?;
+    let kernel_end = load_addr + linux.init_size();
+    info!(
+        "Linux kernel (boot protocol {:#x}) loaded at {:#x}..{:#x}",
+        linux.version, load_addr, kernel_end
+    );
+
+    let mut buf = BootInfoBuf::new(base);
+    let cr3 = build_page_table(&mut buf);
+    let gdt = buf.alloc(BOOT_GDT.len() * 8, 8);
+    for (i, &desc) in BOOT_GDT.iter().enumerate() {
+        buf.write_u64(gdt + i * 8, desc);
+    }
+
+    let bp = buf.alloc(BP_SIZE, PAGE_SIZE_4K);
+    buf.data[bp + HDR_START..bp + HDR_START + linux.header.len()].copy_from_slice(linux.header);
+    buf.data[bp + HDR_TYPE_OF_LOADER] = TYPE_OF_LOADER_UNDEFINED;
+    buf.write_u32(bp + HDR_CODE32_START, load_addr as u32);
+    buf.write_u64(bp + BP_ACPI_RSDP_ADDR, rsdp as u64);
+
+    if cmdline.len() >= linux.cmdline_size() {
+        return ax_err!(InvalidInput, "the kernel command line is too long");
+    }
+    let cmdline = buf.push_str(cmdline) as u64;
+    buf.write_u32(bp + HDR_CMD_LINE_PTR, cmdline as u32);
+    buf.write_u32(bp + BP_EXT_CMD_LINE_PTR, (cmdline >> 32) as u32);
+
+    if let Some(initrd) = initrd {
+        // place the initramfs at the top of RAM below `initrd_addr_max`
+        let addr_max = linux.field_u32(HDR_INITRD_ADDR_MAX) as usize;
+        let Some(top) = map
+            .iter()
+            .filter(|e| e.kind == MemoryType::Ram && e.base + e.size > kernel_end)
+            .map(|e| (e.base + e.size).min(addr_max + 1))
+            .filter(|&end| end >= initrd.len())
+            .max()
+        else {
+            return ax_err!(NoMemory, "no RAM for the initramfs");
+        };
+        let start = align_down_4k(top - initrd.len());
+        if start < kernel_end {
+            return ax_err!(NoMemory, "no RAM for the initramfs");
+        }
+        super::write_guest(map, start, initrd)?;
+        info!(
+            "initramfs loaded at {:#x}..{:#x}",
+            start,
+            start + initrd.len()
+        );
+        let (start, size) = (start as u64, initrd.len() as u64);
+        buf.write_u32(bp + HDR_RAMDISK_IMAGE, start as u32);
+        buf.write_u32(bp + HDR_RAMDISK_SIZE, size as u32);
+        buf.write_u32(bp + BP_EXT_RAMDISK_IMAGE, (start >> 32) as u32);
+        buf.write_u32(bp + BP_EXT_RAMDISK_SIZE, (size >> 32) as u32);
+    }
+
+    if map.len() > MAX_E820_ENTRIES {
+        return ax_err!(InvalidInput, "too many e820 entries");
+    }
+    buf.data[bp + BP_E820_ENTRIES] = map.len() as u8;
+    for (i, e) in map.iter().enumerate() {
+        let entry = bp + BP_E820_TABLE + i * 20;
+        buf.write_u64(entry, e.base as u64);
+        buf.write_u64(entry + 8, e.size as u64);
+        buf.write_u32(entry + 16, e.kind as u32);
+    }
+
+    buf.copy_to_guest(map)?;
+    let mut regs = GeneralRegisters::default();
+    regs.rsi = buf.gpa(bp) as u64;
+    Ok(BootState {
+        entry: load_addr + STARTUP_64_OFFSET,
+        mode: EntryMode::Long {
+            page_table: cr3,
+            gdt: (buf.gpa(gdt), (BOOT_GDT.len() * 8 - 1) as u16),
+            code_sel: BOOT_CS,
+            data_sel: BOOT_DS,
+        },
+        regs,
+    })
+}
@@ -0,0 +1,309 @@
+//! Guest boot protocols, where the VMM acts as the boot loader.
+
+mod elf;
+mod linux;
+mod multiboot;
+mod multiboot2;
+
+use alloc::vec::Vec;
+
+use axerrno::{ax_err, AxResult};
+use axvm::{arch::GeneralRegisters, GuestPhysAddr};
+use memory_addr::{align_down_4k, align_up_4k};
+use page_table_entry::MappingFlags;
+
+use crate::gpm::GuestPhysMemorySet;
+
+pub use self::elf::{is_elf, load_elf};
+pub use self::linux::{load_linux, LinuxImage};
+pub use self::multiboot::{setup_multiboot_info, MULTIBOOT_BOOTLOADER_MAGIC};
+pub use self::multiboot2::{
+    load_multiboot2_image, setup_multiboot2_info, Multiboot2Header, MULTIBOOT2_BOOTLOADER_MAGIC,
+};
+
+/// The boot loader name reported to the guest.
+const BOOT_LOADER_NAME: &str = "arceos-umhv";
+
+/// The legacy area reserved for the EBDA, the VGA memory and the BIOS.
+const LEGACY_HOLE: core::ops::Range<GuestPhysAddr> = 0x9_fc00..0x10_0000;
+/// Boot information must be placed below the legacy hole.
+const LOW_MEMORY_END: GuestPhysAddr = LEGACY_HOLE.start;
+const HIGH_MEMORY_START: GuestPhysAddr = LEGACY_HOLE.end;
+const MAX_32BIT_ADDR: GuestPhysAddr = 0x1_0000_0000;
+
+/// How the guest image is booted.
+#[derive(Debug, Clone, Copy)]
+pub enum BootProtocol {
+    /// Multiboot through the BIOS, which enters the kernel at `GUEST_ENTRY`.
+    Multiboot,
+    /// Multiboot2, the kernel is entered directly in 32-bit protected mode.
+    Multiboot2,
+    /// Linux bzImage, entered at the 64-bit entry point.
+    Linux,
+    /// The firmware ROM is started from the reset vector, which boots the guest.
+    Firmware,
+}
+
+/// The processor mode of the vCPU when entering the guest.
+#[derive(Debug, Clone, Copy)]
+pub enum EntryMode {
+    /// The architectural state after RESET, starting at the reset vector.
+    Reset,
+    Real,
+    /// 32-bit protected mode with flat segments and paging disabled.
+    FlatProtected,
+    /// 64-bit mode with the given page table, GDT and flat segment selectors.
+    Long {
+        page_table: GuestPhysAddr,
+        gdt: (GuestPhysAddr, u16),
+        code_sel: u16,
+        data_sel: u16,
+    },
+}
+
+/// The initial vCPU state set up by the boot protocol.
+pub struct BootState {
+    pub entry: GuestPhysAddr,
+    pub mode: EntryMode,
+    pub regs: GeneralRegisters,
+}
+
+impl BootState {
+    /// Starts at the reset vector, `EDX` holds the processor signature (family 6).
+    pub fn reset() -> Self {
+        let mut regs = GeneralRegisters::default();
+        regs.rdx = 0x600;
+        Self {
+            entry: 0xfff0,
+            mode: EntryMode::Reset,
+            regs,
+        }
+    }
+
+    /// The boot loader magic in EAX and the boot information address in EBX.
+    pub fn multiboot(
+        entry: GuestPhysAddr,
+        mode: EntryMode,
+        magic: u32,
+        info: GuestPhysAddr,
+    ) -> Self {
+        let mut regs = GeneralRegisters::default();
+        regs.rax = magic as u64;
+        regs.rbx = info as u64;
+        Self { entry, mode, regs }
+    }
+}
+
+/// Types of the memory map entries, same as the E820 address range types.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    Ram = 1,
+    Reserved = 2,
+}
+
+/// An entry of the guest physical memory map.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMapEntry {
+    pub base: GuestPhysAddr,
+    pub size: usize,
+    pub kind: MemoryType,
+}
+
+impl MemoryMapEntry {
+    fn end(&self) -> GuestPhysAddr {
+        self.base + self.size
+    }
+}
+
+/// A boot module loaded into guest memory.
+pub struct BootModule {
+    pub start: GuestPhysAddr,
+    pub size: usize,
+    pub cmdline: &'static str,
+}
+
+/// Builds the guest memory map from the regions of `gpm`. RAM regions are
+/// reported as usable except the legacy hole, device regions as reserved.
+pub fn memory_map(gpm: &GuestPhysMemorySet) -> Vec<MemoryMapEntry> {
+    let mut map = Vec::new();
+    let mut push = |base: GuestPhysAddr, end: GuestPhysAddr, kind| {
+        if base < end {
+            map.push(MemoryMapEntry {
+                base,
+                size: end - base,
+                kind,
+            });
+        }
+    };
+    for region in gpm.iter() {
+        let (start, end) = (region.start, region.start + region.size);
+        if region.flags.contains(MappingFlags::DEVICE) || region.is_rom() {
+            push(start, end, MemoryType::Reserved);
+            continue;
+        }
+        let (hole_start, hole_end) = (LEGACY_HOLE.start.max(start), LEGACY_HOLE.end.min(end));
+        if hole_start < hole_end {
+            push(start, hole_start, MemoryType::Ram);
+            push(hole_start, hole_end, MemoryType::Reserved);
+            push(hole_end, end, MemoryType::Ram);
+        } else {
+            push(start, end, MemoryType::Ram);
+        }
+    }
+    map
+}
+
+/// Amount of the lower memory and the upper memory starting at 1 MiB, in KiB.
+fn basic_meminfo(map: &[MemoryMapEntry]) -> (u32, u32) {
+    let ram_size_at = |addr| {
+        map.iter()
+            .find(|e| e.kind == MemoryType::Ram && e.base == addr)
+            .map_or(0, |e| (e.size / 1024) as u32)
+    };
+    (ram_size_at(0), ram_size_at(HIGH_MEMORY_START))
+}
+
+/// Whether `gpa..gpa + size` is inside a single RAM entry of `map`.
+fn ram_contains(map: &[MemoryMapEntry], gpa: GuestPhysAddr, size: usize) -> bool {
+    map.iter()
+        .any(|e| e.kind == MemoryType::Ram && e.base <= gpa && gpa + size <= e.end())
+}
+
+/// Copies `data` to guest memory at `gpa`, which must be inside `map` RAM.
+pub fn write_guest(map: &[MemoryMapEntry], gpa: GuestPhysAddr, data: &[u8]) -> AxResult {
+    if !ram_contains(map, gpa, data.len()) {
+        return ax_err!(
+            InvalidInput,
+            alloc::format!("GPA range {:#x}..{:#x} is not RAM", gpa, gpa + data.len())
+        );
+    }
+    Ok(crate::guest_phys_memory().write_gpa(gpa, data)?)
+}
+
+/// Fills `gpa..gpa + size` of guest memory with zeros, which must be inside `map` RAM.
+fn zero_guest(map: &[MemoryMapEntry], gpa: GuestPhysAddr, size: usize) -> AxResult {
+    if !ram_contains(map, gpa, size) {
+        return ax_err!(
+            InvalidInput,
+            alloc::format!("GPA range {:#x}..{:#x} is not RAM", gpa, gpa + size)
+        );
+    }
+    const ZEROS: [u8; 0x1000] = [0; 0x1000];
+    let mut gpm = crate::guest_phys_memory();
+    for offset in (0..size).step_by(ZEROS.len()) {
+        let len = ZEROS.len().min(size - offset);
+        gpm.write_gpa(gpa + offset, &ZEROS[..len])?;
+    }
+    Ok(())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Loads the boot modules `(file name, command line)` from the file system
+/// into the highest RAM below 4 GiB, top-down and above `kernel_end`.
+pub fn load_boot_modules(
+    map: &[MemoryMapEntry],
+    kernel_end: GuestPhysAddr,
+    modules: &[(&str, &'static str)],
+) -> AxResult<Vec<BootModule>> {
+    let Some(top_ram) = map
+        .iter()
+        .filter(|e| e.kind == MemoryType::Ram && e.base >= HIGH_MEMORY_START)
+        .filter(|e| e.base < MAX_32BIT_ADDR)
+        .max_by_key(|e| e.base)
+    else {
+        return ax_err!(NoMemory, "no high memory for boot modules");
+    };
+    let bottom = align_up_4k(kernel_end.max(top_ram.base));
+    let mut top = align_down_4k(top_ram.end().min(MAX_32BIT_ADDR));
+    let mut loaded = Vec::new();
+    for &(file_name, cmdline) in modules {
+        let data = std::fs::read(file_name).map_err(|err| {
+            warn!("Failed to read boot module {}, err {:?}", file_name, err);
+            axerrno::AxError::NotFound
+        })?;
+        let start = align_down_4k(top.saturating_sub(data.len()));
+        if start < bottom {
+            return ax_err!(NoMemory, "not enough memory for boot modules");
+        }
+        write_guest(map, start, &data)?;
+        info!(
+            "Boot module {} loaded at {:#x}..{:#x}",
+            file_name,
+            start,
+            start + data.len()
+        );
+        loaded.push(BootModule {
+            start,
+            size: data.len(),
+            cmdline,
+        });
+        top = start;
+    }
+    Ok(loaded)
+}
+
+/// A buffer of boot information to be copied to guest memory at `base`.
+struct BootInfoBuf {
+    base: GuestPhysAddr,
+    data: Vec<u8>,
+}
+
+impl BootInfoBuf {
+    fn new(base: GuestPhysAddr) -> Self {
+        Self {
+            base,
+            data: Vec::new(),
+        }
+    }
+
+    /// Appends `size` zero bytes aligned to `align`, returns the offset.
+    fn alloc(&mut self, size: usize, align: usize) -> usize {
+        let offset = (self.base + self.data.len()).next_multiple_of(align) - self.base;
+        self.data.resize(offset + size, 0);
+        offset
+    }
+
+    fn gpa(&self, offset: usize) -> GuestPhysAddr {
+        self.base + offset
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8], align: usize) -> GuestPhysAddr {
+        let offset = self.alloc(bytes.len(), align);
+        self.data[offset..].copy_from_slice(bytes);
+        self.gpa(offset)
+    }
+
+    /// Appends a NUL-terminated string.
+    fn push_str(&mut self, s: &str) -> GuestPhysAddr {
+        let gpa = self.push_bytes(s.as_bytes(), 1);
+        self.data.push(0);
+        gpa
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, offset: usize, value: u64) {
+        self.data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn copy_to_guest(&self, map: &[MemoryMapEntry]) -> AxResult {
+        if self.base + self.data.len() > LOW_MEMORY_END {
+            return ax_err!(NoMemory, "boot information overlaps the legacy hole");
+        }
+        write_guest(map, self.base, &self.data)
+    }
+}
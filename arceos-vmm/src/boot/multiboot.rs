@@ -0,0 +1,93 @@
+//! Multiboot 1 information structure. (ref: Multiboot Specification 0.6.96,
+//! Section 3.3)
+
+use axerrno::AxResult;
+use axvm::GuestPhysAddr;
+
+use super::{BootInfoBuf, BootModule, MemoryMapEntry};
+
+/// The magic value in EAX passed by a Multiboot-compliant boot loader.
+pub const MULTIBOOT_BOOTLOADER_MAGIC: u32 = 0x2bad_b002;
+
+const MBI_SIZE: usize = 116;
+
+bitflags::bitflags! {
+    /// Which fields of the Multiboot information structure are valid.
+    struct MbiFlags: u32 {
+        const MEMORY = 1;
+        const CMDLINE = 1 << 2;
+        const MODS = 1 << 3;
+        const MMAP = 1 << 6;
+        const BOOT_LOADER_NAME = 1 << 9;
+    }
+}
+
+// Offsets of the fields in the information structure.
+const MBI_FLAGS: usize = 0;
+const MBI_MEM_LOWER: usize = 4;
+const MBI_MEM_UPPER: usize = 8;
+const MBI_CMDLINE: usize = 16;
+const MBI_MODS_COUNT: usize = 20;
+const MBI_MODS_ADDR: usize = 24;
+const MBI_MMAP_LENGTH: usize = 44;
+const MBI_MMAP_ADDR: usize = 48;
+const MBI_BOOT_LOADER_NAME: usize = 64;
+
+/// Size of a module structure: `mod_start`, `mod_end`, `string` and a reserved field.
+const MODULE_SIZE: usize = 16;
+/// Size of a memory map entry, excluding the `size` field itself.
+const MMAP_ENTRY_SIZE: u32 = 20;
+
+/// Builds the Multiboot information structure at `base` in guest memory,
+/// returns its address to be passed in EBX.
+pub fn setup_multiboot_info(
+    map: &[MemoryMapEntry],
+    modules: &[BootModule],
+    cmdline: &str,
+    base: GuestPhysAddr,
+) -> AxResult<GuestPhysAddr> {
+    let mut buf = BootInfoBuf::new(base);
+    let mbi = buf.alloc(MBI_SIZE, 8);
+    let mut flags = MbiFlags::MMAP | MbiFlags::CMDLINE | MbiFlags::BOOT_LOADER_NAME;
+
+    let (mem_lower, mem_upper) = super::basic_meminfo(map);
+    if mem_lower != 0 || mem_upper != 0 {
+        flags |= MbiFlags::MEMORY;
+        buf.write_u32(mbi + MBI_MEM_LOWER, mem_lower);
+        buf.write_u32(mbi + MBI_MEM_UPPER, mem_upper);
+    }
+
+    let mmap = buf.alloc(map.len() * (MMAP_ENTRY_SIZE as usize + 4), 8);
+    for (i, e) in map.iter().enumerate() {
+        let entry = mmap + i * (MMAP_ENTRY_SIZE as usize + 4);
+        buf.write_u32(entry, MMAP_ENTRY_SIZE);
+        buf.write_u64(entry + 4, e.base as u64);
+        buf.write_u64(entry + 12, e.size as u64);
+        buf.write_u32(entry + 20, e.kind as u32);
+    }
+    buf.write_u32(mbi + MBI_MMAP_LENGTH, (buf.data.len() - mmap) as u32);
+    buf.write_u32(mbi + MBI_MMAP_ADDR, buf.gpa(mmap) as u32);
+
+    if !modules.is_empty() {
+        flags |= MbiFlags::MODS;
+        let mods = buf.alloc(modules.len() * MODULE_SIZE, 8);
+        for (i, m) in modules.iter().enumerate() {
+            let string = buf.push_str(m.cmdline);
+            let entry = mods + i * MODULE_SIZE;
+            buf.write_u32(entry, m.start as u32);
+            buf.write_u32(entry + 4, (m.start + m.size) as u32);
+            buf.write_u32(entry + 8, string as u32);
+        }
+        buf.write_u32(mbi + MBI_MODS_COUNT, modules.len() as u32);
+        buf.write_u32(mbi + MBI_MODS_ADDR, buf.gpa(mods) as u32);
+    }
+
+    let cmdline = buf.push_str(cmdline);
+    buf.write_u32(mbi + MBI_CMDLINE, cmdline as u32);
+    let name = buf.push_str(super::BOOT_LOADER_NAME);
+    buf.write_u32(mbi + MBI_BOOT_LOADER_NAME, name as u32);
+    buf.write_u32(mbi + MBI_FLAGS, flags.bits());
+
+    buf.copy_to_guest(map)?;
+    Ok(buf.gpa(mbi))
+}
@@ -0,0 +1,275 @@
+//! Multiboot2 image loading and boot information. (ref: Multiboot2
+//! Specification version 2.0, Chapter 3)
+
+use alloc::{format, vec::Vec};
+
+use axerrno::{ax_err, AxResult};
+use axvm::GuestPhysAddr;
+
+use super::{read_u16, read_u32, BootInfoBuf, BootModule, MemoryMapEntry};
+
+/// The magic value in EAX passed by a Multiboot2-compliant boot loader.
+pub const MULTIBOOT2_BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+const HEADER_MAGIC: u32 = 0xe852_50d6;
+const ARCH_I386: u32 = 0;
+/// The header must be contained completely within the first 32768 bytes.
+const HEADER_SEARCH_LIMIT: usize = 32768;
+const HEADER_ALIGN: usize = 8;
+const TAG_ALIGN: usize = 8;
+/// Flag of the header tags, the boot loader may ignore the tag if set.
+const TAG_OPTIONAL: u16 = 1;
+
+// Header tag types.
+const HEADER_TAG_END: u16 = 0;
+const HEADER_TAG_INFO_REQUEST: u16 = 1;
+const HEADER_TAG_ADDRESS: u16 = 2;
+const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+const HEADER_TAG_CONSOLE_FLAGS: u16 = 4;
+const HEADER_TAG_FRAMEBUFFER: u16 = 5;
+const HEADER_TAG_MODULE_ALIGN: u16 = 6;
+
+// Boot information tag types.
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOT_LOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMINFO: u32 = 4;
+const TAG_MMAP: u32 = 6;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+/// Boot information tag types that can be provided to the guest.
+const SUPPORTED_TAGS: &[u32] = &[
+    TAG_CMDLINE,
+    TAG_BOOT_LOADER_NAME,
+    TAG_MODULE,
+    TAG_BASIC_MEMINFO,
+    TAG_MMAP,
+    TAG_ACPI_OLD,
+    TAG_ACPI_NEW,
+];
+
+/// Size of a memory map entry: `base_addr`, `length`, `type` and a reserved field.
+const MMAP_ENTRY_SIZE: u32 = 24;
+
+/// The address tag, where to load the image. All fields are physical addresses.
+#[derive(Debug, Clone, Copy)]
+struct AddressTag {
+    header_addr: u32,
+    load_addr: u32,
+    /// 0 means the whole file is loaded.
+    load_end_addr: u32,
+    /// 0 means no BSS segment.
+    bss_end_addr: u32,
+}
+
+/// The parsed Multiboot2 header of a guest image.
+#[derive(Debug)]
+pub struct Multiboot2Header {
+    /// Offset of the header in the image file.
+    offset: usize,
+    address: Option<AddressTag>,
+    entry_addr: Option<u32>,
+}
+
+impl Multiboot2Header {
+    /// Searches the image for the Multiboot2 header and parses its tags.
+    pub fn parse(image: &[u8]) -> AxResult<Self> {
+        let search_end = image.len().min(HEADER_SEARCH_LIMIT);
+        let Some(offset) = (0..search_end.saturating_sub(15))
+            .step_by(HEADER_ALIGN)
+            .find(|&off| {
+                let magic = read_u32(image, off);
+                let sum = [off, off + 4, off + 8, off + 12]
+                    .iter()
+                    .fold(0u32, |sum, &i| sum.wrapping_add(read_u32(image, i)));
+                magic == HEADER_MAGIC && sum == 0
+            })
+        else {
+            return ax_err!(InvalidData, "Multiboot2 header not found");
+        };
+        let arch = read_u32(image, offset + 4);
+        if arch != ARCH_I386 {
+            return ax_err!(Unsupported, format!("Multiboot2 architecture {}", arch));
+        }
+        let header_len = read_u32(image, offset + 8) as usize;
+        let header = image
+            .get(offset..offset + header_len)
+            .filter(|_| offset + header_len <= search_end);
+        let Some(header) = header else {
+            return ax_err!(InvalidData, "Multiboot2 header exceeds the search limit");
+        };
+
+        let mut parsed = Self {
+            offset,
+            address: None,
+            entry_addr: None,
+        };
+        let mut tag = 16;
+        while tag + 8 <= header.len() {
+            let typ = read_u16(header, tag);
+            let flags = read_u16(header, tag + 2);
+            let size = read_u32(header, tag + 4) as usize;
+            let Some(body) = header.get(tag + 8..tag + size.max(8)) else {
+                return ax_err!(InvalidData, "Multiboot2 header tag exceeds the header");
+            };
+            match typ {
+                HEADER_TAG_END => break,
+                HEADER_TAG_INFO_REQUEST => {
+                    for ty in body.chunks_exact(4).map(|b| read_u32(b, 0)) {
+                        if !SUPPORTED_TAGS.contains(&ty) {
+                            if flags & TAG_OPTIONAL == 0 {
+                                return ax_err!(
+                                    Unsupported,
+                                    format!("required Multiboot2 information tag {}", ty)
+                                );
+                            }
+                            warn!("Multiboot2: information tag {} is not provided", ty);
+                        }
+                    }
+                }
+                HEADER_TAG_ADDRESS if body.len() >= 16 => {
+                    parsed.address = Some(AddressTag {
+                        header_addr: read_u32(body, 0),
+                        load_addr: read_u32(body, 4),
+                        load_end_addr: read_u32(body, 8),
+                        bss_end_addr: read_u32(body, 12),
+                    });
+                }
+                HEADER_TAG_ENTRY_ADDRESS if body.len() >= 4 => {
+                    parsed.entry_addr = Some(read_u32(body, 0));
+                }
+                HEADER_TAG_FRAMEBUFFER if body.len() >= 12 => {
+                    let fb = (read_u32(body, 0), read_u32(body, 4), read_u32(body, 8));
+                    // there is no display device, the request is only a preference
+                    warn!(
+                        "Multiboot2: framebuffer {:?} requested, staying in text mode",
+                        fb
+                    );
+                }
+                HEADER_TAG_CONSOLE_FLAGS | HEADER_TAG_MODULE_ALIGN => {} // modules are page aligned
+                _ if flags & TAG_OPTIONAL != 0 => {
+                    warn!("Multiboot2: ignored optional header tag {}", typ)
+                }
+                _ => return ax_err!(Unsupported, format!("Multiboot2 header tag {}", typ)),
+            }
+            tag += size.next_multiple_of(TAG_ALIGN).max(8);
+        }
+        debug!("{:#x?}", parsed);
+        Ok(parsed)
+    }
+}
+
+/// Loads the Multiboot2 image into guest memory as described by the address
+/// tag of its header, or as an ELF image if there is no address tag.
+/// Returns the entry point and the end address of the loaded image.
+pub fn load_multiboot2_image(
+    map: &[MemoryMapEntry],
+    image: &[u8],
+    header: &Multiboot2Header,
+) -> AxResult<(GuestPhysAddr, GuestPhysAddr)> {
+    let Some(addr) = header.address else {
+        // load the ELF segments, the entry address tag overrides `e_entry`
+        let (entry, kernel_end) = super::load_elf(map, image)?;
+        let entry = header.entry_addr.map_or(entry, |entry| entry as usize);
+        return Ok((entry, kernel_end));
+    };
+    let Some(entry) = header.entry_addr else {
+        return ax_err!(InvalidData, "Multiboot2 image has no entry address tag");
+    };
+    let (header_addr, load_addr) = (addr.header_addr as usize, addr.load_addr as usize);
+    if load_addr > header_addr || header_addr - load_addr > header.offset {
+        return ax_err!(InvalidData, "invalid Multiboot2 load address");
+    }
+    let file_start = header.offset - (header_addr - load_addr);
+    let load_end = match addr.load_end_addr {
+        0 => load_addr + image.len() - file_start,
+        end => end as usize,
+    };
+    let Some(data) = image.get(file_start..file_start + load_end.saturating_sub(load_addr)) else {
+        return ax_err!(InvalidData, "Multiboot2 load end address exceeds the image");
+    };
+    super::write_guest(map, load_addr, data)?;
+
+    let mut kernel_end = load_addr + data.len();
+    let bss_end = addr.bss_end_addr as usize;
+    if bss_end > kernel_end {
+        super::zero_guest(map, kernel_end, bss_end - kernel_end)?;
+        kernel_end = bss_end;
+    }
+    info!(
+        "Multiboot2 image loaded at {:#x}..{:#x}, entry {:#x}",
+        load_addr, kernel_end, entry
+    );
+    Ok((entry as usize, kernel_end))
+}
+
+/// Appends a boot information tag with the given payload.
+fn push_tag(buf: &mut BootInfoBuf, typ: u32, payload: &[u8]) {
+    let tag = buf.alloc(8 + payload.len(), TAG_ALIGN);
+    buf.write_u32(tag, typ);
+    buf.write_u32(tag + 4, (8 + payload.len()) as u32);
+    buf.data[tag + 8..].copy_from_slice(payload);
+}
+
+fn string_payload(s: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(s.len() + 1);
+    payload.extend_from_slice(s.as_bytes());
+    payload.push(0);
+    payload
+}
+
+/// Builds the Multiboot2 boot information at `base` in guest memory, returns
+/// its address to be passed in EBX. `rsdp` is the ACPI 2.0+ RSDP to be copied.
+pub fn setup_multiboot2_info(
+    map: &[MemoryMapEntry],
+    modules: &[BootModule],
+    cmdline: &str,
+    rsdp: &[u8; crate::acpi::RSDP_SIZE],
+    base: GuestPhysAddr,
+) -> AxResult<GuestPhysAddr> {
+    let mut buf = BootInfoBuf::new(base);
+    // total_size and reserved fields
+    let info = buf.alloc(8, TAG_ALIGN);
+
+    push_tag(&mut buf, TAG_CMDLINE, &string_payload(cmdline));
+    push_tag(
+        &mut buf,
+        TAG_BOOT_LOADER_NAME,
+        &string_payload(super::BOOT_LOADER_NAME),
+    );
+    for m in modules {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(m.start as u32).to_le_bytes());
+        payload.extend_from_slice(&((m.start + m.size) as u32).to_le_bytes());
+        payload.extend_from_slice(&string_payload(m.cmdline));
+        push_tag(&mut buf, TAG_MODULE, &payload);
+    }
+
+    let (mem_lower, mem_upper) = super::basic_meminfo(map);
+    let mut meminfo = [0; 8];
+    meminfo[..4].copy_from_slice(&mem_lower.to_le_bytes());
+    meminfo[4..].copy_from_slice(&mem_upper.to_le_bytes());
+    push_tag(&mut buf, TAG_BASIC_MEMINFO, &meminfo);
+
+    let mut mmap = Vec::new();
+    mmap.extend_from_slice(&MMAP_ENTRY_SIZE.to_le_bytes());
+    mmap.extend_from_slice(&0u32.to_le_bytes()); // entry_version
+    for e in map {
+        mmap.extend_from_slice(&(e.base as u64).to_le_bytes());
+        mmap.extend_from_slice(&(e.size as u64).to_le_bytes());
+        mmap.extend_from_slice(&(e.kind as u32).to_le_bytes());
+        mmap.extend_from_slice(&0u32.to_le_bytes());
+    }
+    push_tag(&mut buf, TAG_MMAP, &mmap);
+
+    push_tag(&mut buf, TAG_ACPI_OLD, &rsdp[..crate::acpi::RSDP_V1_SIZE]);
+    push_tag(&mut buf, TAG_ACPI_NEW, rsdp);
+    push_tag(&mut buf, TAG_END, &[]);
+
+    let total_size = buf.data.len() - info;
+    buf.write_u32(info, total_size as u32);
+    buf.copy_to_guest(map)?;
+    Ok(buf.gpa(info))
+}
@@ -0,0 +1,618 @@
+//! The VM configuration, read from a TOML file on the file system. The
+//! constants in `gconfig.rs` are used as the defaults if there is no such file.
+//!
+//! An example of the configuration file:
+//!
+//! ```toml
+//! name = "vm0"
+//! vcpus = 1
+//! apic_virt = true
+//!
+//! [[memory]]              # guest RAM
+//! gpa = 0
+//! size = 0x100_0000
+//! lazy = false            # allocate pages on the first access
+//!
+//! [[passthrough]]         # host MMIO regions mapped to the guest
+//! gpa = 0xfec0_0000
+//! hpa = 0xfec0_0000       # optional, the same as `gpa` by default
+//! size = 0x1000
+//!
+//! [boot]
+//! protocol = "multiboot"  # or "multiboot2", "linux", "firmware"
+//! image = "nimbos.bin"
+//! bios = "rvm-bios.bin"   # enters raw Multiboot images, or the firmware
+//!                         # started from the reset vector
+//! bios_addr = 0x8000
+//! load_addr = 0x20_0000   # load address of raw Multiboot images
+//! cmdline = ""
+//! initrd = "initramfs.cpio.gz"
+//! modules = [{ file = "app.bin", cmdline = "app" }]
+//! info_addr = 0x1_0000
+//!
+//! [platform]
+//! hpet = 0xfed0_0000     # or false without HPET
+//! pci_ecam = { base = 0xb000_0000, start_bus = 0, end_bus = 255 }
+//! acpi_tables = 0xe_0000
+//!
+//! [[devices]]
+//! type = "uart16550"      # or "pic", "pit", "rtc", "acpi-pm", "pci", "fw-cfg"
+//! port = 0x3f8
+//! irq = 4
+//! backend = "mux"         # or "console", { type = "ring", size = 4096 },
+//!                         # { type = "file", path = "com1.log" }, { type = "scripted", input = "ls\n" }
+//!
+//! [[cpuid]]
+//! leaf = 0x1
+//! subleaf = 0             # optional, matches any subleaf by default
+//! ecx = 0x8000_0000       # replaces the result registers given
+//! ```
+
+mod toml;
+
+use alloc::string::{String, ToString};
+use alloc::{format, vec, vec::Vec};
+
+use axerrno::{ax_err, AxError, AxResult};
+use axvm::{GuestPhysAddr, HostPhysAddr};
+use spin::Once;
+
+use self::toml::{Table, Value};
+use crate::boot::BootProtocol;
+use crate::device_emu::CharBackendConfig;
+use crate::gconfig::*;
+
+/// A guest RAM region.
+#[derive(Debug, Clone)]
+pub struct MemoryRegionConfig {
+    pub gpa: GuestPhysAddr,
+    pub size: usize,
+    /// Allocate the pages on the first access instead of up front.
+    pub lazy: bool,
+}
+
+/// A host MMIO region mapped to the guest.
+#[derive(Debug, Clone)]
+pub struct PassthroughConfig {
+    pub gpa: GuestPhysAddr,
+    pub hpa: HostPhysAddr,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    pub protocol: BootProtocol,
+    pub image: String,
+    /// The BIOS image and its load address, which enters raw Multiboot images.
+    /// With the firmware protocol, the firmware image mapped at the top of 4 GiB.
+    pub bios: (String, GuestPhysAddr),
+    /// Load address and entry of raw Multiboot images.
+    pub load_addr: GuestPhysAddr,
+    pub cmdline: String,
+    pub initrd: Option<String>,
+    /// Boot modules as `(file name, command line)`.
+    pub modules: Vec<(String, String)>,
+    /// Guest physical address of the boot information.
+    pub info_addr: GuestPhysAddr,
+}
+
+/// The platform described to the guest by the ACPI tables.
+#[derive(Debug, Clone)]
+pub struct PlatformConfig {
+    pub hpet: Option<GuestPhysAddr>,
+    /// PCI Express ECAM base, start and end bus numbers.
+    pub pci_ecam: Option<(GuestPhysAddr, u8, u8)>,
+    pub acpi_tables: GuestPhysAddr,
+}
+
+/// An emulated device.
+#[derive(Debug, Clone)]
+pub enum DeviceConfig {
+    /// An Intel 8259 PIC at the given I/O port base, 0x20 for the master.
+    Pic { port: u16 },
+    /// The Intel 8254 PIT and the system control port B.
+    Pit,
+    /// The MC146818 RTC, with the initial time in seconds since the Unix epoch.
+    Rtc {
+        base_time: u64,
+        nvram_file: Option<String>,
+    },
+    /// The ACPI PM registers and the reset register.
+    AcpiPm,
+    /// The PCI configuration ports with an i440FX host bridge and a PIIX3
+    /// PCI-to-ISA bridge.
+    Pci,
+    /// The QEMU firmware configuration device.
+    FwCfg,
+    Uart16550 {
+        port: u16,
+        irq: u8,
+        backend: CharBackendConfig,
+    },
+}
+
+/// Replaces the result registers of a CPUID leaf.
+#[derive(Debug, Clone)]
+pub struct CpuidOverride {
+    pub leaf: u32,
+    /// `None` matches any subleaf.
+    pub subleaf: Option<u32>,
+    /// New values of EAX, EBX, ECX and EDX, `None` to keep the register.
+    pub regs: [Option<u32>; 4],
+}
+
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    pub name: String,
+    pub vcpus: usize,
+    /// Use VMX APIC virtualization if supported.
+    pub apic_virt: bool,
+    pub memory: Vec<MemoryRegionConfig>,
+    pub passthrough: Vec<PassthroughConfig>,
+    pub boot: BootConfig,
+    pub platform: PlatformConfig,
+    pub devices: Vec<DeviceConfig>,
+    pub cpuid: Vec<CpuidOverride>,
+}
+
+static VM_CONFIG: Once<VmConfig> = Once::new();
+
+/// Loads the VM configuration from `path`, or uses the defaults if the file
+/// does not exist.
+pub fn init(path: &str) -> AxResult {
+    let config = match std::fs::read_to_string(path) {
+        Ok(text) => {
+            info!("Loading VM config from {}", path);
+            VmConfig::parse(&text)?
+        }
+        Err(err) => {
+            info!("No VM config file {} ({:?}), using defaults", path, err);
+            VmConfig::default()
+        }
+    };
+    config.validate()?;
+    debug!("{:#x?}", config);
+    VM_CONFIG.call_once(|| config);
+    Ok(())
+}
+
+/// The VM configuration, must be called after [`init`].
+pub fn vm_config() -> &'static VmConfig {
+    VM_CONFIG.get().expect("VM config is not loaded")
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        let mut devices = vec![
+            DeviceConfig::Pic { port: 0x20 },
+            DeviceConfig::Pic { port: 0xa0 },
+            DeviceConfig::Pit,
+            DeviceConfig::Rtc {
+                base_time: RTC_BASE_TIME,
+                nvram_file: RTC_NVRAM_FILE.map(ToString::to_string),
+            },
+            DeviceConfig::AcpiPm,
+        ];
+        let serial_ports = SERIAL_PORTS.iter().zip(SERIAL_BACKENDS);
+        devices.extend(serial_ports.filter_map(|(&(port, irq), backend)| {
+            Some(DeviceConfig::Uart16550 {
+                port,
+                irq,
+                backend: backend?,
+            })
+        }));
+
+        let mut passthrough = vec![PassthroughConfig {
+            gpa: IOAPIC_BASE,
+            hpa: HostPhysAddr::from(IOAPIC_BASE),
+            size: 0x1000,
+        }];
+        if let Some(hpet) = HPET_BASE {
+            passthrough.push(PassthroughConfig {
+                gpa: hpet,
+                hpa: HostPhysAddr::from(hpet),
+                size: 0x1000,
+            });
+        }
+
+        Self {
+            name: VM_NAME.into(),
+            vcpus: MAX_VCPUS,
+            apic_virt: ENABLE_APIC_VIRT,
+            memory: vec![MemoryRegionConfig {
+                gpa: GUEST_PHYS_MEMORY_BASE,
+                size: GUEST_PHYS_MEMORY_SIZE,
+                lazy: false,
+            }],
+            passthrough,
+            boot: BootConfig {
+                protocol: BOOT_PROTOCOL,
+                image: GUEST_IMAGE.into(),
+                bios: (BIOS_IMAGE.into(), BIOS_ENTRY),
+                load_addr: GUEST_ENTRY,
+                cmdline: GUEST_CMDLINE.into(),
+                initrd: GUEST_INITRD.map(Into::into),
+                modules: GUEST_BOOT_MODULES
+                    .iter()
+                    .map(|&(file, cmdline)| (file.into(), cmdline.into()))
+                    .collect(),
+                info_addr: BOOT_INFO_GPA,
+            },
+            platform: PlatformConfig {
+                hpet: HPET_BASE,
+                pci_ecam: PCI_ECAM,
+                acpi_tables: ACPI_TABLES_GPA,
+            },
+            devices,
+            cpuid: Vec::new(),
+        }
+    }
+}
+
+impl VmConfig {
+    /// Parses the TOML configuration, missing keys take the default values and
+    /// unknown keys are rejected.
+    pub fn parse(text: &str) -> AxResult<Self> {
+        let root = toml::parse(text)?;
+        let root = Reader::new(&root, "");
+        root.check_keys(&[
+            "name",
+            "vcpus",
+            "apic_virt",
+            "memory",
+            "passthrough",
+            "boot",
+            "platform",
+            "devices",
+            "cpuid",
+        ])?;
+        let mut config = Self::default();
+
+        if let Some(name) = root.string("name")? {
+            config.name = name;
+        }
+        if let Some(vcpus) = root.int("vcpus")? {
+            config.vcpus = vcpus;
+        }
+        if let Some(apic_virt) = root.bool("apic_virt")? {
+            config.apic_virt = apic_virt;
+        }
+        if root.contains("memory") {
+            config.memory = root
+                .tables("memory")?
+                .iter()
+                .map(|r| {
+                    r.check_keys(&["gpa", "size", "lazy"])?;
+                    Ok(MemoryRegionConfig {
+                        gpa: r.required_int("gpa")?,
+                        size: r.required_int("size")?,
+                        lazy: r.bool("lazy")?.unwrap_or(false),
+                    })
+                })
+                .collect::<AxResult<_>>()?;
+        }
+        if root.contains("passthrough") {
+            config.passthrough = root
+                .tables("passthrough")?
+                .iter()
+                .map(|r| {
+                    r.check_keys(&["gpa", "hpa", "size"])?;
+                    let gpa = r.required_int("gpa")?;
+                    Ok(PassthroughConfig {
+                        gpa,
+                        hpa: HostPhysAddr::from(r.int("hpa")?.unwrap_or(gpa)),
+                        size: r.required_int("size")?,
+                    })
+                })
+                .collect::<AxResult<_>>()?;
+        }
+        if let Some(boot) = root.table("boot")? {
+            config.boot.parse(&boot)?;
+        }
+        if let Some(platform) = root.table("platform")? {
+            config.platform.parse(&platform)?;
+        }
+        if root.contains("devices") {
+            config.devices = root
+                .tables("devices")?
+                .iter()
+                .map(DeviceConfig::parse)
+                .collect::<AxResult<_>>()?;
+        }
+        if root.contains("cpuid") {
+            config.cpuid = root
+                .tables("cpuid")?
+                .iter()
+                .map(|r| {
+                    r.check_keys(&["leaf", "subleaf", "eax", "ebx", "ecx", "edx"])?;
+                    Ok(CpuidOverride {
+                        leaf: r.required_int("leaf")?,
+                        subleaf: r.int("subleaf")?,
+                        regs: [r.int("eax")?, r.int("ebx")?, r.int("ecx")?, r.int("edx")?],
+                    })
+                })
+                .collect::<AxResult<_>>()?;
+        }
+        Ok(config)
+    }
+
+    fn validate(&self) -> AxResult {
+        if self.vcpus == 0 || self.vcpus > MAX_VCPUS {
+            return ax_err!(
+                InvalidInput,
+                format!(
+                    "VM config: {} vCPUs, 1 to {} are supported",
+                    self.vcpus, MAX_VCPUS
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// The CPUID override of `leaf` and `subleaf`, if any.
+    pub fn cpuid_override(&self, leaf: u32, subleaf: u32) -> Option<&CpuidOverride> {
+        self.cpuid
+            .iter()
+            .find(|c| c.leaf == leaf && c.subleaf.map_or(true, |s| s == subleaf))
+    }
+}
+
+impl BootConfig {
+    fn parse(&mut self, r: &Reader) -> AxResult {
+        r.check_keys(&[
+            "protocol",
+            "image",
+            "bios",
+            "bios_addr",
+            "load_addr",
+            "cmdline",
+            "initrd",
+            "modules",
+            "info_addr",
+        ])?;
+        if let Some(protocol) = r.string("protocol")? {
+            self.protocol = match protocol.as_str() {
+                "multiboot" => BootProtocol::Multiboot,
+                "multiboot2" => BootProtocol::Multiboot2,
+                "linux" => BootProtocol::Linux,
+                "firmware" => BootProtocol::Firmware,
+                _ => return Err(r.invalid("protocol", &protocol)),
+            };
+        }
+        if let Some(image) = r.string("image")? {
+            self.image = image;
+        }
+        if let Some(bios) = r.string("bios")? {
+            self.bios.0 = bios;
+        }
+        if let Some(bios_addr) = r.int("bios_addr")? {
+            self.bios.1 = bios_addr;
+        }
+        if let Some(load_addr) = r.int("load_addr")? {
+            self.load_addr = load_addr;
+        }
+        if let Some(cmdline) = r.string("cmdline")? {
+            self.cmdline = cmdline;
+        }
+        if let Some(initrd) = r.string("initrd")? {
+            self.initrd = Some(initrd);
+        }
+        if r.contains("modules") {
+            self.modules = r
+                .tables("modules")?
+                .iter()
+                .map(|m| {
+                    m.check_keys(&["file", "cmdline"])?;
+                    let file = m.required_string("file")?;
+                    Ok((file, m.string("cmdline")?.unwrap_or_default()))
+                })
+                .collect::<AxResult<_>>()?;
+        }
+        if let Some(info_addr) = r.int("info_addr")? {
+            self.info_addr = info_addr;
+        }
+        Ok(())
+    }
+}
+
+impl PlatformConfig {
+    fn parse(&mut self, r: &Reader) -> AxResult {
+        r.check_keys(&["hpet", "pci_ecam", "acpi_tables"])?;
+        match r.value("hpet") {
+            None => {}
+            // there is no null in TOML, `false` removes the HPET.
+            Some(Value::Boolean(false)) => self.hpet = None,
+            Some(_) => self.hpet = r.int("hpet")?,
+        }
+        if let Some(ecam) = r.table("pci_ecam")? {
+            ecam.check_keys(&["base", "start_bus", "end_bus"])?;
+            self.pci_ecam = Some((
+                ecam.required_int("base")?,
+                ecam.int("start_bus")?.unwrap_or(0),
+                ecam.int("end_bus")?.unwrap_or(255),
+            ));
+        }
+        if let Some(acpi_tables) = r.int("acpi_tables")? {
+            self.acpi_tables = acpi_tables;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceConfig {
+    fn parse(r: &Reader) -> AxResult<Self> {
+        let typ = r.required_string("type")?;
+        let (device, keys): (_, &[&str]) = match typ.as_str() {
+            "pic" => (
+                Self::Pic {
+                    port: r.required_int("port")?,
+                },
+                &["type", "port"],
+            ),
+            "pit" => (Self::Pit, &["type"]),
+            "rtc" => (
+                Self::Rtc {
+                    base_time: r.int("base_time")?.unwrap_or(RTC_BASE_TIME),
+                    nvram_file: r.string("nvram")?,
+                },
+                &["type", "base_time", "nvram"],
+            ),
+            "acpi-pm" => (Self::AcpiPm, &["type"]),
+            "pci" => (Self::Pci, &["type"]),
+            "fw-cfg" => (Self::FwCfg, &["type"]),
+            "uart16550" => (
+                Self::Uart16550 {
+                    port: r.required_int("port")?,
+                    irq: r.required_int("irq")?,
+                    backend: match r.value("backend") {
+                        None => CharBackendConfig::Mux,
+                        Some(Value::String(_)) => match r.required_string("backend")?.as_str() {
+                            "console" => CharBackendConfig::Console,
+                            "mux" => CharBackendConfig::Mux,
+                            other => return Err(r.invalid("backend", other)),
+                        },
+                        Some(_) => Self::parse_backend(&r.table("backend")?.unwrap())?,
+                    },
+                },
+                &["type", "port", "irq", "backend"],
+            ),
+            _ => return Err(r.invalid("type", &typ)),
+        };
+        r.check_keys(keys)?;
+        Ok(device)
+    }
+
+    /// A serial port backend given as a table.
+    fn parse_backend(r: &Reader) -> AxResult<CharBackendConfig> {
+        let typ = r.required_string("type")?;
+        let (backend, keys): (_, &[&str]) = match typ.as_str() {
+            "ring" => (
+                CharBackendConfig::RingBuffer(r.required_int("size")?),
+                &["type", "size"],
+            ),
+            "file" => (
+                CharBackendConfig::File(r.required_string("path")?),
+                &["type", "path"],
+            ),
+            "scripted" => (
+                CharBackendConfig::Scripted(r.required_string("input")?.into_bytes()),
+                &["type", "input"],
+            ),
+            _ => return Err(r.invalid("type", &typ)),
+        };
+        r.check_keys(keys)?;
+        Ok(backend)
+    }
+}
+
+/// Reads typed values from a TOML table, `path` names the table in errors.
+struct Reader<'a> {
+    table: &'a Table,
+    path: String,
+}
+
+impl<'a> Reader<'a> {
+    fn new(table: &'a Table, path: &str) -> Self {
+        Self {
+            table,
+            path: path.into(),
+        }
+    }
+
+    fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.into()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn error(&self, key: &str, msg: &str) -> AxError {
+        warn!("VM config: `{}`: {}", self.key_path(key), msg);
+        AxError::InvalidData
+    }
+
+    fn type_error(&self, key: &str, expected: &str) -> AxError {
+        let found = self.table.get(key).map_or("nothing", Value::type_name);
+        self.error(key, &format!("expected {}, found {}", expected, found))
+    }
+
+    fn invalid(&self, key: &str, value: &str) -> AxError {
+        self.error(key, &format!("invalid value `{}`", value))
+    }
+
+    /// Rejects the keys not in `keys`, e.g. misspelled ones.
+    fn check_keys(&self, keys: &[&str]) -> AxResult {
+        match self.table.keys().find(|k| !keys.contains(&k.as_str())) {
+            Some(key) => Err(self.error(key, "unknown key")),
+            None => Ok(()),
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.table.contains_key(key)
+    }
+
+    fn value(&self, key: &str) -> Option<&'a Value> {
+        self.table.get(key)
+    }
+
+    fn string(&self, key: &str) -> AxResult<Option<String>> {
+        match self.value(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(self.type_error(key, "a string")),
+        }
+    }
+
+    fn required_string(&self, key: &str) -> AxResult<String> {
+        self.string(key)?
+            .ok_or_else(|| self.type_error(key, "a string"))
+    }
+
+    fn int<T: TryFrom<i64>>(&self, key: &str) -> AxResult<Option<T>> {
+        match self.value(key) {
+            None => Ok(None),
+            Some(&Value::Integer(i)) => T::try_from(i)
+                .map(Some)
+                .map_err(|_| self.error(key, &format!("{:#x} is out of range", i))),
+            Some(_) => Err(self.type_error(key, "an integer")),
+        }
+    }
+
+    fn required_int<T: TryFrom<i64>>(&self, key: &str) -> AxResult<T> {
+        self.int(key)?
+            .ok_or_else(|| self.type_error(key, "an integer"))
+    }
+
+    fn bool(&self, key: &str) -> AxResult<Option<bool>> {
+        match self.value(key) {
+            None => Ok(None),
+            Some(&Value::Boolean(b)) => Ok(Some(b)),
+            Some(_) => Err(self.type_error(key, "a boolean")),
+        }
+    }
+
+    fn table(&self, key: &str) -> AxResult<Option<Reader<'a>>> {
+        match self.value(key) {
+            None => Ok(None),
+            Some(Value::Table(t)) => Ok(Some(Reader::new(t, &self.key_path(key)))),
+            Some(_) => Err(self.type_error(key, "a table")),
+        }
+    }
+
+    /// An array of tables.
+    fn tables(&self, key: &str) -> AxResult<Vec<Reader<'a>>> {
+        let Some(Value::Array(array)) = self.value(key) else {
+            return Err(self.type_error(key, "an array of tables"));
+        };
+        array
+            .iter()
+            .enumerate()
+            .map(|(i, value)| match value {
+                Value::Table(t) => Ok(Reader::new(t, &format!("{}[{}]", self.key_path(key), i))),
+                _ => Err(self.error(key, "expected an array of tables")),
+            })
+            .collect()
+    }
+}
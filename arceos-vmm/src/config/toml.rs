@@ -0,0 +1,515 @@
+//! A minimal TOML parser for the VM configuration file. (ref: https://toml.io/en/v1.0.0)
+//!
+//! Supported: tables, arrays of tables, dotted keys, basic and literal strings,
+//! integers (decimal, hex, octal, binary), booleans, arrays and inline tables.
+//! Floats, dates and multi-line strings are not supported.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::{format, vec::Vec};
+
+use axerrno::{AxError, AxResult};
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Integer(_) => "integer",
+            Self::Boolean(_) => "boolean",
+            Self::Array(_) => "array",
+            Self::Table(_) => "table",
+        }
+    }
+}
+
+/// Parses a TOML document into its root table.
+pub fn parse(text: &str) -> AxResult<Table> {
+    let mut parser = Parser {
+        bytes: text.as_bytes(),
+        pos: 0,
+        line: 1,
+    };
+    parser.parse_document()
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &str) -> AxError {
+        warn!("VM config line {}: {}", self.line, msg);
+        AxError::InvalidData
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == b'\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> AxResult {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c as char)))
+        }
+    }
+
+    /// Skips spaces and tabs.
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some(b'#') {
+            while !matches!(self.peek(), None | Some(b'\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_ws();
+            self.skip_comment();
+            match self.peek() {
+                Some(b'\n' | b'\r') => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Expects the end of the line, allowing a trailing comment.
+    fn expect_line_end(&mut self) -> AxResult {
+        self.skip_ws();
+        self.skip_comment();
+        self.eat(b'\r');
+        match self.peek() {
+            None => Ok(()),
+            Some(b'\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some(_) => Err(self.error("expected the end of line")),
+        }
+    }
+
+    fn parse_document(&mut self) -> AxResult<Table> {
+        let mut root = Table::new();
+        let mut current: Vec<String> = Vec::new();
+        // tables created by headers of their sub-tables, which can still be
+        // defined by their own headers later.
+        let mut implicit = BTreeSet::new();
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => return Ok(root),
+                Some(b'[') => {
+                    self.bump();
+                    let is_array = self.eat(b'[');
+                    self.skip_ws();
+                    let path = self.parse_key()?;
+                    self.expect(b']')?;
+                    if is_array {
+                        self.expect(b']')?;
+                    }
+                    let (last, parent_path) = path.split_last().unwrap();
+                    let parent = self.table_at(&mut root, parent_path, Some(&mut implicit))?;
+                    if is_array {
+                        let entry = parent
+                            .entry(last.clone())
+                            .or_insert_with(|| Value::Array(Vec::new()));
+                        let Value::Array(array) = entry else {
+                            return Err(self.error(&format!("`{}` is not an array", last)));
+                        };
+                        array.push(Value::Table(Table::new()));
+                        // the sub-tables belong to the previous element.
+                        implicit.retain(|p: &Vec<String>| !p.starts_with(&path));
+                    } else if !parent.contains_key(last) {
+                        parent.insert(last.clone(), Value::Table(Table::new()));
+                    } else if !implicit.remove(&path)
+                        || !matches!(parent.get(last), Some(Value::Table(_)))
+                    {
+                        return Err(self.error(&format!("table `{}` defined twice", last)));
+                    }
+                    current = path;
+                }
+                Some(_) => {
+                    let (key, value) = self.parse_key_value()?;
+                    let table = self.table_at(&mut root, &current, None)?;
+                    self.insert(table, &key, value)?;
+                }
+            }
+            self.expect_line_end()?;
+        }
+    }
+
+    /// The table at `path` from `root`, creating the missing tables, whose
+    /// paths are added to `implicit` if given. For an array of tables, the
+    /// last element is used.
+    fn table_at<'t>(
+        &self,
+        root: &'t mut Table,
+        path: &[String],
+        mut implicit: Option<&mut BTreeSet<Vec<String>>>,
+    ) -> AxResult<&'t mut Table> {
+        let mut table = root;
+        for (i, key) in path.iter().enumerate() {
+            if !table.contains_key(key) {
+                if let Some(implicit) = implicit.as_deref_mut() {
+                    implicit.insert(path[..=i].to_vec());
+                }
+            }
+            let value = table
+                .entry(key.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            table = match value {
+                Value::Table(t) => t,
+                Value::Array(a) => match a.last_mut() {
+                    Some(Value::Table(t)) => t,
+                    _ => return Err(self.error(&format!("`{}` is not a table", key))),
+                },
+                _ => return Err(self.error(&format!("`{}` is not a table", key))),
+            };
+        }
+        Ok(table)
+    }
+
+    /// Inserts a value with a dotted key.
+    fn insert(&self, table: &mut Table, key: &[String], value: Value) -> AxResult {
+        let (last, parent) = key.split_last().unwrap();
+        let table = self.table_at(table, parent, None)?;
+        if table.contains_key(last) {
+            return Err(self.error(&format!("key `{}` defined twice", last)));
+        }
+        table.insert(last.clone(), value);
+        Ok(())
+    }
+
+    fn parse_key_value(&mut self) -> AxResult<(Vec<String>, Value)> {
+        let key = self.parse_key()?;
+        self.expect(b'=')?;
+        self.skip_ws();
+        let value = self.parse_value()?;
+        Ok((key, value))
+    }
+
+    /// Parses a (dotted) key, and the whitespace after it.
+    fn parse_key(&mut self) -> AxResult<Vec<String>> {
+        let mut key = Vec::new();
+        loop {
+            let part = match self.peek() {
+                Some(b'"') => self.parse_basic_string()?,
+                Some(b'\'') => self.parse_literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+                    {
+                        self.bump();
+                    }
+                    if start == self.pos {
+                        return Err(self.error("expected a key"));
+                    }
+                    String::from_utf8_lossy(&self.bytes[start..self.pos]).into_owned()
+                }
+            };
+            key.push(part);
+            self.skip_ws();
+            if !self.eat(b'.') {
+                return Ok(key);
+            }
+            self.skip_ws();
+        }
+    }
+
+    fn parse_value(&mut self) -> AxResult<Value> {
+        match self.peek() {
+            Some(b'"') => Ok(Value::String(self.parse_basic_string()?)),
+            Some(b'\'') => Ok(Value::String(self.parse_literal_string()?)),
+            Some(b'[') => self.parse_array(),
+            Some(b'{') => self.parse_inline_table(),
+            Some(b't' | b'f') => {
+                let rest = &self.bytes[self.pos..];
+                let (value, len) = if rest.starts_with(b"true") {
+                    (true, 4)
+                } else if rest.starts_with(b"false") {
+                    (false, 5)
+                } else {
+                    return Err(self.error("invalid value"));
+                };
+                self.pos += len;
+                Ok(Value::Boolean(value))
+            }
+            Some(c) if c.is_ascii_digit() || c == b'+' || c == b'-' => self.parse_integer(),
+            _ => Err(self.error("invalid value")),
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> AxResult<String> {
+        self.expect(b'"')?;
+        let mut s = Vec::new();
+        loop {
+            match self.bump() {
+                None | Some(b'\n') => return Err(self.error("unterminated string")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let c = match self.bump() {
+                        Some(b'n') => b'\n',
+                        Some(b't') => b'\t',
+                        Some(b'r') => b'\r',
+                        Some(b'0') => 0,
+                        Some(c @ (b'"' | b'\\')) => c,
+                        _ => return Err(self.error("invalid escape sequence")),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+            }
+        }
+        String::from_utf8(s).map_err(|_| self.error("invalid UTF-8 string"))
+    }
+
+    fn parse_literal_string(&mut self) -> AxResult<String> {
+        self.expect(b'\'')?;
+        let start = self.pos;
+        loop {
+            match self.bump() {
+                None | Some(b'\n') => return Err(self.error("unterminated string")),
+                Some(b'\'') => break,
+                Some(_) => {}
+            }
+        }
+        Ok(String::from_utf8_lossy(&self.bytes[start..self.pos - 1]).into_owned())
+    }
+
+    fn parse_integer(&mut self) -> AxResult<Value> {
+        let negative = self.eat(b'-');
+        if !negative {
+            self.eat(b'+');
+        }
+        let rest = &self.bytes[self.pos..];
+        let radix = match rest {
+            [b'0', b'x', ..] => 16,
+            [b'0', b'o', ..] => 8,
+            [b'0', b'b', ..] => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.pos += 2;
+        }
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'_') {
+            self.bump();
+        }
+        let digits: String = self.bytes[start..self.pos]
+            .iter()
+            .filter(|&&c| c != b'_')
+            .map(|&c| c as char)
+            .collect();
+        let value = i64::from_str_radix(&digits, radix)
+            .map_err(|_| self.error(&format!("invalid integer `{}`", digits)))?;
+        Ok(Value::Integer(if negative { -value } else { value }))
+    }
+
+    fn parse_array(&mut self) -> AxResult<Value> {
+        self.expect(b'[')?;
+        let mut array = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.eat(b']') {
+                return Ok(Value::Array(array));
+            }
+            array.push(self.parse_value()?);
+            self.skip_blank();
+            if !self.eat(b',') {
+                self.skip_blank();
+                self.expect(b']')?;
+                return Ok(Value::Array(array));
+            }
+        }
+    }
+
+    fn parse_inline_table(&mut self) -> AxResult<Value> {
+        self.expect(b'{')?;
+        let mut table = Table::new();
+        self.skip_ws();
+        if self.eat(b'}') {
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_ws();
+            let (key, value) = self.parse_key_value()?;
+            self.insert(&mut table, &key, value)?;
+            self.skip_ws();
+            if self.eat(b'}') {
+                return Ok(Value::Table(table));
+            }
+            self.expect(b',')?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value at the dotted `path`, using the last element of arrays of tables.
+    fn get<'a>(table: &'a Table, path: &str) -> &'a Value {
+        let (parent, last) = match path.rsplit_once('.') {
+            Some((parent, last)) => (parent.split('.').collect(), last),
+            None => (Vec::new(), path),
+        };
+        let mut table = table;
+        for key in parent {
+            table = match &table[key] {
+                Value::Table(t) => t,
+                Value::Array(a) => match a.last() {
+                    Some(Value::Table(t)) => t,
+                    v => panic!("`{}` is not a table: {:?}", key, v),
+                },
+                v => panic!("`{}` is not a table: {:?}", key, v),
+            };
+        }
+        &table[last]
+    }
+
+    fn int(table: &Table, path: &str) -> i64 {
+        match get(table, path) {
+            Value::Integer(i) => *i,
+            v => panic!("`{}` is not an integer: {:?}", path, v),
+        }
+    }
+
+    fn string<'a>(table: &'a Table, path: &str) -> &'a str {
+        match get(table, path) {
+            Value::String(s) => s,
+            v => panic!("`{}` is not a string: {:?}", path, v),
+        }
+    }
+
+    #[test]
+    fn dotted_keys() {
+        let doc = parse("a.b = 1\na . \"c.d\" = 2\n[t]\nx.y.z = 'v'\n").unwrap();
+        assert_eq!(int(&doc, "a.b"), 1);
+        let Value::Table(a) = get(&doc, "a") else {
+            panic!("`a` is not a table");
+        };
+        assert!(matches!(a["c.d"], Value::Integer(2)));
+        assert_eq!(string(&doc, "t.x.y.z"), "v");
+    }
+
+    #[test]
+    fn arrays_of_tables() {
+        let doc = parse(
+            "[[dev]]\ntype = \"pic\"\n[dev.opt]\nport = 1\n\
+             [[dev]]\ntype = \"uart\"\n[dev.opt]\nport = 2\n",
+        )
+        .unwrap();
+        let Value::Array(devs) = &doc["dev"] else {
+            panic!("`dev` is not an array");
+        };
+        assert_eq!(devs.len(), 2);
+        for (dev, (ty, port)) in devs.iter().zip([("pic", 1), ("uart", 2)]) {
+            let Value::Table(dev) = dev else {
+                panic!("`dev` element is not a table");
+            };
+            assert_eq!(string(dev, "type"), ty);
+            assert_eq!(int(dev, "opt.port"), port);
+        }
+    }
+
+    #[test]
+    fn inline_tables() {
+        let doc = parse("a = { x = 1, y.z = \"s\", e = {} }\nb = [{ k = true }, {}]\n").unwrap();
+        assert_eq!(int(&doc, "a.x"), 1);
+        assert_eq!(string(&doc, "a.y.z"), "s");
+        assert!(matches!(get(&doc, "a.e"), Value::Table(t) if t.is_empty()));
+        let Value::Array(b) = &doc["b"] else {
+            panic!("`b` is not an array");
+        };
+        assert!(matches!(&b[0], Value::Table(t) if matches!(t["k"], Value::Boolean(true))));
+    }
+
+    #[test]
+    fn integers() {
+        let doc = parse(
+            "dec = 1_000\nneg = -42\npos = +7\nhex = 0xdead_BEEF\n\
+             oct = 0o7_55\nbin = 0b1010_0101\n",
+        )
+        .unwrap();
+        assert_eq!(int(&doc, "dec"), 1000);
+        assert_eq!(int(&doc, "neg"), -42);
+        assert_eq!(int(&doc, "pos"), 7);
+        assert_eq!(int(&doc, "hex"), 0xdead_beef);
+        assert_eq!(int(&doc, "oct"), 0o755);
+        assert_eq!(int(&doc, "bin"), 0b1010_0101);
+    }
+
+    #[test]
+    fn implicit_tables() {
+        let doc = parse("[a.b]\nx = 1\n[a]\ny = 2\n").unwrap();
+        assert_eq!(int(&doc, "a.b.x"), 1);
+        assert_eq!(int(&doc, "a.y"), 2);
+        let doc = parse("[[a]]\n[a.b.c]\n[[a]]\n[a.b]\n[a.b.c]\n").unwrap();
+        assert!(matches!(get(&doc, "a.b.c"), Value::Table(_)));
+    }
+
+    #[test]
+    fn errors() {
+        for doc in [
+            "[a]\n[a]\n",
+            "[a.b]\n[a]\n[a]\n",
+            "[a.b]\n[[a]]\n",
+            "[a]\nb.c = 1\n[a.b]\n",
+            "a = { x = 1 }\n[a]\n",
+            "a = 1\na = 2\n",
+            "a = 1\n[a.b]\n",
+            "a = { x = 1, x = 2 }\n",
+            "a = 0x\n",
+            "a = 0b102\n",
+            "a = \"unterminated\n",
+            "a = \"\\q\"\n",
+            "a = 1 b = 2\n",
+            "a = [1, 2\n",
+            "= 1\n",
+            "a = truthy\n",
+        ] {
+            assert!(parse(doc).is_err(), "{:?} should be rejected", doc);
+        }
+    }
+}
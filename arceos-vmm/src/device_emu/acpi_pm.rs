@@ -0,0 +1,214 @@
+//! Emulated ACPI fixed hardware: the PM1a event/control registers, the PM timer
+//! and the reset register. (ref: ACPI Specification 6.5, Section 4.8)
+
+use super::{LifecycleEvent, PortIoDevice};
+use crate::hal::AxvmHalImpl;
+use axerrno::{AxError, AxResult};
+use axvm::AxvmHal;
+use spin::Mutex;
+
+/// Base port of the PM registers.
+const PM_PORT_BASE: u16 = 0x600;
+const PM_PORT_LEN: u16 = 12;
+/// PM1a event registers, 4 bytes.
+pub const PM1A_EVT_BLK: u16 = PM_PORT_BASE;
+/// PM1a control register, 2 bytes.
+pub const PM1A_CNT_BLK: u16 = PM_PORT_BASE + 4;
+/// PM timer, 4 bytes.
+pub const PM_TMR_BLK: u16 = PM_PORT_BASE + 8;
+
+/// The reset register, i.e. the reset control register of the PCH.
+pub const RESET_REG_PORT: u16 = 0xcf9;
+pub const RESET_VALUE: u8 = 0x06;
+/// The reset CPU bit of the reset register, the type of reset (bit 1) is ignored.
+const RESET_CPU: u8 = 1 << 2;
+
+/// `SLP_TYP` of the S5 (soft off) sleeping state, as in the `\_S5` object.
+pub const SLP_TYP_S5: u8 = 5;
+/// The IRQ of the system control interrupt (SCI).
+pub const SCI_IRQ: u8 = 9;
+
+const PM_TIMER_FREQUENCY_HZ: u64 = 3_579_545;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// `TMR_STS` is set when bit 23 of the 24-bit PM timer toggles.
+const PM_TIMER_CARRY_BIT: u32 = 1 << 23;
+const PM_TIMER_MASK: u32 = 0xff_ffff;
+
+bitflags::bitflags! {
+    /// PM1 status and enable bits. (ACPI Specification 6.5, Section 4.8.3.1)
+    #[derive(Clone, Copy)]
+    struct Pm1Event: u16 {
+        const TMR = 1;
+        const BM = 1 << 4;
+        const GBL = 1 << 5;
+        const PWRBTN = 1 << 8;
+        const SLPBTN = 1 << 9;
+        const RTC = 1 << 10;
+        const WAK = 1 << 15;
+    }
+
+    /// PM1 control bits. (ACPI Specification 6.5, Section 4.8.3.2)
+    #[derive(Clone, Copy)]
+    struct Pm1Control: u16 {
+        const SCI_EN = 1;
+        const BM_RLD = 1 << 1;
+        const GBL_RLS = 1 << 2;
+        const SLP_TYP = 0b111 << 10;
+        const SLP_EN = 1 << 13;
+    }
+}
+
+fn pm_timer(now: u64) -> u32 {
+    let ticks = now as u128 * PM_TIMER_FREQUENCY_HZ as u128 / NANOS_PER_SEC as u128;
+    ticks as u32 & PM_TIMER_MASK
+}
+
+struct AcpiPm {
+    pm1_sts: Pm1Event,
+    pm1_en: Pm1Event,
+    pm1_cnt: Pm1Control,
+    last_timer: u32,
+}
+
+impl AcpiPm {
+    fn new() -> Self {
+        Self {
+            pm1_sts: Pm1Event::empty(),
+            pm1_en: Pm1Event::empty(),
+            // no SMI command port, ACPI mode is always enabled
+            pm1_cnt: Pm1Control::SCI_EN,
+            last_timer: 0,
+        }
+    }
+
+    fn sci_level(&self) -> bool {
+        self.pm1_cnt.contains(Pm1Control::SCI_EN) && self.pm1_sts.intersects(self.pm1_en)
+    }
+
+    /// The PM register block as bytes.
+    fn regs(&self, now: u64) -> [u8; PM_PORT_LEN as usize] {
+        let mut regs = [0; PM_PORT_LEN as usize];
+        regs[0..2].copy_from_slice(&self.pm1_sts.bits().to_le_bytes());
+        regs[2..4].copy_from_slice(&self.pm1_en.bits().to_le_bytes());
+        regs[4..6].copy_from_slice(&self.pm1_cnt.bits().to_le_bytes());
+        regs[8..12].copy_from_slice(&pm_timer(now).to_le_bytes());
+        regs
+    }
+
+    fn read(&self, offset: usize, access_size: u8, now: u64) -> u32 {
+        let regs = self.regs(now);
+        let mut bytes = [0; 4];
+        for (i, b) in bytes.iter_mut().take(access_size as usize).enumerate() {
+            *b = regs.get(offset + i).copied().unwrap_or(0);
+        }
+        u32::from_le_bytes(bytes)
+    }
+
+    fn write(&mut self, offset: usize, access_size: u8, value: u32) {
+        for i in 0..access_size as usize {
+            let byte = (value >> (8 * i)) as u16 & 0xff;
+            match offset + i {
+                // write 1 to clear the status bits
+                off @ (0 | 1) => {
+                    let clear = byte << (8 * off);
+                    self.pm1_sts &= !Pm1Event::from_bits_truncate(clear);
+                }
+                off @ (2 | 3) => {
+                    let shift = 8 * (off - 2);
+                    let en = (self.pm1_en.bits() & !(0xff << shift)) | (byte << shift);
+                    self.pm1_en = Pm1Event::from_bits_truncate(en);
+                }
+                off @ (4 | 5) => {
+                    let shift = 8 * (off - 4);
+                    let cnt = (self.pm1_cnt.bits() & !(0xff << shift)) | (byte << shift);
+                    self.pm1_cnt = Pm1Control::from_bits_truncate(cnt) | Pm1Control::SCI_EN;
+                }
+                _ => {} // the PM timer is read-only
+            }
+        }
+        if self.pm1_cnt.contains(Pm1Control::SLP_EN) {
+            // SLP_EN is write-only, always reads 0
+            self.pm1_cnt.remove(Pm1Control::SLP_EN);
+            let slp_typ = ((self.pm1_cnt & Pm1Control::SLP_TYP).bits() >> 10) as u8;
+            if slp_typ == SLP_TYP_S5 {
+                info!("ACPI: guest requested power off");
+                super::request_lifecycle_event(LifecycleEvent::Shutdown);
+            } else {
+                warn!("ACPI: unsupported sleeping state SLP_TYP={}", slp_typ);
+            }
+        }
+    }
+
+    fn poll(&mut self, now: u64) {
+        let timer = pm_timer(now);
+        if (timer ^ self.last_timer) & PM_TIMER_CARRY_BIT != 0 {
+            self.pm1_sts |= Pm1Event::TMR;
+        }
+        self.last_timer = timer;
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref VIRT_ACPI_PM: Mutex<AcpiPm> = Mutex::new(AcpiPm::new());
+}
+
+pub struct AcpiPmDevice;
+
+impl PortIoDevice for AcpiPmDevice {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        PM_PORT_BASE..PM_PORT_BASE + PM_PORT_LEN
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> AxResult<u32> {
+        let now = AxvmHalImpl::current_time_nanos();
+        let offset = (port - PM_PORT_BASE) as usize;
+        Ok(VIRT_ACPI_PM.lock().read(offset, access_size, now))
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> AxResult {
+        let mut pm = VIRT_ACPI_PM.lock();
+        pm.write((port - PM_PORT_BASE) as usize, access_size, value);
+        super::set_isa_irq(SCI_IRQ, pm.sci_level());
+        Ok(())
+    }
+
+    fn poll(&self) {
+        let now = AxvmHalImpl::current_time_nanos();
+        let mut pm = VIRT_ACPI_PM.lock();
+        pm.poll(now);
+        super::set_isa_irq(SCI_IRQ, pm.sci_level());
+    }
+
+    fn reset(&self) {
+        *VIRT_ACPI_PM.lock() = AcpiPm::new();
+    }
+}
+
+/// The reset register, writing [`RESET_VALUE`] resets the VM.
+pub struct ResetRegister;
+
+impl PortIoDevice for ResetRegister {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        RESET_REG_PORT..RESET_REG_PORT + 1
+    }
+
+    fn read(&self, _port: u16, access_size: u8) -> AxResult<u32> {
+        if access_size != 1 {
+            error!("Invalid reset register read size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        Ok(0)
+    }
+
+    fn write(&self, _port: u16, access_size: u8, value: u32) -> AxResult {
+        if access_size != 1 {
+            error!("Invalid reset register write size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        if value as u8 & RESET_CPU != 0 {
+            info!("ACPI: guest requested reset");
+            super::request_lifecycle_event(LifecycleEvent::Reset);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Character backends which connect emulated serial devices to the host.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use std::fs::File;
+use std::io::Write;
+
+use axerrno::{AxError, AxResult};
+use spin::Mutex;
+
+use super::console_mux::MuxBackend;
+
+/// The host side of a character device, such as a serial port.
+pub trait CharBackend: Send + Sync {
+    /// Write a byte transmitted by the guest.
+    fn write_byte(&self, byte: u8);
+    /// Read a byte to be received by the guest, `None` if no input available.
+    fn read_byte(&self) -> Option<u8>;
+    /// Write out the buffered output, e.g. when the VM shuts down.
+    fn flush(&self) {}
+}
+
+/// Selects the backend of a serial port.
+#[derive(Debug, Clone)]
+pub enum CharBackendConfig {
+    /// The host console.
+    Console,
+    /// An in-memory ring buffer with the given capacity.
+    RingBuffer(usize),
+    /// Output to a file on the file system, input is always empty.
+    File(String),
+    /// Input from the given bytes, output to the host console.
+    Scripted(Vec<u8>),
+    /// The host console shared through the console multiplexer.
+    Mux,
+}
+
+/// Ring buffer backends by the name of their ports, to inspect the captured output.
+static RING_BUFFERS: Mutex<BTreeMap<String, Arc<RingBufferBackend>>> = Mutex::new(BTreeMap::new());
+
+/// The ring buffer backend of the port `name`.
+pub fn ring_buffer(name: &str) -> Option<Arc<RingBufferBackend>> {
+    RING_BUFFERS.lock().get(name).cloned()
+}
+
+/// Names of all ports with ring buffer backends, and their buffered output sizes.
+pub fn ring_buffers() -> Vec<(String, usize)> {
+    RING_BUFFERS
+        .lock()
+        .iter()
+        .map(|(name, buffer)| (name.clone(), buffer.output_len()))
+        .collect()
+}
+
+impl CharBackendConfig {
+    /// Create the backend, `name` identifies it in the console multiplexer and
+    /// the ring buffer registry.
+    pub fn build(&self, name: String) -> AxResult<Arc<dyn CharBackend>> {
+        Ok(match self {
+            Self::Console => Arc::new(ConsoleBackend),
+            Self::RingBuffer(capacity) => {
+                let backend = Arc::new(RingBufferBackend::new(*capacity));
+                RING_BUFFERS.lock().insert(name, backend.clone());
+                backend
+            }
+            Self::File(path) => Arc::new(FileBackend::create(path)?),
+            Self::Scripted(script) => Arc::new(ScriptedBackend::new(script.clone())),
+            Self::Mux => Arc::new(MuxBackend::new(name)),
+        })
+    }
+}
+
+/// Reads and writes the host console directly.
+pub struct ConsoleBackend;
+
+impl CharBackend for ConsoleBackend {
+    fn write_byte(&self, byte: u8) {
+        axhal::console::putchar(byte);
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        axhal::console::console_getchar()
+    }
+}
+
+/// Keeps the latest output in memory, and the input is pushed by the VMM.
+pub struct RingBufferBackend {
+    capacity: usize,
+    output: Mutex<VecDeque<u8>>,
+    input: Mutex<VecDeque<u8>>,
+}
+
+impl RingBufferBackend {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            output: Mutex::new(VecDeque::with_capacity(capacity)),
+            input: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Number of bytes of the buffered output.
+    pub fn output_len(&self) -> usize {
+        self.output.lock().len()
+    }
+
+    /// Take all the buffered output.
+    pub fn take_output(&self) -> Vec<u8> {
+        self.output.lock().drain(..).collect()
+    }
+
+    /// Queue bytes to be received by the guest.
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.input.lock().extend(bytes);
+    }
+}
+
+impl CharBackend for RingBufferBackend {
+    fn write_byte(&self, byte: u8) {
+        let mut output = self.output.lock();
+        if output.len() == self.capacity {
+            // drop the oldest byte
+            output.pop_front();
+        }
+        output.push_back(byte);
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        self.input.lock().pop_front()
+    }
+}
+
+/// Captures the output to a file, flushed at every line and on shutdown.
+pub struct FileBackend {
+    inner: Mutex<(File, Vec<u8>)>,
+}
+
+impl FileBackend {
+    const MAX_LINE_LEN: usize = 256;
+
+    pub fn create(path: &str) -> AxResult<Self> {
+        let file = File::create(path).map_err(|err| {
+            warn!("Failed to create serial log file {}, err {:?}", path, err);
+            AxError::Io
+        })?;
+        Ok(Self {
+            inner: Mutex::new((file, Vec::with_capacity(Self::MAX_LINE_LEN))),
+        })
+    }
+
+    /// Writes the buffered `line` to `file`, and clears it.
+    fn write_line(file: &mut File, line: &mut Vec<u8>) {
+        if let Err(err) = file.write_all(line) {
+            warn!("Failed to write serial log file, err {:?}", err);
+        }
+        line.clear();
+    }
+}
+
+impl CharBackend for FileBackend {
+    fn write_byte(&self, byte: u8) {
+        let mut inner = self.inner.lock();
+        let (file, line) = &mut *inner;
+        line.push(byte);
+        if byte == b'\n' || line.len() >= Self::MAX_LINE_LEN {
+            Self::write_line(file, line);
+        }
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        None
+    }
+
+    fn flush(&self) {
+        let mut inner = self.inner.lock();
+        let (file, line) = &mut *inner;
+        if !line.is_empty() {
+            Self::write_line(file, line);
+        }
+    }
+}
+
+/// Feeds a fixed input script to the guest, for deterministic tests.
+pub struct ScriptedBackend {
+    script: Vec<u8>,
+    pos: Mutex<usize>,
+}
+
+impl ScriptedBackend {
+    pub const fn new(script: Vec<u8>) -> Self {
+        Self {
+            script,
+            pos: Mutex::new(0),
+        }
+    }
+}
+
+impl CharBackend for ScriptedBackend {
+    fn write_byte(&self, byte: u8) {
+        axhal::console::putchar(byte);
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        let mut pos = self.pos.lock();
+        let byte = self.script.get(*pos).copied()?;
+        *pos += 1;
+        Some(byte)
+    }
+}
@@ -0,0 +1,250 @@
+//! Multiplexes the host console between the serial ports of VMs and the
+//! hypervisor monitor.
+//!
+//! Press `Ctrl-A` followed by:
+//!
+//! - `c`: switch to the next console;
+//! - `0`: switch to the monitor;
+//! - `1`-`9`: switch to the console with this index;
+//! - `h`: print the help message;
+//! - `Ctrl-A`: send `Ctrl-A` to the attached console.
+//!
+//! Output of the detached consoles is buffered, and printed when attached.
+
+use alloc::{string::String, vec::Vec};
+use axhal::console;
+use spin::Mutex;
+
+use super::char_backend::{self, CharBackend, RingBufferBackend};
+
+/// Ctrl-A
+const ESCAPE_CHAR: u8 = 0x01;
+/// Maximum bytes buffered for each detached console.
+const BUFFER_CAPACITY: usize = 0x10000;
+
+const HELP_MSG: &str = "\
+Ctrl-A c     switch to the next console\r
+Ctrl-A 0     switch to the monitor\r
+Ctrl-A 1-9   switch to the console with this index\r
+Ctrl-A h     print this help\r
+Ctrl-A Ctrl-A  send Ctrl-A\r
+";
+const MONITOR_PROMPT: &str = "(monitor) ";
+
+struct MuxConsole {
+    name: String,
+    /// Buffers the output when detached, and the input from the host.
+    buffer: RingBufferBackend,
+}
+
+struct ConsoleMux {
+    consoles: Vec<MuxConsole>,
+    /// The console attached to the host, `None` for the monitor.
+    attached: Option<usize>,
+    escape_pending: bool,
+    monitor_line: String,
+}
+
+fn puts(s: &str) {
+    for b in s.bytes() {
+        console::putchar(b);
+    }
+}
+
+impl ConsoleMux {
+    const fn new() -> Self {
+        Self {
+            consoles: Vec::new(),
+            attached: None,
+            escape_pending: false,
+            monitor_line: String::new(),
+        }
+    }
+
+    fn register(&mut self, name: String) -> usize {
+        let index = self.consoles.len();
+        self.consoles.push(MuxConsole {
+            name,
+            buffer: RingBufferBackend::new(BUFFER_CAPACITY),
+        });
+        if self.attached.is_none() && index == 0 {
+            // attach the first console by default
+            self.attached = Some(index);
+        }
+        index
+    }
+
+    fn attach(&mut self, attached: Option<usize>) {
+        if matches!(attached, Some(index) if index >= self.consoles.len()) {
+            return;
+        }
+        self.attached = attached;
+        match attached {
+            Some(index) => {
+                let con = &self.consoles[index];
+                puts("\r\n[attached to ");
+                puts(&con.name);
+                puts("]\r\n");
+                for b in con.buffer.take_output() {
+                    console::putchar(b);
+                }
+            }
+            None => {
+                puts("\r\n[attached to monitor]\r\n");
+                puts(MONITOR_PROMPT);
+                puts(&self.monitor_line);
+            }
+        }
+    }
+
+    fn write(&mut self, index: usize, byte: u8) {
+        if self.attached == Some(index) {
+            console::putchar(byte);
+        } else {
+            self.consoles[index].buffer.write_byte(byte);
+        }
+    }
+
+    fn read(&mut self, index: usize) -> Option<u8> {
+        self.poll();
+        self.consoles[index].buffer.read_byte()
+    }
+
+    /// Dispatch the input from the host console.
+    fn poll(&mut self) {
+        while let Some(c) = console::console_getchar() {
+            if self.escape_pending {
+                self.escape_pending = false;
+                self.handle_escape(c);
+            } else if c == ESCAPE_CHAR {
+                self.escape_pending = true;
+            } else {
+                self.input(c);
+            }
+        }
+    }
+
+    fn handle_escape(&mut self, c: u8) {
+        match c {
+            ESCAPE_CHAR => self.input(c),
+            b'c' => {
+                let next = match self.attached {
+                    Some(index) if index + 1 < self.consoles.len() => Some(index + 1),
+                    Some(_) => None,
+                    None if !self.consoles.is_empty() => Some(0),
+                    None => None,
+                };
+                self.attach(next);
+            }
+            b'0' => self.attach(None),
+            b'1'..=b'9' => self.attach(Some((c - b'1') as usize)),
+            b'h' => puts(HELP_MSG),
+            _ => {}
+        }
+    }
+
+    fn input(&mut self, c: u8) {
+        match self.attached {
+            Some(index) => self.consoles[index].buffer.push_input(&[c]),
+            None => self.monitor_input(c),
+        }
+    }
+
+    fn monitor_input(&mut self, c: u8) {
+        match c {
+            b'\r' | b'\n' => {
+                puts("\r\n");
+                let line = core::mem::take(&mut self.monitor_line);
+                self.monitor_command(line.trim());
+                if self.attached.is_none() {
+                    puts(MONITOR_PROMPT);
+                }
+            }
+            0x08 | 0x7f => {
+                // backspace
+                if self.monitor_line.pop().is_some() {
+                    puts("\x08 \x08");
+                }
+            }
+            0x20..=0x7e => {
+                self.monitor_line.push(c as char);
+                console::putchar(c);
+            }
+            _ => {}
+        }
+    }
+
+    fn monitor_command(&mut self, line: &str) {
+        let mut args = line.split_whitespace();
+        match args.next() {
+            None => {}
+            Some("help") => {
+                puts("help        print this help\r\n");
+                puts("list        list all consoles\r\n");
+                puts("attach <n>  switch to the console with index n\r\n");
+                puts("buffers     list all serial ports with ring buffer backends\r\n");
+                puts("dump <name> print and drain the output captured by a ring buffer\r\n");
+                puts(HELP_MSG);
+            }
+            Some("list") => {
+                for (i, con) in self.consoles.iter().enumerate() {
+                    let msg = alloc::format!(
+                        "{} {}\t{} bytes buffered\r\n",
+                        i + 1,
+                        con.name,
+                        con.buffer.output_len(),
+                    );
+                    puts(&msg);
+                }
+            }
+            Some("attach") => match args.next().and_then(|s| s.parse::<usize>().ok()) {
+                Some(n) if (1..=self.consoles.len()).contains(&n) => self.attach(Some(n - 1)),
+                _ => puts("invalid console index\r\n"),
+            },
+            Some("buffers") => {
+                for (name, len) in char_backend::ring_buffers() {
+                    puts(&alloc::format!("{}\t{} bytes buffered\r\n", name, len));
+                }
+            }
+            Some("dump") => match args.next().and_then(char_backend::ring_buffer) {
+                Some(buffer) => {
+                    for b in buffer.take_output() {
+                        console::putchar(b);
+                    }
+                    puts("\r\n");
+                }
+                None => puts("invalid ring buffer name\r\n"),
+            },
+            Some(cmd) => {
+                puts("unknown command: ");
+                puts(cmd);
+                puts("\r\n");
+            }
+        }
+    }
+}
+
+static CONSOLE_MUX: Mutex<ConsoleMux> = Mutex::new(ConsoleMux::new());
+
+/// A console attached to the multiplexer.
+pub struct MuxBackend {
+    index: usize,
+}
+
+impl MuxBackend {
+    pub fn new(name: String) -> Self {
+        Self {
+            index: CONSOLE_MUX.lock().register(name),
+        }
+    }
+}
+
+impl CharBackend for MuxBackend {
+    fn write_byte(&self, byte: u8) {
+        CONSOLE_MUX.lock().write(self.index, byte);
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        CONSOLE_MUX.lock().read(self.index)
+    }
+}
@@ -0,0 +1,308 @@
+//! Emulated QEMU firmware configuration (fw_cfg) device, with the traditional
+//! I/O port interface and the DMA interface. (ref: QEMU docs/specs/fw_cfg.rst)
+//!
+//! The files in the directory are:
+//!
+//! - `opt/kernel`, `opt/initrd`, `opt/cmdline`: the boot image, initial ramdisk
+//!   and command line in the VM configuration, also as the legacy items.
+//! - `etc/acpi/rsdp`, `etc/acpi/tables`: the ACPI tables, whose addresses are
+//!   fixed at `platform.acpi_tables` with the tables right after the RSDP.
+//! - `etc/e820`: the guest physical memory map.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::PortIoDevice;
+use crate::acpi::{self, AcpiPlatform};
+use crate::config::vm_config;
+use crate::gpm::{GuestMemoryError, GuestMemoryResult};
+use axerrno::AxResult;
+use axvm::GuestPhysAddr;
+use memory_addr::PAGE_SIZE_4K;
+use spin::Mutex;
+
+/// The selector register, 16 bits.
+const FW_CFG_PORT_SEL: u16 = 0x510;
+/// The data register, 8 bits.
+const FW_CFG_PORT_DATA: u16 = 0x511;
+/// The DMA address register, 64 bits in big endian. Writing the low half at
+/// `FW_CFG_PORT_DMA + 4` starts the transfer.
+const FW_CFG_PORT_DMA: u16 = 0x514;
+/// Read from the DMA address register.
+const FW_CFG_DMA_SIGNATURE: &[u8; 8] = b"QEMU CFG";
+
+const FW_CFG_SIGNATURE: u16 = 0x00;
+const FW_CFG_ID: u16 = 0x01;
+const FW_CFG_RAM_SIZE: u16 = 0x03;
+const FW_CFG_NB_CPUS: u16 = 0x05;
+const FW_CFG_KERNEL_SIZE: u16 = 0x08;
+const FW_CFG_INITRD_SIZE: u16 = 0x0b;
+const FW_CFG_MAX_CPUS: u16 = 0x0f;
+const FW_CFG_KERNEL_DATA: u16 = 0x11;
+const FW_CFG_INITRD_DATA: u16 = 0x12;
+const FW_CFG_CMDLINE_SIZE: u16 = 0x14;
+const FW_CFG_CMDLINE_DATA: u16 = 0x15;
+const FW_CFG_FILE_DIR: u16 = 0x19;
+const FW_CFG_FILE_FIRST: u16 = 0x20;
+
+/// Feature bits in `FW_CFG_ID`: the traditional interface and the DMA interface.
+const FW_CFG_VERSION: u32 = 1 << 0;
+const FW_CFG_VERSION_DMA: u32 = 1 << 1;
+
+/// Control bits of a DMA access.
+const FW_CFG_DMA_CTL_ERROR: u32 = 1 << 0;
+const FW_CFG_DMA_CTL_READ: u32 = 1 << 1;
+const FW_CFG_DMA_CTL_SKIP: u32 = 1 << 2;
+const FW_CFG_DMA_CTL_SELECT: u32 = 1 << 3;
+const FW_CFG_DMA_CTL_WRITE: u32 = 1 << 4;
+
+/// Size of a file name in the directory, including the terminating NUL.
+const FW_CFG_MAX_FILE_PATH: usize = 56;
+/// Size of an `etc/e820` entry: address, length and type.
+const E820_ENTRY_SIZE: usize = 20;
+
+/// Reads `path` for an item, `None` if it cannot be read.
+fn load_file(path: &str) -> Option<Arc<[u8]>> {
+    crate::read_file(path).ok().map(Arc::from)
+}
+
+struct FwCfgState {
+    items: BTreeMap<u16, Arc<[u8]>>,
+    selector: u16,
+    /// Offset of the next data byte in the selected item.
+    offset: usize,
+    /// The high half of the DMA address register.
+    dma_addr_high: u32,
+}
+
+impl FwCfgState {
+    /// Builds the items from the VM configuration and the guest memory, which
+    /// must be set up before the devices are created on the first VM exit.
+    fn new() -> Self {
+        let config = vm_config();
+        let boot = &config.boot;
+        let ram_size: usize = config.memory.iter().map(|r| r.size).sum();
+        let vcpus = (config.vcpus as u16).to_le_bytes();
+        let mut items = BTreeMap::new();
+        let mut files = BTreeMap::new();
+        items.insert(FW_CFG_SIGNATURE, Arc::from(&b"QEMU"[..]));
+        let id = FW_CFG_VERSION | FW_CFG_VERSION_DMA;
+        items.insert(FW_CFG_ID, Arc::from(&id.to_le_bytes()[..]));
+        items.insert(
+            FW_CFG_RAM_SIZE,
+            Arc::from(&(ram_size as u64).to_le_bytes()[..]),
+        );
+        items.insert(FW_CFG_NB_CPUS, Arc::from(&vcpus[..]));
+        items.insert(FW_CFG_MAX_CPUS, Arc::from(&vcpus[..]));
+
+        let mut add_legacy = |name, size_key, data_key, data: Arc<[u8]>| {
+            let size = data.len() as u32;
+            items.insert(size_key, Arc::from(&size.to_le_bytes()[..]));
+            items.insert(data_key, data.clone());
+            files.insert(name, data);
+        };
+        if let Some(kernel) = load_file(&boot.image) {
+            add_legacy("opt/kernel", FW_CFG_KERNEL_SIZE, FW_CFG_KERNEL_DATA, kernel);
+        }
+        if let Some(initrd) = boot.initrd.as_deref().and_then(load_file) {
+            add_legacy("opt/initrd", FW_CFG_INITRD_SIZE, FW_CFG_INITRD_DATA, initrd);
+        }
+        let mut cmdline = boot.cmdline.clone().into_bytes();
+        cmdline.push(0);
+        add_legacy(
+            "opt/cmdline",
+            FW_CFG_CMDLINE_SIZE,
+            FW_CFG_CMDLINE_DATA,
+            Arc::from(cmdline),
+        );
+
+        let platform = AcpiPlatform::from_config(config);
+        match acpi::build_acpi_tables(&platform, config.platform.acpi_tables) {
+            Ok(tables) => {
+                let (rsdp, tables) = tables.split_at(acpi::RSDP_SIZE);
+                files.insert("etc/acpi/rsdp", Arc::from(rsdp));
+                files.insert("etc/acpi/tables", Arc::from(tables));
+            }
+            Err(err) => warn!("fw_cfg: failed to build ACPI tables: {:?}", err),
+        }
+
+        let map = crate::boot::memory_map(&crate::guest_phys_memory());
+        let mut e820 = Vec::with_capacity(map.len() * E820_ENTRY_SIZE);
+        for entry in map {
+            e820.extend_from_slice(&(entry.base as u64).to_le_bytes());
+            e820.extend_from_slice(&(entry.size as u64).to_le_bytes());
+            e820.extend_from_slice(&(entry.kind as u32).to_le_bytes());
+        }
+        files.insert("etc/e820", Arc::from(e820));
+
+        // the directory is sorted by file name, each entry is the size, the
+        // selector, 2 reserved bytes and the name, in big endian.
+        let mut dir = (files.len() as u32).to_be_bytes().to_vec();
+        for (key, (name, data)) in (FW_CFG_FILE_FIRST..).zip(files) {
+            dir.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            dir.extend_from_slice(&key.to_be_bytes());
+            dir.extend_from_slice(&[0; 2]);
+            let mut path = [0; FW_CFG_MAX_FILE_PATH];
+            path[..name.len()].copy_from_slice(name.as_bytes());
+            dir.extend_from_slice(&path);
+            items.insert(key, data);
+        }
+        items.insert(FW_CFG_FILE_DIR, Arc::from(dir));
+        Self {
+            items,
+            selector: 0,
+            offset: 0,
+            dma_addr_high: 0,
+        }
+    }
+
+    fn select(&mut self, selector: u16) {
+        self.selector = selector;
+        self.offset = 0;
+    }
+
+    /// The rest of the selected item, empty if absent or exhausted.
+    fn remaining(&self) -> &[u8] {
+        self.items
+            .get(&self.selector)
+            .and_then(|item| item.get(self.offset..))
+            .unwrap_or_default()
+    }
+
+    /// Reads the next byte of the selected item, 0 past the end.
+    fn read_byte(&mut self) -> u8 {
+        let byte = self.remaining().first().copied().unwrap_or(0);
+        self.offset += 1;
+        byte
+    }
+
+    /// Performs the DMA access described at `access_gpa`: the control word, the
+    /// length and the guest address, in big endian. The control word is
+    /// cleared on success or set to the error bit, also for invalid guest
+    /// addresses. Nothing is done if the access itself cannot be read.
+    fn dma_transfer(&mut self, access_gpa: GuestPhysAddr) {
+        let mut access = [0; 16];
+        if let Err(err) = crate::guest_phys_memory().read_gpa(access_gpa, &mut access) {
+            warn!("fw_cfg: invalid DMA access at {:#x}: {:?}", access_gpa, err);
+            return;
+        }
+        let control = u32::from_be_bytes(access[0..4].try_into().unwrap());
+        let length = u32::from_be_bytes(access[4..8].try_into().unwrap()) as usize;
+        let address = u64::from_be_bytes(access[8..16].try_into().unwrap()) as usize;
+        trace!(
+            "fw_cfg DMA: control {:#x}, length {:#x}, address {:#x}",
+            control,
+            length,
+            address
+        );
+
+        if control & FW_CFG_DMA_CTL_SELECT != 0 {
+            self.select((control >> 16) as u16);
+        }
+        let mut result = 0;
+        if control & FW_CFG_DMA_CTL_READ != 0 {
+            if let Err(err) = self.dma_read(address, length) {
+                warn!("fw_cfg: DMA read to {:#x} failed: {:?}", address, err);
+                result = FW_CFG_DMA_CTL_ERROR;
+            }
+        } else if control & FW_CFG_DMA_CTL_SKIP != 0 {
+            self.offset = self.offset.saturating_add(length);
+        } else if control & FW_CFG_DMA_CTL_WRITE != 0 {
+            warn!("fw_cfg: writing item {:#x} is not supported", self.selector);
+            result = FW_CFG_DMA_CTL_ERROR;
+        }
+        if let Err(err) = crate::guest_phys_memory().write_gpa(access_gpa, &result.to_be_bytes()) {
+            warn!("fw_cfg: invalid DMA access at {:#x}: {:?}", access_gpa, err);
+        }
+    }
+
+    /// Copies `length` bytes of the selected item to guest memory at `address`
+    /// a page at most each time, bytes past the end of the item read as 0.
+    fn dma_read(&mut self, address: GuestPhysAddr, length: usize) -> GuestMemoryResult {
+        static ZEROS: [u8; PAGE_SIZE_4K] = [0; PAGE_SIZE_4K];
+        let mut done = 0;
+        while done < length {
+            let gpa = address
+                .checked_add(done)
+                .ok_or(GuestMemoryError::NotMapped(address))?;
+            let len = (length - done).min(PAGE_SIZE_4K);
+            let remaining = self.remaining();
+            let data = match remaining.get(..len) {
+                Some(data) => data,
+                None if remaining.is_empty() => &ZEROS[..len],
+                None => remaining,
+            };
+            let len = data.len();
+            crate::guest_phys_memory().write_gpa(gpa, data)?;
+            self.offset = self.offset.saturating_add(len);
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+pub struct FwCfg {
+    state: Mutex<FwCfgState>,
+}
+
+impl FwCfg {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(FwCfgState::new()),
+        }
+    }
+}
+
+impl PortIoDevice for FwCfg {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        FW_CFG_PORT_SEL..FW_CFG_PORT_DMA + 8
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> AxResult<u32> {
+        match (port, access_size) {
+            (FW_CFG_PORT_DATA, 1) => Ok(self.state.lock().read_byte() as u32),
+            (FW_CFG_PORT_DMA.., _) => {
+                let offset = (port - FW_CFG_PORT_DMA) as usize;
+                let mut buf = [0; 4];
+                let size = (access_size as usize).min(FW_CFG_DMA_SIGNATURE.len() - offset);
+                buf[..size].copy_from_slice(&FW_CFG_DMA_SIGNATURE[offset..offset + size]);
+                Ok(u32::from_le_bytes(buf))
+            }
+            // reserved ports and other access sizes read as 0, as QEMU.
+            _ => {
+                warn!("Invalid fw_cfg I/O read: {:#x}, size {}", port, access_size);
+                Ok(0)
+            }
+        }
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> AxResult {
+        let mut state = self.state.lock();
+        // the DMA address is in big endian, i.e. the byte order on the bus.
+        let dma_value = u32::from_be_bytes(value.to_le_bytes());
+        match (port, access_size) {
+            (FW_CFG_PORT_SEL, 2) => state.select(value as u16),
+            // writes to the data register are ignored since QEMU 2.4.
+            (FW_CFG_PORT_DATA, 1) => {}
+            (FW_CFG_PORT_DMA, 4) => state.dma_addr_high = dma_value,
+            (p, 4) if p == FW_CFG_PORT_DMA + 4 => {
+                let access_gpa = (state.dma_addr_high as usize) << 32 | dma_value as usize;
+                state.dma_addr_high = 0;
+                state.dma_transfer(access_gpa);
+            }
+            // reserved ports and other access sizes are ignored, as QEMU.
+            _ => warn!(
+                "Invalid fw_cfg I/O write: {:#x}, size {}",
+                port, access_size
+            ),
+        }
+        Ok(())
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        state.select(0);
+        state.dma_addr_high = 0;
+    }
+}
@@ -0,0 +1,425 @@
+//! Emulated Intel 8254 Programmable Interval Timer. (ref: https://wiki.osdev.org/PIT,
+//! and the Intel 8254 datasheet)
+//!
+//! Channel 0 drives IRQ0, and the gate and output of channel 2 are connected to
+//! the system control port B (0x61).
+
+use super::PortIoDevice;
+use crate::hal::AxvmHalImpl;
+use axerrno::{AxError, AxResult};
+use axvm::AxvmHal;
+use spin::Mutex;
+
+const PIT_PORT_BASE: u16 = 0x40;
+const PIT_CONTROL_REG: u16 = 3;
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// The refresh request bit of port 0x61 toggles every 15.085 us.
+const REFRESH_PERIOD_NANOS: u64 = 15_085;
+const PIT_IRQ: u8 = 0;
+
+const RW_LATCH: u8 = 0;
+const RW_LSB: u8 = 1;
+const RW_MSB: u8 = 2;
+const RW_WORD: u8 = 3;
+
+fn current_time_nanos() -> u64 {
+    AxvmHalImpl::current_time_nanos()
+}
+
+fn nanos_to_ticks(nanos: u64) -> u64 {
+    (nanos as u128 * PIT_FREQUENCY_HZ as u128 / NANOS_PER_SEC as u128) as u64
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * NANOS_PER_SEC as u128).div_ceil(PIT_FREQUENCY_HZ as u128) as u64
+}
+
+/// The state of one counter.
+#[derive(Default)]
+struct PitChannel {
+    /// Initial count, 0 is treated as 0x10000.
+    count: u32,
+    /// Time when the count is loaded or the gate triggers, moved forward by
+    /// the time suspended by the gate in modes 0 and 4.
+    load_time: u64,
+    /// Whether the count has been written since the mode is set.
+    armed: bool,
+    mode: u8,
+    rw_mode: u8,
+    bcd: bool,
+    gate: bool,
+    /// Time when the gate goes low.
+    gate_low_time: u64,
+    /// Written LSB of the count in the word access mode.
+    write_lsb: Option<u8>,
+    /// The next read returns MSB in the word access mode.
+    read_msb: bool,
+    latched_count: Option<u16>,
+    latched_msb: bool,
+    latched_status: Option<u8>,
+    /// Whether the IRQ of the current count has been raised (modes 0, 1, 4, 5).
+    irq_fired: bool,
+    /// Time of the next rising edge of the output (modes 2 and 3).
+    next_irq_time: u64,
+}
+
+impl PitChannel {
+    fn new(gate: bool) -> Self {
+        Self {
+            count: 0x10000,
+            rw_mode: RW_WORD,
+            gate,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the counting is suspended by the low gate (modes 0, 2, 3 and 4).
+    fn suspended(&self) -> bool {
+        !self.gate && matches!(self.mode, 0 | 2 | 3 | 4)
+    }
+
+    /// Ticks elapsed since the count is loaded, frozen while suspended.
+    fn elapsed_ticks(&self, now: u64) -> u64 {
+        let now = if self.suspended() {
+            now.min(self.gate_low_time)
+        } else {
+            now
+        };
+        nanos_to_ticks(now.saturating_sub(self.load_time))
+    }
+
+    fn counter(&self, now: u64) -> u16 {
+        if !self.armed {
+            return self.count as u16;
+        }
+        let d = self.elapsed_ticks(now);
+        let count = self.count as u64;
+        let value = match self.mode {
+            2 => count - d % count,
+            3 => count - (2 * d) % count,
+            _ => count.wrapping_sub(d),
+        };
+        value as u16
+    }
+
+    fn output(&self, now: u64) -> bool {
+        if !self.armed {
+            return self.mode != 0;
+        }
+        if !self.gate && matches!(self.mode, 2 | 3) {
+            return true; // the low gate forces the output high
+        }
+        let d = self.elapsed_ticks(now);
+        let count = self.count as u64;
+        match self.mode {
+            0 => d >= count,
+            1 => d < count,
+            2 => d % count != 0 || d == 0,
+            3 => d % count < (count + 1) / 2,
+            _ => d != count,
+        }
+    }
+
+    fn status(&self, now: u64) -> u8 {
+        let mut status = (self.rw_mode << 4) | (self.mode << 1) | self.bcd as u8;
+        if self.output(now) {
+            status |= 0x80;
+        }
+        if !self.armed {
+            status |= 0x40; // null count
+        }
+        status
+    }
+
+    /// Time of the first output rising edge after `now` in the periodic modes.
+    fn next_edge_time(&self, now: u64) -> u64 {
+        let count = self.count as u64;
+        let periods = self.elapsed_ticks(now) / count + 1;
+        self.load_time + ticks_to_nanos(periods * count)
+    }
+
+    fn restart(&mut self, now: u64) {
+        self.load_time = now;
+        self.irq_fired = false;
+        self.next_irq_time = now + ticks_to_nanos(self.count as u64);
+    }
+
+    fn load_count(&mut self, value: u16, now: u64) {
+        self.count = if value == 0 { 0x10000 } else { value as u32 };
+        self.armed = true;
+        self.restart(now);
+    }
+
+    fn set_mode(&mut self, value: u8) {
+        self.rw_mode = (value >> 4) & 0b11;
+        self.mode = match (value >> 1) & 0b111 {
+            6 => 2,
+            7 => 3,
+            mode => mode,
+        };
+        self.bcd = value & 1 != 0;
+        if self.bcd {
+            warn!("8254 BCD counting is not supported");
+        }
+        self.armed = false;
+        self.write_lsb = None;
+        self.read_msb = false;
+        self.latched_count = None;
+        self.latched_status = None;
+    }
+
+    fn latch_count(&mut self, now: u64) {
+        if self.latched_count.is_none() {
+            self.latched_count = Some(self.counter(now));
+            self.latched_msb = false;
+        }
+    }
+
+    fn latch_status(&mut self, now: u64) {
+        if self.latched_status.is_none() {
+            self.latched_status = Some(self.status(now));
+        }
+    }
+
+    fn set_gate(&mut self, gate: bool, now: u64) {
+        if gate && !self.gate && self.armed {
+            if matches!(self.mode, 0 | 4) {
+                // resume the counting, skipping the time suspended since loaded
+                self.load_time += now.saturating_sub(self.gate_low_time.max(self.load_time));
+            } else {
+                // rising edge triggers the counting
+                self.restart(now);
+            }
+        } else if !gate && self.gate {
+            self.gate_low_time = now;
+        }
+        self.gate = gate;
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        if let Some(status) = self.latched_status.take() {
+            return status;
+        }
+        if let Some(count) = self.latched_count {
+            let [lsb, msb] = count.to_le_bytes();
+            return match self.rw_mode {
+                RW_LSB => {
+                    self.latched_count = None;
+                    lsb
+                }
+                RW_MSB => {
+                    self.latched_count = None;
+                    msb
+                }
+                _ if self.latched_msb => {
+                    self.latched_count = None;
+                    msb
+                }
+                _ => {
+                    self.latched_msb = true;
+                    lsb
+                }
+            };
+        }
+        let [lsb, msb] = self.counter(now).to_le_bytes();
+        match self.rw_mode {
+            RW_LSB => lsb,
+            RW_MSB => msb,
+            _ => {
+                self.read_msb = !self.read_msb;
+                if self.read_msb {
+                    lsb
+                } else {
+                    msb
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, value: u8, now: u64) {
+        match self.rw_mode {
+            RW_LSB => self.load_count(value as u16, now),
+            RW_MSB => self.load_count((value as u16) << 8, now),
+            _ => match self.write_lsb.take() {
+                Some(lsb) => self.load_count(u16::from_le_bytes([lsb, value]), now),
+                None => {
+                    // writing the LSB stops the counting in mode 0
+                    if self.mode == 0 {
+                        self.armed = false;
+                    }
+                    self.write_lsb = Some(value);
+                }
+            },
+        }
+    }
+
+    /// Whether the output has a new rising edge which raises the IRQ.
+    fn check_irq(&mut self, now: u64) -> bool {
+        if !self.armed || !self.gate {
+            return false;
+        }
+        match self.mode {
+            2 | 3 => {
+                if now >= self.next_irq_time {
+                    // missed edges are coalesced
+                    self.next_irq_time = self.next_edge_time(now);
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => {
+                if !self.irq_fired && self.elapsed_ticks(now) >= self.count as u64 {
+                    self.irq_fired = true;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+struct I8254 {
+    channels: [PitChannel; 3],
+    /// Speaker data enable bit of port 0x61.
+    speaker_data: bool,
+}
+
+impl I8254 {
+    fn new() -> Self {
+        Self {
+            // the gates of channel 0 and 1 are always high
+            channels: [
+                PitChannel::new(true),
+                PitChannel::new(true),
+                PitChannel::new(false),
+            ],
+            speaker_data: false,
+        }
+    }
+
+    fn write_control(&mut self, value: u8, now: u64) {
+        let select = value >> 6;
+        if select == 3 {
+            // read-back command
+            for (i, ch) in self.channels.iter_mut().enumerate() {
+                if value & (2 << i) != 0 {
+                    if value & 0x20 == 0 {
+                        ch.latch_count(now);
+                    }
+                    if value & 0x10 == 0 {
+                        ch.latch_status(now);
+                    }
+                }
+            }
+            return;
+        }
+        let ch = &mut self.channels[select as usize];
+        if (value >> 4) & 0b11 == RW_LATCH {
+            ch.latch_count(now);
+        } else {
+            ch.set_mode(value);
+        }
+    }
+
+    fn read_port_b(&self, now: u64) -> u8 {
+        let ch2 = &self.channels[2];
+        let mut value = ch2.gate as u8 | (self.speaker_data as u8) << 1;
+        if (now / REFRESH_PERIOD_NANOS) & 1 != 0 {
+            value |= 1 << 4;
+        }
+        if ch2.output(now) {
+            value |= 1 << 5;
+        }
+        value
+    }
+
+    fn write_port_b(&mut self, value: u8, now: u64) {
+        self.channels[2].set_gate(value & 1 != 0, now);
+        self.speaker_data = value & 2 != 0;
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref VIRT_PIT: Mutex<I8254> = Mutex::new(I8254::new());
+}
+
+pub struct I8254Pit;
+
+impl PortIoDevice for I8254Pit {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        PIT_PORT_BASE..PIT_PORT_BASE + 4
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> AxResult<u32> {
+        if access_size != 1 {
+            error!("Invalid PIT I/O read size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        let now = current_time_nanos();
+        let value = match port - PIT_PORT_BASE {
+            PIT_CONTROL_REG => 0xff, // the control word register is write-only
+            offset => VIRT_PIT.lock().channels[offset as usize].read(now),
+        };
+        Ok(value as u32)
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> AxResult {
+        if access_size != 1 {
+            error!("Invalid PIT I/O write size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        let now = current_time_nanos();
+        let mut pit = VIRT_PIT.lock();
+        match port - PIT_PORT_BASE {
+            PIT_CONTROL_REG => pit.write_control(value as u8, now),
+            offset => pit.channels[offset as usize].write(value as u8, now),
+        }
+        Ok(())
+    }
+
+    fn poll(&self) {
+        let now = current_time_nanos();
+        if VIRT_PIT.lock().channels[0].check_irq(now) {
+            // IRQs are edge triggered, send a pulse.
+            super::set_isa_irq(PIT_IRQ, true);
+            super::set_isa_irq(PIT_IRQ, false);
+        }
+    }
+
+    fn reset(&self) {
+        *VIRT_PIT.lock() = I8254::new();
+    }
+}
+
+/// The system control port B (0x61), only the bits related to the PIT are emulated.
+pub struct SystemControlPortB;
+
+impl PortIoDevice for SystemControlPortB {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        SYSTEM_CONTROL_PORT_B..SYSTEM_CONTROL_PORT_B + 1
+    }
+
+    fn read(&self, _port: u16, access_size: u8) -> AxResult<u32> {
+        if access_size != 1 {
+            error!("Invalid port 0x61 I/O read size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        let now = current_time_nanos();
+        Ok(VIRT_PIT.lock().read_port_b(now) as u32)
+    }
+
+    fn write(&self, _port: u16, access_size: u8, value: u32) -> AxResult {
+        if access_size != 1 {
+            error!("Invalid port 0x61 I/O write size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        let now = current_time_nanos();
+        VIRT_PIT.lock().write_port_b(value as u8, now);
+        Ok(())
+    }
+}
@@ -1,7 +1,322 @@
 //! Emulated Intel 8259 Programmable Interrupt Controller. (ref: https://wiki.osdev.org/8259_PIC)
+//!
+//! The master and slave PICs are cascaded through IRQ2 of the master, whose
+//! output is delivered to the BSP as an external interrupt. (ref: Intel 8259A datasheet)
 
 use super::PortIoDevice;
 use axerrno::{AxError, AxResult};
+use spin::Mutex;
+
+const MASTER_PORT_BASE: u16 = 0x20;
+const SLAVE_PORT_BASE: u16 = 0xA0;
+/// The IRQ of the master which the slave is connected to.
+const CASCADE_IRQ: u8 = 2;
+
+/// The state of one 8259A.
+#[derive(Default)]
+struct I8259State {
+    is_master: bool,
+    /// Interrupt Request Register.
+    irr: u8,
+    /// In-Service Register.
+    isr: u8,
+    /// Interrupt Mask Register.
+    imr: u8,
+    /// IRQ line levels, to detect the rising edges.
+    last_irr: u8,
+    /// Vector of IR0, set by ICW2.
+    irq_base: u8,
+    /// IRQ number with the highest priority, changed by rotations.
+    priority_add: u8,
+    /// Read ISR instead of IRR from the command port.
+    read_isr: bool,
+    /// The next read is a poll command.
+    poll: bool,
+    special_mask: bool,
+    /// Expected initialization command word, 0 if initialized.
+    init_state: u8,
+    need_icw4: bool,
+    single_mode: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    special_fully_nested_mode: bool,
+}
+
+impl I8259State {
+    fn new(is_master: bool) -> Self {
+        Self {
+            is_master,
+            ..Default::default()
+        }
+    }
+
+    /// ICW1 resets the 8259A to an uninitialized state.
+    fn reset(&mut self) {
+        *self = Self {
+            is_master: self.is_master,
+            ..Default::default()
+        }
+    }
+
+    /// The priority (0 is the highest) of the highest priority bit in `mask`, 8 if
+    /// no bits set.
+    fn priority(&self, mask: u8) -> u8 {
+        (0..8)
+            .find(|&p| mask & (1 << ((p + self.priority_add) & 7)) != 0)
+            .unwrap_or(8)
+    }
+
+    /// The IRQ to be requested to the processor, which is higher than all in-service IRQs.
+    fn pending_irq(&self) -> Option<u8> {
+        let priority = self.priority(self.irr & !self.imr);
+        if priority == 8 {
+            return None;
+        }
+        let mut in_service = self.isr;
+        if self.special_mask {
+            in_service &= !self.imr;
+        }
+        if self.special_fully_nested_mode && self.is_master {
+            // requests from the slave are allowed even if IRQ2 is in service.
+            in_service &= !(1 << CASCADE_IRQ);
+        }
+        if priority < self.priority(in_service) {
+            Some((priority + self.priority_add) & 7)
+        } else {
+            None
+        }
+    }
+
+    /// Change the level of an IRQ line, all IRQs are edge triggered.
+    fn set_irq(&mut self, irq: u8, level: bool) {
+        let mask = 1 << irq;
+        if level {
+            if self.last_irr & mask == 0 {
+                self.irr |= mask;
+            }
+            self.last_irr |= mask;
+        } else {
+            self.last_irr &= !mask;
+        }
+    }
+
+    /// Acknowledge an IRQ, move it from IRR to ISR.
+    fn acknowledge(&mut self, irq: u8) {
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.priority_add = (irq + 1) & 7;
+            }
+        } else {
+            self.isr |= 1 << irq;
+        }
+        self.irr &= !(1 << irq);
+    }
+
+    /// End of interrupt of `irq`, rotate the priorities if `rotate`.
+    fn end_of_interrupt(&mut self, irq: u8, rotate: bool) {
+        self.isr &= !(1 << irq);
+        if rotate {
+            self.priority_add = (irq + 1) & 7;
+        }
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if value & 0x10 != 0 {
+            // ICW1
+            self.reset();
+            self.init_state = 1;
+            self.need_icw4 = value & 0x01 != 0;
+            self.single_mode = value & 0x02 != 0;
+            if value & 0x08 != 0 {
+                warn!("8259 level triggered mode is not supported");
+            }
+        } else if value & 0x08 != 0 {
+            // OCW3
+            if value & 0x04 != 0 {
+                self.poll = true;
+            }
+            if value & 0x02 != 0 {
+                self.read_isr = value & 0x01 != 0;
+            }
+            if value & 0x40 != 0 {
+                self.special_mask = value & 0x20 != 0;
+            }
+        } else {
+            // OCW2
+            let irq = value & 0x07;
+            match value >> 5 {
+                0b000 | 0b100 => self.rotate_on_auto_eoi = value & 0x80 != 0,
+                0b001 | 0b101 => {
+                    // non-specific EOI, reset the highest priority ISR bit
+                    let priority = self.priority(self.isr);
+                    if priority != 8 {
+                        let irq = (priority + self.priority_add) & 7;
+                        self.end_of_interrupt(irq, value & 0x80 != 0);
+                    }
+                }
+                // specific EOI
+                0b011 | 0b111 => self.end_of_interrupt(irq, value & 0x80 != 0),
+                // set priority, `irq` becomes the lowest
+                0b110 => self.priority_add = (irq + 1) & 7,
+                _ => {} // no operation
+            }
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.init_state = match self.init_state {
+            0 => {
+                // OCW1
+                self.imr = value;
+                0
+            }
+            1 => {
+                // ICW2
+                self.irq_base = value & 0xf8;
+                match (self.single_mode, self.need_icw4) {
+                    (false, _) => 2,
+                    (true, true) => 3,
+                    (true, false) => 0,
+                }
+            }
+            2 => {
+                // ICW3, the cascade is fixed to IRQ2 of the master
+                if self.need_icw4 {
+                    3
+                } else {
+                    0
+                }
+            }
+            _ => {
+                // ICW4
+                if value & 0x01 == 0 {
+                    warn!("8259 MCS-80/85 mode is not supported");
+                }
+                self.auto_eoi = value & 0x02 != 0;
+                self.special_fully_nested_mode = value & 0x10 != 0;
+                0
+            }
+        };
+    }
+}
+
+/// The cascaded master and slave 8259As.
+struct I8259Cascade {
+    master: I8259State,
+    slave: I8259State,
+}
+
+impl I8259Cascade {
+    fn new() -> Self {
+        Self {
+            master: I8259State::new(true),
+            slave: I8259State::new(false),
+        }
+    }
+
+    fn chip_mut(&mut self, port_base: u16) -> &mut I8259State {
+        if port_base == MASTER_PORT_BASE {
+            &mut self.master
+        } else {
+            &mut self.slave
+        }
+    }
+
+    /// Propagate the slave output to IRQ2 of the master.
+    fn update(&mut self) {
+        let slave_output = self.slave.pending_irq().is_some();
+        self.master.set_irq(CASCADE_IRQ, slave_output);
+    }
+
+    fn set_irq(&mut self, irq: u8, level: bool) {
+        if irq < 8 {
+            self.master.set_irq(irq, level);
+        } else {
+            self.slave.set_irq(irq - 8, level);
+        }
+        self.update();
+    }
+
+    /// The interrupt acknowledge (INTA) cycle, returns the vector.
+    fn acknowledge(&mut self) -> u8 {
+        let vector = match self.master.pending_irq() {
+            Some(CASCADE_IRQ) => {
+                self.master.acknowledge(CASCADE_IRQ);
+                match self.slave.pending_irq() {
+                    Some(irq) => {
+                        self.slave.acknowledge(irq);
+                        self.slave.irq_base + irq
+                    }
+                    None => self.slave.irq_base + 7, // spurious IRQ15
+                }
+            }
+            Some(irq) => {
+                self.master.acknowledge(irq);
+                self.master.irq_base + irq
+            }
+            None => self.master.irq_base + 7, // spurious IRQ7
+        };
+        self.update();
+        vector
+    }
+
+    /// Poll command, acknowledge the highest priority IRQ without interrupting
+    /// the processor, returns the poll word.
+    fn poll(&mut self, port_base: u16) -> u8 {
+        let chip = self.chip_mut(port_base);
+        chip.poll = false;
+        let Some(irq) = chip.pending_irq() else {
+            return 0;
+        };
+        chip.acknowledge(irq);
+        if port_base == SLAVE_PORT_BASE {
+            self.master.irr &= !(1 << CASCADE_IRQ);
+            self.master.isr &= !(1 << CASCADE_IRQ);
+        }
+        self.update();
+        0x80 | irq
+    }
+
+    fn read(&mut self, port_base: u16, offset: u16) -> u8 {
+        let chip = self.chip_mut(port_base);
+        if chip.poll {
+            return self.poll(port_base);
+        }
+        match offset {
+            0 if chip.read_isr => chip.isr,
+            0 => chip.irr,
+            _ => chip.imr,
+        }
+    }
+
+    fn write(&mut self, port_base: u16, offset: u16, value: u8) {
+        let chip = self.chip_mut(port_base);
+        match offset {
+            0 => chip.write_command(value),
+            _ => chip.write_data(value),
+        }
+        self.update();
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref VIRT_PIC: Mutex<I8259Cascade> = Mutex::new(I8259Cascade::new());
+}
+
+/// Change the level of an ISA IRQ line (0-15).
+pub fn set_irq(irq: u8, level: bool) {
+    VIRT_PIC.lock().set_irq(irq, level);
+}
+
+/// Whether the INT output of the master PIC is asserted.
+pub fn has_interrupt() -> bool {
+    VIRT_PIC.lock().master.pending_irq().is_some()
+}
+
+/// Acknowledge the interrupt requested by the master PIC, returns the vector.
+pub fn acknowledge_interrupt() -> u8 {
+    VIRT_PIC.lock().acknowledge()
+}
 
 pub struct I8259Pic {
     port_base: u16,
@@ -12,17 +327,34 @@ impl PortIoDevice for I8259Pic {
         self.port_base..self.port_base + 2
     }
 
-    fn read(&self, _port: u16, _access_size: u8) -> AxResult<u32> {
-        Err(AxError::Unsupported) // report error for read
+    fn read(&self, port: u16, access_size: u8) -> AxResult<u32> {
+        if access_size != 1 {
+            error!("Invalid PIC I/O read size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        let value = VIRT_PIC.lock().read(self.port_base, port - self.port_base);
+        Ok(value as u32)
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> AxResult {
+        if access_size != 1 {
+            error!("Invalid PIC I/O write size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        VIRT_PIC
+            .lock()
+            .write(self.port_base, port - self.port_base, value as u8);
+        Ok(())
     }
 
-    fn write(&self, _port: u16, _access_size: u8, _value: u32) -> AxResult {
-        Ok(()) // ignore write
+    fn reset(&self) {
+        *VIRT_PIC.lock() = I8259Cascade::new();
     }
 }
 
 impl I8259Pic {
     pub const fn new(port_base: u16) -> Self {
+        assert!(port_base == MASTER_PORT_BASE || port_base == SLAVE_PORT_BASE);
         Self { port_base }
     }
 }
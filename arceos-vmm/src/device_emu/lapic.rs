@@ -1,9 +1,15 @@
 //! Emulated Local APIC. (SDM Vol. 3A, Chapter 10)
 
-#![allow(dead_code)]
+use alloc::vec::Vec;
+use core::ops::Range;
 
 use axerrno::{AxError, AxResult};
-use axvm::AxvmVcpu;
+use axvm::arch::ApicVirtFeatures;
+use axvm::{AxvmVcpu, GuestPhysAddr};
+use spin::{Mutex, MutexGuard};
+
+use super::i8259_pic;
+use crate::config::vm_config;
 
 type Vcpu = AxvmVcpu<crate::hal::AxvmHalImpl>;
 
@@ -11,14 +17,39 @@ type Vcpu = AxvmVcpu<crate::hal::AxvmHalImpl>;
 const APICID: u32 = 0x2;
 /// Version register.
 const VERSION: u32 = 0x3;
+/// Task Priority Register.
+const TPR: u32 = 0x8;
+/// Arbitration Priority Register.
+const APR: u32 = 0x9;
+/// Processor Priority Register.
+const PPR: u32 = 0xA;
 /// EOI register.
 const EOI: u32 = 0xB;
+/// Remote Read Register.
+const RRD: u32 = 0xC;
 /// Logical Destination Register.
 const LDR: u32 = 0xD;
+/// Destination Format Register.
+const DFR: u32 = 0xE;
 /// Spurious Interrupt Vector register.
 const SIVR: u32 = 0xF;
+/// In-Service Register, bits 31:0 ~ 255:224.
+const ISR_START: u32 = 0x10;
+const ISR_END: u32 = 0x17;
+/// Trigger Mode Register, bits 31:0 ~ 255:224.
+const TMR_START: u32 = 0x18;
+const TMR_END: u32 = 0x1F;
+/// Interrupt Request Register, bits 31:0 ~ 255:224.
+const IRR_START: u32 = 0x20;
+const IRR_END: u32 = 0x27;
+/// Error Status Register.
+const ESR: u32 = 0x28;
+/// LVT Corrected Machine Check Interrupt register.
+const LVT_CMCI: u32 = 0x2F;
 /// Interrupt Command register.
 const ICR: u32 = 0x30;
+/// Interrupt Command register, bits 63:32 (xAPIC mode only).
+const ICR_HIGH: u32 = 0x31;
 /// LVT Timer Interrupt register.
 const LVT_TIMER: u32 = 0x32;
 /// LVT Thermal Sensor Interrupt register.
@@ -37,59 +68,791 @@ const INIT_COUNT: u32 = 0x38;
 const CUR_COUNT: u32 = 0x39;
 /// Divide Configuration register.
 const DIV_CONF: u32 = 0x3E;
+/// Self IPI register (x2APIC mode only).
+const SELF_IPI: u32 = 0x3F;
+
+/// Version 0x14, 7 LVT entries. (SDM Vol. 3A, Section 10.4.8, Figure 10-7)
+const APIC_VERSION: u32 = 0x0006_0014;
+/// Mask bit of LVT registers.
+const LVT_MASKED: u32 = 1 << 16;
+/// Software enable bit of the Spurious Interrupt Vector register.
+const SIVR_APIC_ENABLED: u32 = 1 << 8;
+/// ESR bit: a fixed IPI with an illegal vector was sent.
+const ESR_SEND_ILLEGAL_VECTOR: u32 = 1 << 5;
+/// ESR bit: an interrupt with an illegal vector was received.
+const ESR_RECEIVE_ILLEGAL_VECTOR: u32 = 1 << 6;
+
+/// Default physical address of the xAPIC MMIO page.
+const DEFAULT_APIC_BASE: usize = 0xfee0_0000;
+/// Size of the xAPIC MMIO page.
+const XAPIC_MMIO_SIZE: usize = 0x1000;
+
+bitflags::bitflags! {
+    /// IA32_APIC_BASE MSR flags. (SDM Vol. 3A, Section 10.4.4, Figure 10-5)
+    struct ApicBaseFlags: u64 {
+        /// Processor is BSP.
+        const BSP = 1 << 8;
+        /// Enable x2APIC mode.
+        const X2APIC_ENABLE = 1 << 10;
+        /// APIC global enable.
+        const XAPIC_ENABLE = 1 << 11;
+        /// APIC base physical address.
+        const APIC_BASE = 0x000f_ffff_ffff_f000;
+    }
+}
+
+impl ApicBaseFlags {
+    fn mode(&self) -> ApicMode {
+        if !self.contains(Self::XAPIC_ENABLE) {
+            ApicMode::Disabled
+        } else if self.contains(Self::X2APIC_ENABLE) {
+            ApicMode::X2Apic
+        } else {
+            ApicMode::XApic
+        }
+    }
+}
+
+/// Local APIC operating modes. (SDM Vol. 3A, Section 10.12.5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// The local APIC is globally disabled.
+    Disabled,
+    /// xAPIC mode, registers are accessed through the MMIO page.
+    XApic,
+    /// x2APIC mode, registers are accessed through MSRs.
+    X2Apic,
+}
+
+/// Delivery modes of IPIs. (SDM Vol. 3A, Section 10.6.1, Figure 10-12)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeliveryMode {
+    Fixed,
+    LowestPriority,
+    Smi,
+    Nmi,
+    Init,
+    StartUp,
+}
+
+impl DeliveryMode {
+    fn from_icr(icr: u64) -> Option<Self> {
+        match (icr >> 8) & 0b111 {
+            0b000 => Some(Self::Fixed),
+            0b001 => Some(Self::LowestPriority),
+            0b010 => Some(Self::Smi),
+            0b100 => Some(Self::Nmi),
+            0b101 => Some(Self::Init),
+            0b110 => Some(Self::StartUp),
+            _ => None, // reserved
+        }
+    }
+}
+
+/// A 256-bit register set, one bit for each interrupt vector, as IRR/ISR/TMR.
+#[derive(Debug, Default, Clone, Copy)]
+struct VectorSet([u32; 8]);
+
+impl VectorSet {
+    fn set(&mut self, vector: u8) {
+        self.0[vector as usize / 32] |= 1 << (vector % 32);
+    }
+
+    fn clear(&mut self, vector: u8) {
+        self.0[vector as usize / 32] &= !(1 << (vector % 32));
+    }
+
+    fn contains(&self, vector: u8) -> bool {
+        self.0[vector as usize / 32] & (1 << (vector % 32)) != 0
+    }
+
+    fn as_u64s(&self) -> [u64; 4] {
+        core::array::from_fn(|i| self.0[i * 2] as u64 | (self.0[i * 2 + 1] as u64) << 32)
+    }
+
+    /// The highest vector in the set.
+    fn highest(&self) -> Option<u8> {
+        self.0
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, &bits)| bits != 0)
+            .map(|(i, &bits)| (i * 32 + 31 - bits.leading_zeros() as usize) as u8)
+    }
+}
+
+/// Per-vCPU states of the emulated local APIC.
+pub struct VirtLocalApic {
+    apic_id: u32,
+    apic_base: ApicBaseFlags,
+    tpr: u32,
+    ldr: u32,
+    dfr: u32,
+    sivr: u32,
+    esr: u32,
+    esr_pending: u32,
+    icr: u64,
+    lvt_cmci: u32,
+    lvt_thermal: u32,
+    lvt_pmi: u32,
+    lvt_lint0: u32,
+    lvt_lint1: u32,
+    lvt_err: u32,
+    irr: VectorSet,
+    isr: VectorSet,
+    tmr: VectorSet,
+    /// IRR last written to the virtual-APIC page.
+    synced_irr: VectorSet,
+    nmi_pending: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref VIRT_LAPICS: Vec<Mutex<VirtLocalApic>> =
+        (0..vm_config().vcpus).map(|id| Mutex::new(VirtLocalApic::new(id))).collect();
+}
+
+impl VirtLocalApic {
+    fn new(vcpu_id: usize) -> Self {
+        // SDM Vol. 3A, Section 10.4.3: the local APIC is enabled in xAPIC mode after reset.
+        let mut apic_base = ApicBaseFlags::from_bits_retain(DEFAULT_APIC_BASE as u64);
+        apic_base |= ApicBaseFlags::XAPIC_ENABLE;
+        if vcpu_id == 0 {
+            apic_base |= ApicBaseFlags::BSP;
+        }
+        let mut lapic = Self {
+            apic_id: vcpu_id as u32,
+            apic_base,
+            tpr: 0,
+            ldr: 0,
+            dfr: 0,
+            sivr: 0,
+            esr: 0,
+            esr_pending: 0,
+            icr: 0,
+            lvt_cmci: 0,
+            lvt_thermal: 0,
+            lvt_pmi: 0,
+            lvt_lint0: 0,
+            lvt_lint1: 0,
+            lvt_err: 0,
+            irr: VectorSet::default(),
+            isr: VectorSet::default(),
+            tmr: VectorSet::default(),
+            synced_irr: VectorSet::default(),
+            nmi_pending: false,
+        };
+        lapic.reset();
+        lapic
+    }
+
+    /// Reset registers to their power-up values, except the APIC ID and the
+    /// IA32_APIC_BASE MSR. (SDM Vol. 3A, Section 10.4.7.1)
+    fn reset(&mut self) {
+        self.tpr = 0;
+        self.ldr = 0;
+        self.dfr = 0xffff_ffff;
+        self.sivr = 0xff;
+        self.esr = 0;
+        self.esr_pending = 0;
+        self.icr = 0;
+        self.lvt_cmci = LVT_MASKED;
+        self.lvt_thermal = LVT_MASKED;
+        self.lvt_pmi = LVT_MASKED;
+        self.lvt_lint0 = LVT_MASKED;
+        self.lvt_lint1 = LVT_MASKED;
+        self.lvt_err = LVT_MASKED;
+        self.irr = VectorSet::default();
+        self.isr = VectorSet::default();
+        self.tmr = VectorSet::default();
+        self.synced_irr = VectorSet::default();
+        self.nmi_pending = false;
+    }
+
+    fn of(vcpu: &Vcpu) -> MutexGuard<'static, Self> {
+        VIRT_LAPICS[vcpu.vcpu_id()].lock()
+    }
+
+    fn mode(&self) -> ApicMode {
+        self.apic_base.mode()
+    }
 
-pub struct VirtLocalApic;
+    fn mmio_base(&self) -> GuestPhysAddr {
+        (self.apic_base.bits() & ApicBaseFlags::APIC_BASE.bits()) as usize
+    }
+
+    fn software_enabled(&self) -> bool {
+        self.sivr & SIVR_APIC_ENABLED != 0
+    }
+
+    /// In x2APIC mode, LDR is derived from the x2APIC ID. (SDM Vol. 3A, Section 10.12.10.2)
+    fn logical_id(&self) -> u32 {
+        match self.mode() {
+            ApicMode::X2Apic => ((self.apic_id >> 4) << 16) | (1 << (self.apic_id & 0xf)),
+            _ => self.ldr,
+        }
+    }
+
+    /// Whether the 8259 PIC output is connected to this processor, i.e. the BSP in
+    /// virtual wire mode, where LINT0 is programmed as ExtINT, or the local APIC is
+    /// globally disabled. (SDM Vol. 3A, Section 10.5.1)
+    fn accepts_ext_int(&self) -> bool {
+        if !self.apic_base.contains(ApicBaseFlags::BSP) {
+            return false;
+        }
+        match self.mode() {
+            ApicMode::Disabled => true,
+            _ => self.lvt_lint0 & LVT_MASKED == 0 && (self.lvt_lint0 >> 8) & 0b111 == 0b111,
+        }
+    }
+
+    /// Processor Priority Register. (SDM Vol. 3A, Section 10.8.3.1)
+    fn ppr(&self) -> u32 {
+        let isrv = self.isr.highest().unwrap_or(0) as u32;
+        if (self.tpr & 0xf0) >= (isrv & 0xf0) {
+            self.tpr & 0xff
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    /// The highest pending vector in IRR whose priority class is above PPR.
+    /// (SDM Vol. 3A, Section 10.8.3)
+    fn deliverable_vector(&self) -> Option<u8> {
+        self.irr
+            .highest()
+            .filter(|&vector| (vector as u32 & 0xf0) > (self.ppr() & 0xf0))
+    }
+
+    /// Accept a fixed interrupt into IRR. (SDM Vol. 3A, Section 10.8.4)
+    fn accept_interrupt(&mut self, vector: u8, level_triggered: bool) {
+        if vector < 16 {
+            self.esr_pending |= ESR_RECEIVE_ILLEGAL_VECTOR;
+            return;
+        }
+        if !self.software_enabled() {
+            return; // fixed interrupts are not accepted when software disabled
+        }
+        self.irr.set(vector);
+        if level_triggered {
+            self.tmr.set(vector);
+        } else {
+            self.tmr.clear(vector);
+        }
+    }
+
+    /// Clear the highest priority bit in ISR. (SDM Vol. 3A, Section 10.8.5)
+    fn end_of_interrupt(&mut self) {
+        if let Some(vector) = self.isr.highest() {
+            self.isr.clear(vector);
+            if self.tmr.contains(vector) {
+                // no emulated I/O APIC to broadcast the EOI message to
+                trace!("EOI for level-triggered vector {:#x}", vector);
+                self.tmr.clear(vector);
+            }
+        }
+    }
+
+    /// Whether this local APIC is one of the IPI destinations.
+    /// (SDM Vol. 3A, Section 10.6.2, and Section 10.12.9)
+    fn match_destination(&self, dest: u32, logical: bool, x2apic: bool) -> bool {
+        let broadcast = if x2apic { u32::MAX } else { 0xff };
+        if dest == broadcast {
+            return true;
+        }
+        if !logical {
+            return dest == self.apic_id;
+        }
+        let ldr = self.logical_id();
+        if x2apic {
+            (dest >> 16) == (ldr >> 16) && (dest & ldr & 0xffff) != 0
+        } else if self.dfr >> 28 == 0xf {
+            // flat model
+            (ldr >> 24) & dest != 0
+        } else {
+            // cluster model
+            (ldr >> 28) == (dest >> 4) && (ldr >> 24) & dest & 0xf != 0
+        }
+    }
+
+    /// Deliver an IPI to this local APIC.
+    fn receive_ipi(&mut self, mode: DeliveryMode, vector: u8) {
+        match mode {
+            DeliveryMode::Fixed | DeliveryMode::LowestPriority => {
+                self.accept_interrupt(vector, false)
+            }
+            DeliveryMode::Nmi => self.nmi_pending = true,
+            // INIT and SIPI only start APs, but a VM has one vCPU (`MAX_VCPUS`).
+            DeliveryMode::Init | DeliveryMode::StartUp | DeliveryMode::Smi => {
+                warn!("{:?} IPI is not supported", mode)
+            }
+        }
+    }
+}
 
 impl VirtLocalApic {
-    pub const fn msr_range() -> core::ops::Range<u32> {
+    /// Reset the local APICs of all vCPUs to the power-on state when the VM restarts.
+    pub fn reset_all() {
+        for (id, lapic) in VIRT_LAPICS.iter().enumerate() {
+            *lapic.lock() = Self::new(id);
+        }
+    }
+
+    pub const fn msr_range() -> Range<u32> {
         0x800..0x840
     }
 
     pub fn rdmsr(vcpu: &mut Vcpu, msr: u32) -> AxResult<u64> {
+        if Self::of(vcpu).mode() != ApicMode::X2Apic {
+            return Err(AxError::InvalidInput); // #GP if not in x2APIC mode
+        }
         Self::read(vcpu, msr - 0x800)
     }
 
     pub fn wrmsr(vcpu: &mut Vcpu, msr: u32, value: u64) -> AxResult {
+        if Self::of(vcpu).mode() != ApicMode::X2Apic {
+            return Err(AxError::InvalidInput); // #GP if not in x2APIC mode
+        }
         Self::write(vcpu, msr - 0x800, value)
     }
+
+    /// The current operating mode of the local APIC of `vcpu`.
+    pub fn apic_mode(vcpu: &Vcpu) -> ApicMode {
+        Self::of(vcpu).mode()
+    }
+
+    /// Read the IA32_APIC_BASE MSR.
+    pub fn apic_base(vcpu: &Vcpu) -> u64 {
+        Self::of(vcpu).apic_base.bits()
+    }
+
+    /// Write the IA32_APIC_BASE MSR, switching the operating mode.
+    pub fn set_apic_base(vcpu: &mut Vcpu, value: u64) -> AxResult {
+        let new_base = ApicBaseFlags::from_bits(value).ok_or(AxError::InvalidInput)?; // reserved bits
+        let mut lapic = Self::of(vcpu);
+        let (old_mode, new_mode) = (lapic.mode(), new_base.mode());
+        // SDM Vol. 3A, Section 10.12.5, Figure 10-27: x2APIC mode can only be entered from
+        // xAPIC mode, and can only be left by disabling the local APIC.
+        if matches!(
+            (old_mode, new_mode),
+            (ApicMode::Disabled, ApicMode::X2Apic) | (ApicMode::X2Apic, ApicMode::XApic)
+        ) {
+            return Err(AxError::InvalidInput);
+        }
+        lapic.apic_base = new_base;
+        if old_mode != new_mode && new_mode == ApicMode::Disabled {
+            lapic.reset();
+        }
+        drop(lapic);
+
+        if old_mode != new_mode {
+            info!(
+                "vCPU {} local APIC mode: {:?} -> {:?}",
+                vcpu.vcpu_id(),
+                old_mode,
+                new_mode
+            );
+            if new_mode == ApicMode::Disabled {
+                // The local APIC is reset to its power-up state when globally disabled.
+                Self::reset_timer(vcpu)?;
+            }
+            if old_mode == ApicMode::X2Apic || new_mode == ApicMode::X2Apic {
+                vcpu.set_x2apic_virtualization(new_mode == ApicMode::X2Apic)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Use the VMX APIC virtualization features as many as the processor supports,
+    /// otherwise all local APIC accesses and interrupt deliveries are emulated.
+    pub fn enable_virtualization(vcpu: &mut Vcpu) -> AxResult {
+        // Posted interrupts are left disabled: IPIs posted to a running vCPU need a
+        // notification IPI to its physical CPU, which the host cannot send yet.
+        let features = vcpu.enable_apic_virtualization(ApicVirtFeatures::all(), None)?;
+        if features.is_empty() {
+            info!("APIC virtualization is not supported, fallback to emulation");
+        }
+        if Self::of(vcpu).mode() == ApicMode::X2Apic {
+            vcpu.set_x2apic_virtualization(true)?;
+        }
+        Ok(())
+    }
+
+    /// Load the states updated by the processor from the virtual-APIC page,
+    /// should be called at the beginning of every VM exit handling.
+    pub fn sync_from_virt_apic(vcpu: &mut Vcpu) {
+        let features = vcpu.apic_virt_features();
+        let Some(page) = vcpu.virt_apic_page() else {
+            return;
+        };
+        let mut lapic = Self::of(vcpu);
+        lapic.tpr = page.read(TPR) & 0xff;
+        if features.contains(ApicVirtFeatures::VIRT_INTR_DELIVERY) {
+            for i in 0..8 {
+                // Keep the requests accepted since the last sync, and take the processor's
+                // view for others, as it moves vectors from IRR to ISR on delivery.
+                let added = lapic.irr.0[i] & !lapic.synced_irr.0[i];
+                lapic.irr.0[i] = page.read(IRR_START + i as u32) | added;
+                // Virtualized EOIs clear ISR bits, clear TMR bits of these vectors too.
+                let isr = page.read(ISR_START + i as u32);
+                let eoi_vectors = lapic.isr.0[i] & !isr;
+                lapic.tmr.0[i] &= !eoi_vectors;
+                lapic.isr.0[i] = isr;
+            }
+        }
+    }
+
+    /// Deliver pending interrupts of the local APIC to `vcpu`, should be called
+    /// before every VM entry.
+    ///
+    /// IPIs sent to other vCPUs are picked up on their next VM exit.
+    pub fn check_interrupts(vcpu: &mut Vcpu) -> AxResult {
+        if vcpu.apic_timer_mut().check_interrupt() {
+            let vector = vcpu.apic_timer_mut().vector();
+            Self::of(vcpu).accept_interrupt(vector, false);
+        }
+
+        let features = vcpu.apic_virt_features();
+        let mut lapic = Self::of(vcpu);
+        if core::mem::take(&mut lapic.nmi_pending) {
+            vcpu.inject_event(x86::irq::NONMASKABLE_INTERRUPT_VECTOR, None);
+        }
+        if lapic.accepts_ext_int() && i8259_pic::has_interrupt() {
+            // ExtINT bypasses IRR and the priority arbitration, the vector is
+            // supplied by the PIC on acknowledge.
+            if vcpu.allow_interrupt() && !vcpu.has_pending_events() {
+                vcpu.inject_event(i8259_pic::acknowledge_interrupt(), None);
+            } else {
+                vcpu.set_interrupt_window(true)?;
+            }
+        }
+        if features.contains(ApicVirtFeatures::VIRT_INTR_DELIVERY) {
+            // SDM Vol. 3C, Section 29.2: the processor evaluates and delivers the pending
+            // virtual interrupts by itself.
+            let rvi = lapic.irr.highest().unwrap_or(0);
+            let svi = lapic.isr.highest().unwrap_or(0);
+            vcpu.set_guest_interrupt_status(rvi, svi)?;
+            // Level-triggered EOIs cause VM exits, then TMR bits can be cleared.
+            vcpu.set_eoi_exit_bitmap(lapic.tmr.as_u64s())?;
+        } else if let Some(vector) = lapic.deliverable_vector() {
+            if vcpu.allow_interrupt() && !vcpu.has_pending_events() {
+                // SDM Vol. 3A, Section 10.8.4: move the vector from IRR to ISR on acknowledge.
+                lapic.irr.clear(vector);
+                lapic.isr.set(vector);
+                vcpu.inject_event(vector, None);
+            } else {
+                vcpu.set_interrupt_window(true)?;
+            }
+        }
+        if features.contains(ApicVirtFeatures::TPR_SHADOW)
+            && !features.contains(ApicVirtFeatures::VIRT_INTR_DELIVERY)
+        {
+            // Cause a VM exit when the guest lowers TPR to unblock the pending interrupt.
+            // SDM Vol. 3C, Section 26.2.1.1: the threshold must not exceed VTPR[7:4].
+            let irr_class = lapic.irr.highest().unwrap_or(0) >> 4;
+            let tpr_class = (lapic.tpr >> 4) as u8;
+            vcpu.set_tpr_threshold(if irr_class <= tpr_class { irr_class } else { 0 })?;
+        }
+        drop(lapic);
+
+        if !features.is_empty() {
+            Self::sync_to_virt_apic(vcpu);
+        }
+        Ok(())
+    }
+
+    /// The guest physical address range of the xAPIC MMIO page of `vcpu`.
+    pub fn mmio_range(vcpu: &Vcpu) -> Range<GuestPhysAddr> {
+        let base = Self::of(vcpu).mmio_base();
+        base..base + XAPIC_MMIO_SIZE
+    }
+
+    /// Handle a read from the xAPIC MMIO page.
+    pub fn mmio_read(vcpu: &mut Vcpu, gpa: GuestPhysAddr, access_size: u8) -> AxResult<u64> {
+        let lapic = Self::of(vcpu);
+        let offset = gpa - lapic.mmio_base();
+        if lapic.mode() != ApicMode::XApic {
+            // the MMIO interface is disabled, reads return all ones as an unclaimed bus.
+            return Ok(u32::MAX as u64);
+        }
+        drop(lapic);
+        if access_size != 4 || offset % 16 != 0 {
+            warn!(
+                "Unaligned xAPIC MMIO read @ {:#x}, size {}",
+                offset, access_size
+            );
+            return Ok(0);
+        }
+        match Self::read(vcpu, offset as u32 >> 4) {
+            Ok(value) => Ok(value & 0xffff_ffff),
+            Err(AxError::InvalidInput) => {
+                warn!("Invalid xAPIC MMIO read @ {:#x}", offset);
+                Ok(0)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Handle a write to the xAPIC MMIO page.
+    pub fn mmio_write(
+        vcpu: &mut Vcpu,
+        gpa: GuestPhysAddr,
+        access_size: u8,
+        value: u64,
+    ) -> AxResult {
+        let lapic = Self::of(vcpu);
+        let offset = gpa - lapic.mmio_base();
+        if lapic.mode() != ApicMode::XApic {
+            return Ok(()); // the MMIO interface is disabled, ignore writes.
+        }
+        drop(lapic);
+        if access_size != 4 || offset % 16 != 0 {
+            warn!(
+                "Unaligned xAPIC MMIO write @ {:#x}, size {}: {:#x}",
+                offset, access_size, value
+            );
+            return Ok(());
+        }
+        match Self::write(vcpu, offset as u32 >> 4, value) {
+            Err(AxError::InvalidInput) => {
+                // no #GP for xAPIC accesses, ignore the write.
+                warn!("Invalid xAPIC MMIO write @ {:#x}: {:#x}", offset, value);
+                Ok(())
+            }
+            res => res,
+        }
+    }
 }
 
 impl VirtLocalApic {
+    /// Store the local APIC states into the virtual-APIC page before VM entry.
+    fn sync_to_virt_apic(vcpu: &mut Vcpu) {
+        let features = vcpu.apic_virt_features();
+        let apic_timer = vcpu.apic_timer();
+        let timer_regs = [
+            (LVT_TIMER, apic_timer.lvt_timer()),
+            (INIT_COUNT, apic_timer.initial_count()),
+            (DIV_CONF, apic_timer.divide()),
+        ];
+        let mut lapic = Self::of(vcpu);
+        let Some(page) = vcpu.virt_apic_page_mut() else {
+            return;
+        };
+
+        page.write(TPR, lapic.tpr);
+        if features.contains(ApicVirtFeatures::VIRT_INTR_DELIVERY) {
+            for i in 0..8 {
+                page.write(IRR_START + i as u32, lapic.irr.0[i]);
+                page.write(ISR_START + i as u32, lapic.isr.0[i]);
+            }
+            lapic.synced_irr = lapic.irr;
+        }
+        if features.contains(ApicVirtFeatures::APIC_REGISTER_VIRT) {
+            // Registers read by the guest through x2APIC MSRs without VM exits.
+            for i in 0..8 {
+                page.write(TMR_START + i as u32, lapic.tmr.0[i]);
+            }
+            for (reg, value) in timer_regs {
+                page.write(reg, value);
+            }
+            page.write(APICID, lapic.apic_id);
+            page.write(VERSION, APIC_VERSION);
+            page.write(PPR, lapic.ppr());
+            page.write(LDR, lapic.logical_id());
+            page.write(SIVR, lapic.sivr);
+            page.write(ESR, lapic.esr);
+            page.write(LVT_CMCI, lapic.lvt_cmci);
+            page.write_u64(ICR, lapic.icr); // 64-bit in x2APIC mode
+            page.write(LVT_THERMAL, lapic.lvt_thermal);
+            page.write(LVT_PMI, lapic.lvt_pmi);
+            page.write(LVT_LINT0, lapic.lvt_lint0);
+            page.write(LVT_LINT1, lapic.lvt_lint1);
+            page.write(LVT_ERR, lapic.lvt_err);
+        }
+    }
+
+    fn reset_timer(vcpu: &mut Vcpu) -> AxResult {
+        let apic_timer = vcpu.apic_timer_mut();
+        apic_timer.set_initial_count(0)?;
+        apic_timer.set_lvt_timer(LVT_MASKED)
+    }
+
+    /// Send an IPI as the ICR written. (SDM Vol. 3A, Section 10.6.1)
+    fn send_ipi(src_id: u32, icr: u64, x2apic: bool) {
+        let vector = icr as u8;
+        let Some(mode) = DeliveryMode::from_icr(icr) else {
+            warn!("IPI with reserved delivery mode: {:#x}", icr);
+            return;
+        };
+        if mode == DeliveryMode::Init && icr & (1 << 14) == 0 {
+            return; // INIT level de-assert, no effects
+        }
+        let dest = if x2apic {
+            (icr >> 32) as u32
+        } else {
+            (icr >> 56) as u32
+        };
+        let logical = icr & (1 << 11) != 0;
+        let shorthand = (icr >> 18) & 0b11;
+        trace!(
+            "APIC {} send IPI {:?}, vector {:#x}, dest {:#x}, shorthand {}",
+            src_id,
+            mode,
+            vector,
+            dest,
+            shorthand
+        );
+
+        let mut src_lapic = VIRT_LAPICS[src_id as usize].lock();
+        if matches!(mode, DeliveryMode::Fixed | DeliveryMode::LowestPriority) && vector < 16 {
+            src_lapic.esr_pending |= ESR_SEND_ILLEGAL_VECTOR;
+            return;
+        }
+        if shorthand == 0b01 {
+            // self
+            src_lapic.receive_ipi(mode, vector);
+            return;
+        }
+        drop(src_lapic);
+
+        for lapic in VIRT_LAPICS.iter() {
+            let mut lapic = lapic.lock();
+            let is_dest = match shorthand {
+                0b00 => lapic.match_destination(dest, logical, x2apic),
+                0b10 => true,                 // all including self
+                _ => lapic.apic_id != src_id, // all excluding self
+            };
+            if is_dest && lapic.mode() != ApicMode::Disabled {
+                lapic.receive_ipi(mode, vector);
+                if mode == DeliveryMode::LowestPriority {
+                    break; // delivered to only one processor
+                }
+            }
+        }
+    }
+
     fn read(vcpu: &mut Vcpu, offset: u32) -> AxResult<u64> {
         let apic_timer = vcpu.apic_timer_mut();
         match offset {
-            SIVR => Ok(0x1ff), // SDM Vol. 3A, Section 10.9, Figure 10-23 (with Software Enable bit)
-            LVT_THERMAL | LVT_PMI | LVT_LINT0 | LVT_LINT1 | LVT_ERR => {
-                Ok(0x1_0000) // SDM Vol. 3A, Section 10.5.1, Figure 10-8 (with Mask bit)
-            }
-            LVT_TIMER => Ok(apic_timer.lvt_timer() as u64),
-            INIT_COUNT => Ok(apic_timer.initial_count() as u64),
-            DIV_CONF => Ok(apic_timer.divide() as u64),
-            CUR_COUNT => Ok(apic_timer.current_counter() as u64),
-            _ => Err(AxError::Unsupported),
+            LVT_TIMER => return Ok(apic_timer.lvt_timer() as u64),
+            INIT_COUNT => return Ok(apic_timer.initial_count() as u64),
+            DIV_CONF => return Ok(apic_timer.divide() as u64),
+            CUR_COUNT => return Ok(apic_timer.current_counter() as u64),
+            _ => {}
         }
+
+        let lapic = Self::of(vcpu);
+        let x2apic = lapic.mode() == ApicMode::X2Apic;
+        let value = match offset {
+            APICID if x2apic => lapic.apic_id,
+            APICID => lapic.apic_id << 24,
+            VERSION => APIC_VERSION,
+            TPR => lapic.tpr,
+            APR | RRD if !x2apic => 0,
+            PPR => lapic.ppr(),
+            EOI if !x2apic => 0,
+            LDR => lapic.logical_id(),
+            DFR if !x2apic => lapic.dfr,
+            SIVR => lapic.sivr,
+            ISR_START..=ISR_END => lapic.isr.0[(offset - ISR_START) as usize],
+            TMR_START..=TMR_END => lapic.tmr.0[(offset - TMR_START) as usize],
+            IRR_START..=IRR_END => lapic.irr.0[(offset - IRR_START) as usize],
+            ESR => lapic.esr,
+            LVT_CMCI => lapic.lvt_cmci,
+            ICR if x2apic => return Ok(lapic.icr),
+            ICR => lapic.icr as u32, // delivery status is always idle
+            ICR_HIGH if !x2apic => (lapic.icr >> 32) as u32,
+            LVT_THERMAL => lapic.lvt_thermal,
+            LVT_PMI => lapic.lvt_pmi,
+            LVT_LINT0 => lapic.lvt_lint0,
+            LVT_LINT1 => lapic.lvt_lint1,
+            LVT_ERR => lapic.lvt_err,
+            _ => return Err(AxError::InvalidInput), // reserved or write-only
+        };
+        Ok(value as u64)
     }
 
     fn write(vcpu: &mut Vcpu, offset: u32, value: u64) -> AxResult {
         if offset != ICR && (value >> 32) != 0 {
             return Err(AxError::InvalidInput); // all registers except ICR are 32-bits
         }
-        let apic_timer = vcpu.apic_timer_mut();
+        let mut lapic = Self::of(vcpu);
+        let x2apic = lapic.mode() == ApicMode::X2Apic;
+        // SDM Vol. 3A, Section 10.5.1: LVT mask bits can't be cleared when software disabled.
+        let lvt_mask = if lapic.software_enabled() {
+            0
+        } else {
+            LVT_MASKED
+        };
+        let value32 = value as u32;
         match offset {
+            LVT_TIMER => {
+                drop(lapic);
+                return vcpu.apic_timer_mut().set_lvt_timer(value32 | lvt_mask);
+            }
+            INIT_COUNT => {
+                drop(lapic);
+                return vcpu.apic_timer_mut().set_initial_count(value32);
+            }
+            DIV_CONF => {
+                drop(lapic);
+                return vcpu.apic_timer_mut().set_divide(value32);
+            }
+            TPR => lapic.tpr = value32 & 0xff,
             EOI => {
-                if value != 0 {
-                    Err(AxError::InvalidInput) // write a non-zero value causes #GP
-                } else {
-                    Ok(())
+                if x2apic && value != 0 {
+                    return Err(AxError::InvalidInput); // write a non-zero value causes #GP
+                }
+                lapic.end_of_interrupt();
+            }
+            LDR if !x2apic => lapic.ldr = value32 & 0xff00_0000,
+            DFR if !x2apic => lapic.dfr = value32 | 0x0fff_ffff,
+            SIVR => {
+                lapic.sivr = value32 & 0x3ff;
+                if !lapic.software_enabled() {
+                    // SDM Vol. 3A, Section 10.4.7.2: all LVT entries are masked when
+                    // software disabled.
+                    lapic.lvt_cmci |= LVT_MASKED;
+                    lapic.lvt_thermal |= LVT_MASKED;
+                    lapic.lvt_pmi |= LVT_MASKED;
+                    lapic.lvt_lint0 |= LVT_MASKED;
+                    lapic.lvt_lint1 |= LVT_MASKED;
+                    lapic.lvt_err |= LVT_MASKED;
+                    drop(lapic);
+                    let apic_timer = vcpu.apic_timer_mut();
+                    let lvt_timer = apic_timer.lvt_timer();
+                    return apic_timer.set_lvt_timer(lvt_timer | LVT_MASKED);
+                }
+            }
+            ESR => {
+                if x2apic && value != 0 {
+                    return Err(AxError::InvalidInput);
                 }
+                // SDM Vol. 3A, Section 10.5.3: a write updates ESR with the errors
+                // detected since the last write.
+                lapic.esr = core::mem::take(&mut lapic.esr_pending);
+            }
+            LVT_CMCI => lapic.lvt_cmci = (value32 & 0x1_07ff) | lvt_mask,
+            ICR => {
+                lapic.icr = if x2apic {
+                    value
+                } else {
+                    (lapic.icr & !0xffff_ffff) | value
+                };
+                let (src_id, icr) = (lapic.apic_id, lapic.icr);
+                drop(lapic);
+                Self::send_ipi(src_id, icr, x2apic);
             }
-            SIVR | LVT_THERMAL | LVT_PMI | LVT_LINT0 | LVT_LINT1 | LVT_ERR => {
-                Ok(()) // ignore these register writes
+            ICR_HIGH if !x2apic => {
+                lapic.icr = (lapic.icr & 0xffff_ffff) | ((value & 0xff00_0000) << 32)
             }
-            LVT_TIMER => apic_timer.set_lvt_timer(value as u32),
-            INIT_COUNT => apic_timer.set_initial_count(value as u32),
-            DIV_CONF => apic_timer.set_divide(value as u32),
-            _ => Err(AxError::Unsupported),
+            LVT_THERMAL => lapic.lvt_thermal = (value32 & 0x1_07ff) | lvt_mask,
+            LVT_PMI => lapic.lvt_pmi = (value32 & 0x1_07ff) | lvt_mask,
+            LVT_LINT0 => lapic.lvt_lint0 = (value32 & 0x1_a7ff) | lvt_mask,
+            LVT_LINT1 => lapic.lvt_lint1 = (value32 & 0x1_a7ff) | lvt_mask,
+            LVT_ERR => lapic.lvt_err = (value32 & 0x1_00ff) | lvt_mask,
+            SELF_IPI if x2apic => lapic.accept_interrupt(value as u8, false),
+            _ => return Err(AxError::InvalidInput), // reserved or read-only
         }
+        Ok(())
     }
 }
@@ -0,0 +1,504 @@
+//! Emulated Motorola MC146818 Real-Time Clock and CMOS memory.
+//! (ref: https://wiki.osdev.org/CMOS, and the MC146818A datasheet)
+
+use alloc::string::String;
+
+use super::PortIoDevice;
+use crate::hal::AxvmHalImpl;
+use axerrno::{AxError, AxResult};
+use axvm::AxvmHal;
+use spin::Mutex;
+
+const RTC_PORT_BASE: u16 = 0x70;
+const RTC_IRQ: u8 = 8;
+
+const REG_SECONDS: usize = 0x00;
+const REG_SECONDS_ALARM: usize = 0x01;
+const REG_MINUTES: usize = 0x02;
+const REG_MINUTES_ALARM: usize = 0x03;
+const REG_HOURS: usize = 0x04;
+const REG_HOURS_ALARM: usize = 0x05;
+const REG_WEEKDAY: usize = 0x06;
+const REG_DAY: usize = 0x07;
+const REG_MONTH: usize = 0x08;
+const REG_YEAR: usize = 0x09;
+const REG_A: usize = 0x0a;
+const REG_B: usize = 0x0b;
+const REG_C: usize = 0x0c;
+const REG_D: usize = 0x0d;
+/// The first byte of the general purpose NVRAM.
+const REG_NVRAM: usize = 0x0e;
+const REG_CENTURY: usize = 0x32;
+/// Base memory size in KiB, 16 bits.
+const REG_BASE_MEM: usize = 0x15;
+/// Extended memory size above 1 MiB in KiB, 16 bits, also at `REG_EXT_MEM_COPY`.
+const REG_EXT_MEM: usize = 0x17;
+const REG_EXT_MEM_COPY: usize = 0x30;
+/// Memory size above 16 MiB in 64 KiB units, 16 bits.
+const REG_EXT_MEM_16M: usize = 0x34;
+/// Memory size above 4 GiB in 64 KiB units, 24 bits.
+const REG_HIGH_MEM: usize = 0x5b;
+/// Number of CPUs minus one.
+const REG_SMP_COUNT: usize = 0x5f;
+const CMOS_SIZE: usize = 128;
+
+const REG_A_UIP: u8 = 1 << 7;
+const REG_A_RATE_MASK: u8 = 0x0f;
+const REG_B_SET: u8 = 1 << 7;
+const REG_B_PIE: u8 = 1 << 6;
+const REG_B_AIE: u8 = 1 << 5;
+const REG_B_UIE: u8 = 1 << 4;
+const REG_B_SQWE: u8 = 1 << 3;
+const REG_B_DM_BINARY: u8 = 1 << 2;
+const REG_B_24H: u8 = 1 << 1;
+const REG_C_IRQF: u8 = 1 << 7;
+const REG_C_PF: u8 = 1 << 6;
+const REG_C_AF: u8 = 1 << 5;
+const REG_C_UF: u8 = 1 << 4;
+const REG_D_VRT: u8 = 1 << 7;
+/// Alarm bytes with the two high bits set match any value.
+const ALARM_DONT_CARE: u8 = 0xc0;
+/// The hours register bit for PM in the 12-hour mode.
+const HOUR_PM: u8 = 0x80;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// The update cycle takes 244 us after UIP is set.
+const UPDATE_IN_PROGRESS_NANOS: u64 = 244_000;
+/// Minimum interval to save the modified NVRAM to the file.
+const NVRAM_SAVE_INTERVAL_NANOS: u64 = NANOS_PER_SEC;
+
+fn current_time_nanos() -> u64 {
+    AxvmHalImpl::current_time_nanos()
+}
+
+/// Broken down date and time, weekday is 1-7 starting from Sunday.
+#[derive(Debug, Clone, Copy)]
+struct DateTime {
+    year: u64,
+    month: u8,
+    day: u8,
+    weekday: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+}
+
+impl DateTime {
+    /// Convert from seconds since the Unix epoch. (ref: http://howardhinnant.github.io/date_algorithms.html)
+    fn from_unix_time(secs: u64) -> Self {
+        let days = secs / 86400;
+        let rem = secs % 86400;
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u64;
+        Self {
+            year,
+            month: month as u8,
+            day: day as u8,
+            weekday: ((days + 4) % 7 + 1) as u8, // 1970-01-01 is Thursday
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// Convert to seconds since the Unix epoch, years before 1970 are clamped.
+    fn to_unix_time(self) -> u64 {
+        let year = self.year.max(1970) - (self.month <= 2) as u64;
+        let month = self.month.clamp(1, 12) as u64;
+        let day = self.day.clamp(1, 31) as u64;
+        let era = year / 400;
+        let yoe = year - era * 400;
+        let mp = if month > 2 { month - 3 } else { month + 9 };
+        let doy = (153 * mp + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+}
+
+struct Mc146818 {
+    cmos: [u8; CMOS_SIZE],
+    /// The selected register index.
+    index: usize,
+    nmi_disabled: bool,
+    /// Guest time in seconds since the Unix epoch at `base_nanos`.
+    base_time: u64,
+    base_nanos: u64,
+    /// Guest time in seconds when the last update cycle is checked.
+    last_update: u64,
+    next_periodic_nanos: u64,
+    /// File to load and save the NVRAM bytes.
+    nvram_file: Option<String>,
+    /// The NVRAM is modified since saved.
+    nvram_dirty: bool,
+    last_save_nanos: u64,
+}
+
+impl Mc146818 {
+    fn new(base_time: u64, nvram_file: Option<String>) -> Self {
+        let mut cmos = [0; CMOS_SIZE];
+        if let Some(path) = &nvram_file {
+            match std::fs::read(path) {
+                Ok(data) => {
+                    let len = data.len().min(CMOS_SIZE - REG_NVRAM);
+                    cmos[REG_NVRAM..REG_NVRAM + len].copy_from_slice(&data[..len]);
+                }
+                Err(err) => warn!("Failed to load CMOS NVRAM from {}, err {:?}", path, err),
+            }
+        }
+        write_config_bytes(&mut cmos);
+        cmos[REG_A] = 0x26; // 32.768 kHz time base, 1024 Hz periodic rate
+        cmos[REG_B] = REG_B_24H;
+        cmos[REG_D] = REG_D_VRT;
+        Self {
+            cmos,
+            index: 0,
+            nmi_disabled: false,
+            base_time,
+            base_nanos: current_time_nanos(),
+            last_update: base_time,
+            next_periodic_nanos: 0,
+            nvram_file,
+            nvram_dirty: false,
+            last_save_nanos: 0,
+        }
+    }
+
+    fn reg_b(&self) -> u8 {
+        self.cmos[REG_B]
+    }
+
+    fn current_time(&self, now: u64) -> u64 {
+        self.base_time + (now - self.base_nanos) / NANOS_PER_SEC
+    }
+
+    fn encode(&self, value: u8) -> u8 {
+        if self.reg_b() & REG_B_DM_BINARY != 0 {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        }
+    }
+
+    fn decode(&self, value: u8) -> u8 {
+        if self.reg_b() & REG_B_DM_BINARY != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0x0f)
+        }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        if self.reg_b() & REG_B_24H != 0 {
+            self.encode(hour)
+        } else {
+            let pm = if hour >= 12 { HOUR_PM } else { 0 };
+            let hour12 = if hour % 12 == 0 { 12 } else { hour % 12 };
+            self.encode(hour12) | pm
+        }
+    }
+
+    fn decode_hour(&self, value: u8) -> u8 {
+        if self.reg_b() & REG_B_24H != 0 {
+            self.decode(value)
+        } else {
+            let hour12 = self.decode(value & !HOUR_PM) % 12;
+            if value & HOUR_PM != 0 {
+                hour12 + 12
+            } else {
+                hour12
+            }
+        }
+    }
+
+    /// Update the time registers from the clock.
+    fn update_time_regs(&mut self, now: u64) {
+        let dt = DateTime::from_unix_time(self.current_time(now));
+        self.cmos[REG_SECONDS] = self.encode(dt.second);
+        self.cmos[REG_MINUTES] = self.encode(dt.minute);
+        self.cmos[REG_HOURS] = self.encode_hour(dt.hour);
+        self.cmos[REG_WEEKDAY] = self.encode(dt.weekday);
+        self.cmos[REG_DAY] = self.encode(dt.day);
+        self.cmos[REG_MONTH] = self.encode(dt.month);
+        self.cmos[REG_YEAR] = self.encode((dt.year % 100) as u8);
+        self.cmos[REG_CENTURY] = self.encode((dt.year / 100) as u8);
+    }
+
+    /// Set the clock from the time registers.
+    fn load_time_regs(&mut self, now: u64) {
+        let dt = DateTime {
+            year: self.decode(self.cmos[REG_CENTURY]) as u64 * 100
+                + self.decode(self.cmos[REG_YEAR]) as u64,
+            month: self.decode(self.cmos[REG_MONTH]),
+            day: self.decode(self.cmos[REG_DAY]),
+            weekday: self.decode(self.cmos[REG_WEEKDAY]),
+            hour: self.decode_hour(self.cmos[REG_HOURS]),
+            minute: self.decode(self.cmos[REG_MINUTES]),
+            second: self.decode(self.cmos[REG_SECONDS]),
+        };
+        self.base_time = dt.to_unix_time();
+        self.base_nanos = now;
+        self.last_update = self.base_time;
+    }
+
+    fn is_time_reg(index: usize) -> bool {
+        matches!(
+            index,
+            REG_SECONDS
+                | REG_MINUTES
+                | REG_HOURS
+                | REG_WEEKDAY
+                | REG_DAY
+                | REG_MONTH
+                | REG_YEAR
+                | REG_CENTURY
+        )
+    }
+
+    /// Period of the periodic interrupt selected by the rate bits of register A.
+    fn periodic_nanos(&self) -> Option<u64> {
+        match self.cmos[REG_A] & REG_A_RATE_MASK {
+            0 => None,
+            rate @ (1 | 2) => Some(NANOS_PER_SEC * (1 << (rate + 6)) / 32768),
+            rate => Some(NANOS_PER_SEC * (1 << (rate - 1)) / 32768),
+        }
+    }
+
+    fn alarm_matches(&self) -> bool {
+        [
+            (REG_SECONDS_ALARM, REG_SECONDS),
+            (REG_MINUTES_ALARM, REG_MINUTES),
+            (REG_HOURS_ALARM, REG_HOURS),
+        ]
+        .iter()
+        .all(|&(alarm, reg)| {
+            self.cmos[alarm] & ALARM_DONT_CARE == ALARM_DONT_CARE
+                || self.cmos[alarm] == self.cmos[reg]
+        })
+    }
+
+    /// Update the interrupt flags in register C, returns the IRQ output.
+    fn update_irq_flags(&mut self) -> bool {
+        let enabled = self.reg_b() & (REG_B_PIE | REG_B_AIE | REG_B_UIE);
+        let flags = self.cmos[REG_C] & (REG_C_PF | REG_C_AF | REG_C_UF);
+        // the enable bits and the flag bits are at the same positions
+        if enabled & flags != 0 {
+            self.cmos[REG_C] |= REG_C_IRQF;
+        }
+        self.cmos[REG_C] & REG_C_IRQF != 0
+    }
+
+    /// Check the periodic and update-ended events, returns the IRQ output.
+    fn poll(&mut self, now: u64) -> bool {
+        if let Some(period) = self.periodic_nanos() {
+            if now >= self.next_periodic_nanos {
+                self.cmos[REG_C] |= REG_C_PF;
+                // missed periods are coalesced
+                self.next_periodic_nanos = now - (now % period) + period;
+            }
+        }
+        if self.reg_b() & REG_B_SET == 0 {
+            let time = self.current_time(now);
+            if time != self.last_update {
+                self.last_update = time;
+                self.update_time_regs(now);
+                self.cmos[REG_C] |= REG_C_UF;
+                if self.alarm_matches() {
+                    self.cmos[REG_C] |= REG_C_AF;
+                }
+            }
+        }
+        self.update_irq_flags()
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        match self.index {
+            REG_A => {
+                let mut value = self.cmos[REG_A] & !REG_A_UIP;
+                let frac = (now - self.base_nanos) % NANOS_PER_SEC;
+                if self.reg_b() & REG_B_SET == 0 && frac >= NANOS_PER_SEC - UPDATE_IN_PROGRESS_NANOS
+                {
+                    value |= REG_A_UIP;
+                }
+                value
+            }
+            REG_C => {
+                // flags are cleared on read
+                core::mem::replace(&mut self.cmos[REG_C], 0)
+            }
+            index if Self::is_time_reg(index) => {
+                if self.reg_b() & REG_B_SET == 0 {
+                    self.update_time_regs(now);
+                }
+                self.cmos[index]
+            }
+            index => self.cmos[index],
+        }
+    }
+
+    fn write(&mut self, value: u8, now: u64) {
+        match self.index {
+            REG_A => {
+                self.cmos[REG_A] = value & !REG_A_UIP;
+                self.next_periodic_nanos = 0;
+            }
+            REG_B => {
+                let old = self.reg_b();
+                if value & REG_B_SET != 0 && old & REG_B_SET == 0 {
+                    // freeze the time registers
+                    self.update_time_regs(now);
+                }
+                self.cmos[REG_B] = value;
+                if value & REG_B_SET == 0 && old & REG_B_SET != 0 {
+                    self.load_time_regs(now);
+                }
+            }
+            REG_C | REG_D => {} // read-only
+            index if Self::is_time_reg(index) => {
+                if self.reg_b() & REG_B_SET == 0 {
+                    self.update_time_regs(now);
+                    self.cmos[index] = value;
+                    self.load_time_regs(now);
+                } else {
+                    self.cmos[index] = value;
+                }
+            }
+            index => {
+                self.cmos[index] = value;
+                // saved later by `poll`, not to write the file on every VM exit.
+                self.nvram_dirty |= index >= REG_NVRAM && self.nvram_file.is_some();
+            }
+        }
+    }
+
+    /// Save the NVRAM to the file if modified.
+    fn save_nvram(&mut self, now: u64) {
+        if !self.nvram_dirty {
+            return;
+        }
+        self.nvram_dirty = false;
+        self.last_save_nanos = now;
+        if let Some(path) = &self.nvram_file {
+            if let Err(err) = std::fs::write(path, &self.cmos[REG_NVRAM..]) {
+                warn!("Failed to save CMOS NVRAM to {}, err {:?}", path, err);
+            }
+        }
+    }
+}
+
+/// Writes the memory sizes and the number of CPUs to the CMOS for the BIOS, in
+/// the layout of the PC/AT and QEMU. Guest RAM is assumed to start at 0.
+fn write_config_bytes(cmos: &mut [u8; CMOS_SIZE]) {
+    const KB: usize = 1 << 10;
+    const MB: usize = 1 << 20;
+    const GB: usize = 1 << 30;
+    let config = crate::config::vm_config();
+    let (mut low_end, mut high_size) = (0, 0);
+    for r in &config.memory {
+        let end = r.gpa + r.size;
+        low_end = low_end.max(end.min(4 * GB));
+        high_size += end - end.min(4 * GB).max(r.gpa);
+    }
+    let mut write = |reg: usize, value: usize, len: usize| {
+        cmos[reg..reg + len].copy_from_slice(&value.to_le_bytes()[..len]);
+    };
+    write(REG_BASE_MEM, 640, 2);
+    let ext_mem = (low_end.saturating_sub(MB) / KB).min(0xffff);
+    write(REG_EXT_MEM, ext_mem, 2);
+    write(REG_EXT_MEM_COPY, ext_mem, 2);
+    write(
+        REG_EXT_MEM_16M,
+        (low_end.saturating_sub(16 * MB) / (64 * KB)).min(0xffff),
+        2,
+    );
+    write(REG_HIGH_MEM, high_size / (64 * KB), 3);
+    write(REG_SMP_COUNT, config.vcpus - 1, 1);
+}
+
+pub struct Mc146818Rtc {
+    rtc: Mutex<Mc146818>,
+}
+
+impl Mc146818Rtc {
+    /// Creates the RTC starting at `base_time` in seconds since the Unix epoch,
+    /// with the NVRAM persisted in `nvram_file` if given.
+    pub fn new(base_time: u64, nvram_file: Option<String>) -> Self {
+        Self {
+            rtc: Mutex::new(Mc146818::new(base_time, nvram_file)),
+        }
+    }
+}
+
+impl PortIoDevice for Mc146818Rtc {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        RTC_PORT_BASE..RTC_PORT_BASE + 2
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> AxResult<u32> {
+        if access_size != 1 {
+            error!("Invalid RTC I/O read size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        let mut rtc = self.rtc.lock();
+        let value = if port == RTC_PORT_BASE {
+            0xff // the index register is write-only
+        } else {
+            let value = rtc.read(current_time_nanos());
+            let irq = rtc.cmos[REG_C] & REG_C_IRQF != 0;
+            super::set_isa_irq(RTC_IRQ, irq);
+            value
+        };
+        Ok(value as u32)
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> AxResult {
+        if access_size != 1 {
+            error!("Invalid RTC I/O write size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        let mut rtc = self.rtc.lock();
+        if port == RTC_PORT_BASE {
+            rtc.index = (value & 0x7f) as usize;
+            let nmi_disabled = value & 0x80 != 0;
+            if nmi_disabled != rtc.nmi_disabled {
+                debug!("RTC: NMI disabled: {}", nmi_disabled);
+                rtc.nmi_disabled = nmi_disabled;
+            }
+        } else {
+            rtc.write(value as u8, current_time_nanos());
+            let irq = rtc.update_irq_flags();
+            super::set_isa_irq(RTC_IRQ, irq);
+        }
+        Ok(())
+    }
+
+    fn poll(&self) {
+        let mut rtc = self.rtc.lock();
+        let now = current_time_nanos();
+        if now >= rtc.last_save_nanos + NVRAM_SAVE_INTERVAL_NANOS {
+            rtc.save_nvram(now);
+        }
+        let irq = rtc.poll(now);
+        super::set_isa_irq(RTC_IRQ, irq);
+    }
+
+    fn reset(&self) {
+        // the clock and NVRAM are kept, the interrupts are disabled.
+        let mut rtc = self.rtc.lock();
+        rtc.save_nvram(current_time_nanos());
+        rtc.cmos[REG_B] &= !(REG_B_PIE | REG_B_AIE | REG_B_UIE | REG_B_SQWE);
+        rtc.cmos[REG_C] = 0;
+        super::set_isa_irq(RTC_IRQ, false);
+    }
+
+    fn shutdown(&self) {
+        self.rtc.lock().save_nvram(current_time_nanos());
+    }
+}
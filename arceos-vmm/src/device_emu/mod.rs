@@ -1,19 +1,52 @@
+mod acpi_pm;
+mod char_backend;
+mod console_mux;
+mod fw_cfg;
+mod i8254_pit;
 mod i8259_pic;
 mod lapic;
+mod mc146818_rtc;
+mod pci;
 mod uart16550;
 
-use alloc::{sync::Arc, vec, vec::Vec};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::{format, sync::Arc, vec::Vec};
 
 use axerrno::AxResult;
+use spin::Mutex;
 
-pub use self::lapic::VirtLocalApic;
+use crate::config::{vm_config, DeviceConfig};
+
+pub use self::acpi_pm::{
+    PM1A_CNT_BLK, PM1A_EVT_BLK, PM_TMR_BLK, RESET_REG_PORT, RESET_VALUE, SCI_IRQ, SLP_TYP_S5,
+};
+pub use self::char_backend::{CharBackend, CharBackendConfig};
+pub use self::lapic::{ApicMode, VirtLocalApic};
 
 pub trait PortIoDevice: Send + Sync {
     fn port_range(&self) -> core::ops::Range<u16>;
     fn read(&self, port: u16, access_size: u8) -> AxResult<u32>;
     fn write(&self, port: u16, access_size: u8, value: u32) -> AxResult;
+    /// Update the states driven by the host (e.g. input, time), called on every VM exit.
+    fn poll(&self) {}
+    /// Reset to the power-on state when the VM restarts.
+    fn reset(&self) {}
+    /// Flush the states kept on the host (e.g. files) when the VM shuts down.
+    fn shutdown(&self) {}
+}
+
+/// Power state changes requested by the guest, handled by the VMM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    Shutdown,
+    Reset,
 }
 
+static LIFECYCLE_EVENT: Mutex<Option<LifecycleEvent>> = Mutex::new(None);
+
+/// The sources asserting each shared ISA IRQ line, by their I/O port bases.
+static SHARED_ISA_IRQS: Mutex<BTreeMap<u8, BTreeSet<u16>>> = Mutex::new(BTreeMap::new());
+
 pub struct VirtDeviceList {
     port_io_devices: Vec<Arc<dyn PortIoDevice>>,
 }
@@ -24,18 +57,106 @@ impl VirtDeviceList {
             .iter()
             .find(|dev| dev.port_range().contains(&port))
     }
+
+    pub fn poll(&self) {
+        for dev in &self.port_io_devices {
+            dev.poll();
+        }
+    }
+
+    pub fn reset(&self) {
+        for dev in &self.port_io_devices {
+            dev.reset();
+        }
+    }
+
+    pub fn shutdown(&self) {
+        for dev in &self.port_io_devices {
+            dev.shutdown();
+        }
+    }
+}
+
+/// Creates the emulated devices described by the VM configuration, in order.
+fn create_devices(devices: &[DeviceConfig]) -> Vec<Arc<dyn PortIoDevice>> {
+    let mut port_io_devices: Vec<Arc<dyn PortIoDevice>> = Vec::new();
+    let mut num_serials = 0;
+    for dev in devices {
+        match dev {
+            DeviceConfig::Pic { port } => {
+                port_io_devices.push(Arc::new(i8259_pic::I8259Pic::new(*port)));
+            }
+            DeviceConfig::Pit => {
+                port_io_devices.push(Arc::new(i8254_pit::I8254Pit));
+                port_io_devices.push(Arc::new(i8254_pit::SystemControlPortB)); // port 0x61
+            }
+            DeviceConfig::Rtc {
+                base_time,
+                nvram_file,
+            } => port_io_devices.push(Arc::new(mc146818_rtc::Mc146818Rtc::new(
+                *base_time,
+                nvram_file.clone(),
+            ))),
+            DeviceConfig::AcpiPm => {
+                port_io_devices.push(Arc::new(acpi_pm::AcpiPmDevice));
+                port_io_devices.push(Arc::new(acpi_pm::ResetRegister)); // port 0xcf9
+            }
+            DeviceConfig::Pci => {
+                // the reset register 0xcf9 is between the two ports.
+                port_io_devices.push(Arc::new(pci::PciConfigAddress)); // port 0xcf8
+                port_io_devices.push(Arc::new(pci::PciConfigData)); // ports 0xcfc-0xcff
+            }
+            DeviceConfig::FwCfg => port_io_devices.push(Arc::new(fw_cfg::FwCfg::new())),
+            DeviceConfig::Uart16550 { port, irq, backend } => {
+                num_serials += 1;
+                let name = format!("{}:COM{}", vm_config().name, num_serials);
+                let backend = backend.build(name).unwrap_or_else(|err| {
+                    panic!("Failed to create COM{} backend: {:?}", num_serials, err)
+                });
+                port_io_devices.push(Arc::new(uart16550::Uart16550::new(*port, *irq, backend)));
+            }
+        }
+    }
+    port_io_devices
 }
 
 lazy_static::lazy_static! {
     static ref VIRT_DEVICES : VirtDeviceList = VirtDeviceList {
-        port_io_devices: vec![
-            Arc::new(uart16550::Uart16550::new(0x3f8)), // COM1
-            Arc::new(i8259_pic::I8259Pic::new(0x20)), // PIC1
-            Arc::new(i8259_pic::I8259Pic::new(0xA0)), // PIC2
-        ],
+        port_io_devices: create_devices(&vm_config().devices),
     };
 }
 
 pub fn all_virt_devices() -> &'static VirtDeviceList {
     &VIRT_DEVICES
 }
+
+/// Change the level of an ISA IRQ line of the virtual interrupt controller.
+pub fn set_isa_irq(irq: u8, level: bool) {
+    i8259_pic::set_irq(irq, level);
+}
+
+/// Change the level driven by `source` on an ISA IRQ line shared by several
+/// devices (e.g. COM1 and COM3), the line is high if any source drives it high.
+pub fn set_shared_isa_irq(irq: u8, source: u16, level: bool) {
+    let mut shared = SHARED_ISA_IRQS.lock();
+    let sources = shared.entry(irq).or_default();
+    if level {
+        sources.insert(source);
+    } else {
+        sources.remove(&source);
+    }
+    i8259_pic::set_irq(irq, !sources.is_empty());
+}
+
+/// Request the VMM to shut down or reset the VM after the current VM exit.
+pub fn request_lifecycle_event(event: LifecycleEvent) {
+    LIFECYCLE_EVENT.lock().replace(event);
+}
+
+pub fn has_lifecycle_event() -> bool {
+    LIFECYCLE_EVENT.lock().is_some()
+}
+
+pub fn take_lifecycle_event() -> Option<LifecycleEvent> {
+    LIFECYCLE_EVENT.lock().take()
+}
@@ -0,0 +1,235 @@
+//! Emulated PCI configuration mechanism #1 with the functions of a minimal
+//! i440FX platform: the 82441FX host bridge and the PIIX3 PCI-to-ISA bridge.
+//! (ref: PCI Local Bus Specification 3.0, Section 3.2.2.3.2)
+
+use alloc::vec::Vec;
+
+use super::PortIoDevice;
+use axerrno::AxResult;
+use spin::Mutex;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+const CONFIG_SPACE_SIZE: usize = 256;
+
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_DEVICE_ID: usize = 0x02;
+const PCI_COMMAND: usize = 0x04;
+const PCI_REVISION_ID: usize = 0x08;
+const PCI_CLASS_PROG: usize = 0x09;
+const PCI_HEADER_TYPE: usize = 0x0e;
+/// The device-specific registers, all writable.
+const PCI_DEVICE_SPECIFIC: usize = 0x40;
+const PCI_HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+
+const PCI_VENDOR_ID_INTEL: u16 = 0x8086;
+const PCI_DEVICE_ID_INTEL_82441: u16 = 0x1237;
+const PCI_DEVICE_ID_INTEL_82371SB_0: u16 = 0x7000;
+const PCI_CLASS_BRIDGE_HOST: u32 = 0x06_00_00;
+const PCI_CLASS_BRIDGE_ISA: u32 = 0x06_01_00;
+
+/// The PAM registers of the 82441FX, each has two 4-bit attribute fields.
+/// (Intel 82441FX datasheet, Section 3.2.18)
+const I440FX_PAM: usize = 0x59;
+const I440FX_PAM_REGS: usize = 7;
+const PAM_READ_ENABLE: u8 = 1 << 0;
+const PAM_WRITE_ENABLE: u8 = 1 << 1;
+/// Either enable maps the shadow RAM.
+const PAM_SHADOW: u8 = PAM_READ_ENABLE | PAM_WRITE_ENABLE;
+/// PIRQ route control registers of the PIIX3, disabled (bit 7) by default.
+const PIIX3_PIRQC: usize = 0x60;
+
+/// The memory range controlled by the PAM attribute field `field`, fields are
+/// numbered from the low half of the first register, which is reserved.
+fn pam_range(field: usize) -> core::ops::Range<usize> {
+    match field {
+        1 => 0xf_0000..0x10_0000,
+        _ => {
+            let start = 0xc_0000 + (field - 2) * 0x4000;
+            start..start + 0x4000
+        }
+    }
+}
+
+/// A PCI function on bus 0.
+struct PciFunction {
+    /// Device and function number, `device << 3 | function`.
+    devfn: u8,
+    config: [u8; CONFIG_SPACE_SIZE],
+}
+
+impl PciFunction {
+    fn new(devfn: u8, vendor: u16, device: u16, class: u32, header_type: u8) -> Self {
+        let mut config = [0; CONFIG_SPACE_SIZE];
+        config[PCI_VENDOR_ID..PCI_VENDOR_ID + 2].copy_from_slice(&vendor.to_le_bytes());
+        config[PCI_DEVICE_ID..PCI_DEVICE_ID + 2].copy_from_slice(&device.to_le_bytes());
+        config[PCI_REVISION_ID] = 0x02;
+        config[PCI_CLASS_PROG..PCI_CLASS_PROG + 3].copy_from_slice(&class.to_le_bytes()[..3]);
+        config[PCI_HEADER_TYPE] = header_type;
+        Self { devfn, config }
+    }
+
+    fn read(&self, offset: usize, access_size: u8) -> u32 {
+        let mut buf = [0; 4];
+        let size = access_size as usize;
+        buf[..size].copy_from_slice(&self.config[offset..offset + size]);
+        u32::from_le_bytes(buf)
+    }
+
+    /// Only the command register and the device-specific registers are
+    /// writable, there is no BAR.
+    fn write(&mut self, offset: usize, access_size: u8, value: u32) {
+        let bytes = value.to_le_bytes();
+        for (i, &byte) in bytes[..access_size as usize].iter().enumerate() {
+            let offset = offset + i;
+            if (PCI_COMMAND..PCI_COMMAND + 2).contains(&offset) || offset >= PCI_DEVICE_SPECIFIC {
+                self.config[offset] = byte;
+            }
+        }
+    }
+}
+
+struct PciBus {
+    /// The CONFIG_ADDRESS register.
+    address: u32,
+    functions: Vec<PciFunction>,
+}
+
+impl PciBus {
+    fn new() -> Self {
+        let host_bridge = PciFunction::new(
+            0,
+            PCI_VENDOR_ID_INTEL,
+            PCI_DEVICE_ID_INTEL_82441,
+            PCI_CLASS_BRIDGE_HOST,
+            0,
+        );
+        let mut isa_bridge = PciFunction::new(
+            1 << 3,
+            PCI_VENDOR_ID_INTEL,
+            PCI_DEVICE_ID_INTEL_82371SB_0,
+            PCI_CLASS_BRIDGE_ISA,
+            PCI_HEADER_TYPE_MULTI_FUNCTION,
+        );
+        isa_bridge.config[PIIX3_PIRQC..PIIX3_PIRQC + 4].fill(0x80);
+        Self {
+            address: 0,
+            functions: Vec::from([host_bridge, isa_bridge]),
+        }
+    }
+
+    /// The function and the register offset selected by CONFIG_ADDRESS.
+    fn selected(&mut self, port: u16) -> Option<(&mut PciFunction, usize)> {
+        let bus = (self.address >> 16) as u8;
+        let devfn = (self.address >> 8) as u8;
+        if self.address & CONFIG_ADDRESS_ENABLE == 0 || bus != 0 {
+            return None;
+        }
+        let offset = (self.address & 0xfc) as usize + (port - CONFIG_DATA_PORT) as usize;
+        self.functions
+            .iter_mut()
+            .find(|f| f.devfn == devfn)
+            .map(|f| (f, offset))
+    }
+
+    fn write_data(&mut self, port: u16, access_size: u8, value: u32) -> AxResult {
+        let Some((function, offset)) = self.selected(port) else {
+            return Ok(());
+        };
+        if function.devfn != 0 {
+            function.write(offset, access_size, value);
+            return Ok(());
+        }
+        let old_pam = function.config[I440FX_PAM..I440FX_PAM + I440FX_PAM_REGS].to_vec();
+        function.write(offset, access_size, value);
+        let new_pam = &function.config[I440FX_PAM..I440FX_PAM + I440FX_PAM_REGS];
+        for field in 1..I440FX_PAM_REGS * 2 {
+            let attr = |pam: &[u8]| (pam[field / 2] >> (field % 2 * 4)) & 0xf;
+            let (old, new) = (attr(&old_pam), attr(new_pam));
+            if old == new {
+                continue;
+            }
+            if old & PAM_SHADOW != 0 && new & PAM_SHADOW != 0 {
+                // still the shadow RAM, only the write access may change.
+                crate::firmware::protect_shadow(pam_range(field), new & PAM_WRITE_ENABLE != 0)?;
+            } else {
+                crate::firmware::update_pam(
+                    pam_range(field),
+                    new & PAM_READ_ENABLE != 0,
+                    new & PAM_WRITE_ENABLE != 0,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref PCI_BUS: Mutex<PciBus> = Mutex::new(PciBus::new());
+}
+
+/// The CONFIG_ADDRESS register (0xcf8), only accessed as a dword. Other
+/// accesses are ignored, as the reset register is at 0xcf9.
+pub struct PciConfigAddress;
+
+impl PortIoDevice for PciConfigAddress {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        CONFIG_ADDRESS_PORT..CONFIG_ADDRESS_PORT + 1
+    }
+
+    fn read(&self, _port: u16, access_size: u8) -> AxResult<u32> {
+        if access_size != 4 {
+            return Ok(u32::MAX);
+        }
+        Ok(PCI_BUS.lock().address)
+    }
+
+    fn write(&self, _port: u16, access_size: u8, value: u32) -> AxResult {
+        if access_size == 4 {
+            PCI_BUS.lock().address = value;
+        }
+        Ok(())
+    }
+}
+
+/// The CONFIG_DATA register (0xcfc-0xcff), accessing the configuration space
+/// selected by CONFIG_ADDRESS. Absent functions and unaligned accesses read as
+/// all ones, and their writes are ignored.
+pub struct PciConfigData;
+
+impl PortIoDevice for PciConfigData {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        CONFIG_DATA_PORT..CONFIG_DATA_PORT + 4
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> AxResult<u32> {
+        if port as usize % access_size as usize != 0 {
+            warn!(
+                "Unaligned PCI config read: {:#x}, size {}",
+                port, access_size
+            );
+            return Ok(u32::MAX);
+        }
+        let mut bus = PCI_BUS.lock();
+        Ok(match bus.selected(port) {
+            Some((function, offset)) => function.read(offset, access_size),
+            None => u32::MAX,
+        })
+    }
+
+    fn write(&self, port: u16, access_size: u8, value: u32) -> AxResult {
+        if port as usize % access_size as usize != 0 {
+            warn!(
+                "Unaligned PCI config write: {:#x}, size {}",
+                port, access_size
+            );
+            return Ok(());
+        }
+        PCI_BUS.lock().write_data(port, access_size, value)
+    }
+
+    fn reset(&self) {
+        *PCI_BUS.lock() = PciBus::new();
+    }
+}
@@ -1,9 +1,9 @@
-//! Emulated UART 16550. (ref: https://wiki.osdev.org/Serial_Ports)
+//! Emulated UART 16550A. (ref: https://wiki.osdev.org/Serial_Ports, and the
+//! PC16550D datasheet)
 
-use super::PortIoDevice;
-
-use axhal::console;
+use super::{CharBackend, PortIoDevice};
 
+use alloc::sync::Arc;
 use axerrno::{AxError, AxResult};
 use spin::Mutex;
 
@@ -19,15 +19,87 @@ const SCRATCH_REG: u16 = 7;
 const UART_FIFO_CAPACITY: usize = 16;
 
 bitflags::bitflags! {
+    /// Interrupt enable flags
+    #[derive(Clone, Copy)]
+    struct IntEnFlags: u8 {
+        const RECEIVED_AVAILABLE = 1;
+        const TRANSMITTER_EMPTY = 1 << 1;
+        const RECEIVER_LINE_STATUS = 1 << 2;
+        const MODEM_STATUS = 1 << 3;
+    }
+
+    /// FIFO control flags
+    #[derive(Clone, Copy)]
+    struct FifoCtrlFlags: u8 {
+        const ENABLE = 1;
+        const CLEAR_RECEIVER = 1 << 1;
+        const CLEAR_TRANSMITTER = 1 << 2;
+        const DMA_MODE = 1 << 3;
+        const TRIGGER_LEVEL = 0b11 << 6;
+    }
+
+    /// Line control flags
+    #[derive(Clone, Copy)]
+    struct LineCtrlFlags: u8 {
+        const WORD_LENGTH = 0b11;
+        const STOP_BITS = 1 << 2;
+        const PARITY = 0b111 << 3;
+        const BREAK = 1 << 6;
+        const DIVISOR_LATCH_ACCESS = 1 << 7;
+    }
+
+    /// Modem control flags
+    #[derive(Clone, Copy)]
+    struct ModemCtrlFlags: u8 {
+        const DATA_TERMINAL_READY = 1;
+        const REQUEST_TO_SEND = 1 << 1;
+        const OUT1 = 1 << 2;
+        const OUT2 = 1 << 3;
+        const LOOPBACK = 1 << 4;
+    }
+
     /// Line status flags
+    #[derive(Clone, Copy)]
     struct LineStsFlags: u8 {
         const INPUT_FULL = 1;
-        // 1 to 4 unknown
+        const OVERRUN_ERROR = 1 << 1;
+        const PARITY_ERROR = 1 << 2;
+        const FRAMING_ERROR = 1 << 3;
+        const BREAK_INTERRUPT = 1 << 4;
         const OUTPUT_EMPTY = 1 << 5;
-        // 6 and 7 unknown
+        const TRANSMITTER_EMPTY = 1 << 6;
+        const FIFO_ERROR = 1 << 7;
+        /// Errors which cause receiver line status interrupts.
+        const ERRORS = Self::OVERRUN_ERROR.bits()
+            | Self::PARITY_ERROR.bits()
+            | Self::FRAMING_ERROR.bits()
+            | Self::BREAK_INTERRUPT.bits();
+    }
+
+    /// Modem status flags
+    #[derive(Clone, Copy)]
+    struct ModemStsFlags: u8 {
+        const DELTA_CLEAR_TO_SEND = 1;
+        const DELTA_DATA_SET_READY = 1 << 1;
+        const TRAILING_EDGE_RING_INDICATOR = 1 << 2;
+        const DELTA_DATA_CARRIER_DETECT = 1 << 3;
+        const CLEAR_TO_SEND = 1 << 4;
+        const DATA_SET_READY = 1 << 5;
+        const RING_INDICATOR = 1 << 6;
+        const DATA_CARRIER_DETECT = 1 << 7;
+        const DELTAS = 0x0f;
     }
 }
 
+/// Interrupt identifications in IIR, from the highest priority to the lowest.
+const IIR_NO_INT_PENDING: u8 = 0x01;
+const IIR_RECEIVER_LINE_STATUS: u8 = 0x06;
+const IIR_RECEIVED_AVAILABLE: u8 = 0x04;
+const IIR_CHARACTER_TIMEOUT: u8 = 0x0c;
+const IIR_TRANSMITTER_EMPTY: u8 = 0x02;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
 /// FIFO queue for caching bytes read.
 struct Fifo<const CAP: usize> {
     buf: [u8; CAP],
@@ -44,12 +116,17 @@ impl<const CAP: usize> Fifo<CAP> {
         }
     }
 
+    fn len(&self) -> usize {
+        self.num
+    }
+
     fn is_empty(&self) -> bool {
         self.num == 0
     }
 
-    fn is_full(&self) -> bool {
-        self.num == CAP
+    fn clear(&mut self) {
+        self.head = 0;
+        self.num = 0;
     }
 
     fn push(&mut self, value: u8) {
@@ -68,52 +145,319 @@ impl<const CAP: usize> Fifo<CAP> {
     }
 }
 
-pub struct Uart16550 {
-    port_base: u16,
-    fifo: Mutex<Fifo<UART_FIFO_CAPACITY>>,
+/// Registers and internal states of the UART.
+struct UartState {
+    backend: Arc<dyn CharBackend>,
+    rx_fifo: Fifo<UART_FIFO_CAPACITY>,
+    divisor: u16,
+    ier: IntEnFlags,
+    fcr: FifoCtrlFlags,
+    lcr: LineCtrlFlags,
+    mcr: ModemCtrlFlags,
+    lsr: LineStsFlags,
+    msr: ModemStsFlags,
+    scratch: u8,
+    /// THR becomes empty, cleared by reading IIR or writing THR.
+    thr_int_pending: bool,
+    /// The receiver FIFO has not been read since the last poll.
+    timeout_int_pending: bool,
+    rx_read_since_poll: bool,
 }
 
-impl PortIoDevice for Uart16550 {
-    fn port_range(&self) -> core::ops::Range<u16> {
-        self.port_base..self.port_base + 8
+impl UartState {
+    fn new(backend: Arc<dyn CharBackend>) -> Self {
+        Self {
+            backend,
+            rx_fifo: Fifo::new(),
+            divisor: 12, // 9600 baud
+            ier: IntEnFlags::empty(),
+            fcr: FifoCtrlFlags::empty(),
+            lcr: LineCtrlFlags::empty(),
+            mcr: ModemCtrlFlags::empty(),
+            lsr: LineStsFlags::OUTPUT_EMPTY.union(LineStsFlags::TRANSMITTER_EMPTY),
+            msr: ModemStsFlags::CLEAR_TO_SEND
+                .union(ModemStsFlags::DATA_SET_READY)
+                .union(ModemStsFlags::DATA_CARRIER_DETECT),
+            scratch: 0,
+            thr_int_pending: false,
+            timeout_int_pending: false,
+            rx_read_since_poll: false,
+        }
     }
 
-    fn read(&self, port: u16, access_size: u8) -> AxResult<u32> {
-        if access_size != 1 {
-            error!("Invalid serial port I/O read size: {} != 1", access_size);
-            return Err(AxError::InvalidInput);
+    fn fifo_enabled(&self) -> bool {
+        self.fcr.contains(FifoCtrlFlags::ENABLE)
+    }
+
+    fn loopback(&self) -> bool {
+        self.mcr.contains(ModemCtrlFlags::LOOPBACK)
+    }
+
+    /// Number of bytes in the receiver FIFO to trigger the received data interrupt.
+    fn trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match self.fcr.bits() >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    /// Number of bytes the receiver holds, only RBR in the 16450 mode.
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            UART_FIFO_CAPACITY
+        } else {
+            1
+        }
+    }
+
+    /// Receive a byte from the serial line.
+    fn receive(&mut self, byte: u8) {
+        if self.rx_fifo.len() >= self.rx_capacity() {
+            self.lsr |= LineStsFlags::OVERRUN_ERROR;
+        } else {
+            self.rx_fifo.push(byte);
+            self.lsr |= LineStsFlags::INPUT_FULL;
+        }
+    }
+
+    /// Transmit a byte from THR, which becomes empty immediately.
+    fn transmit(&mut self, byte: u8) {
+        if self.loopback() {
+            self.receive(byte);
+        } else {
+            self.backend.write_byte(byte);
+        }
+        self.thr_int_pending = true;
+    }
+
+    /// Fetch available bytes from the backend.
+    fn receive_from_backend(&mut self) {
+        if self.loopback() {
+            return;
+        }
+        while self.rx_fifo.len() < self.rx_capacity() {
+            match self.backend.read_byte() {
+                Some(c) => self.receive(c),
+                None => break,
+            }
+        }
+    }
+
+    /// The highest priority pending interrupt in IIR. (PC16550D datasheet, Table 4)
+    fn interrupt_id(&self) -> u8 {
+        let ier = self.ier;
+        if ier.contains(IntEnFlags::RECEIVER_LINE_STATUS)
+            && self.lsr.intersects(LineStsFlags::ERRORS)
+        {
+            IIR_RECEIVER_LINE_STATUS
+        } else if ier.contains(IntEnFlags::RECEIVED_AVAILABLE)
+            && self.rx_fifo.len() >= self.trigger_level()
+        {
+            IIR_RECEIVED_AVAILABLE
+        } else if ier.contains(IntEnFlags::RECEIVED_AVAILABLE) && self.timeout_int_pending {
+            IIR_CHARACTER_TIMEOUT
+        } else if ier.contains(IntEnFlags::TRANSMITTER_EMPTY) && self.thr_int_pending {
+            IIR_TRANSMITTER_EMPTY
+        } else if ier.contains(IntEnFlags::MODEM_STATUS)
+            && self.msr.intersects(ModemStsFlags::DELTAS)
+        {
+            IIR_MODEM_STATUS
+        } else {
+            IIR_NO_INT_PENDING
+        }
+    }
+
+    /// The interrupt output to the PIC, gated by OUT2 and disconnected in loopback mode.
+    fn irq_level(&self) -> bool {
+        self.interrupt_id() != IIR_NO_INT_PENDING
+            && self.mcr.contains(ModemCtrlFlags::OUT2)
+            && !self.loopback()
+    }
+
+    fn read_iir(&mut self) -> u8 {
+        let id = self.interrupt_id();
+        if id == IIR_TRANSMITTER_EMPTY {
+            self.thr_int_pending = false;
+        }
+        if self.fifo_enabled() {
+            id | IIR_FIFO_ENABLED
+        } else {
+            id
         }
-        let ret = match port - self.port_base {
+    }
+
+    fn write_fcr(&mut self, value: u8) {
+        let fcr = FifoCtrlFlags::from_bits_truncate(value);
+        if fcr.contains(FifoCtrlFlags::ENABLE) != self.fifo_enabled()
+            || fcr.contains(FifoCtrlFlags::CLEAR_RECEIVER)
+        {
+            // changing the FIFO enable bit also clears the FIFOs
+            self.rx_fifo.clear();
+            self.lsr.remove(LineStsFlags::INPUT_FULL);
+            self.timeout_int_pending = false;
+        }
+        self.fcr =
+            fcr & (FifoCtrlFlags::ENABLE | FifoCtrlFlags::DMA_MODE | FifoCtrlFlags::TRIGGER_LEVEL);
+    }
+
+    /// Modem status inputs, which are connected to the modem control outputs in
+    /// loopback mode, or always active otherwise.
+    fn write_mcr(&mut self, value: u8) {
+        self.mcr = ModemCtrlFlags::from_bits_truncate(value);
+        let old = self.msr;
+        let mut new = if self.loopback() {
+            let mut msr = ModemStsFlags::empty();
+            msr.set(
+                ModemStsFlags::CLEAR_TO_SEND,
+                self.mcr.contains(ModemCtrlFlags::REQUEST_TO_SEND),
+            );
+            msr.set(
+                ModemStsFlags::DATA_SET_READY,
+                self.mcr.contains(ModemCtrlFlags::DATA_TERMINAL_READY),
+            );
+            msr.set(
+                ModemStsFlags::RING_INDICATOR,
+                self.mcr.contains(ModemCtrlFlags::OUT1),
+            );
+            msr.set(
+                ModemStsFlags::DATA_CARRIER_DETECT,
+                self.mcr.contains(ModemCtrlFlags::OUT2),
+            );
+            msr
+        } else {
+            ModemStsFlags::CLEAR_TO_SEND
+                | ModemStsFlags::DATA_SET_READY
+                | ModemStsFlags::DATA_CARRIER_DETECT
+        };
+        let changed = old.bits() ^ new.bits();
+        new |= old & ModemStsFlags::DELTAS;
+        new.set(
+            ModemStsFlags::DELTA_CLEAR_TO_SEND,
+            new.contains(ModemStsFlags::DELTA_CLEAR_TO_SEND)
+                || changed & ModemStsFlags::CLEAR_TO_SEND.bits() != 0,
+        );
+        new.set(
+            ModemStsFlags::DELTA_DATA_SET_READY,
+            new.contains(ModemStsFlags::DELTA_DATA_SET_READY)
+                || changed & ModemStsFlags::DATA_SET_READY.bits() != 0,
+        );
+        new.set(
+            ModemStsFlags::DELTA_DATA_CARRIER_DETECT,
+            new.contains(ModemStsFlags::DELTA_DATA_CARRIER_DETECT)
+                || changed & ModemStsFlags::DATA_CARRIER_DETECT.bits() != 0,
+        );
+        if old.contains(ModemStsFlags::RING_INDICATOR)
+            && !new.contains(ModemStsFlags::RING_INDICATOR)
+        {
+            new |= ModemStsFlags::TRAILING_EDGE_RING_INDICATOR;
+        }
+        self.msr = new;
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        let dlab = self.lcr.contains(LineCtrlFlags::DIVISOR_LATCH_ACCESS);
+        match offset {
+            DATA_REG if dlab => self.divisor as u8,
             DATA_REG => {
                 // read a byte from FIFO
-                let mut fifo = self.fifo.lock();
-                if fifo.is_empty() {
-                    0
-                } else {
-                    fifo.pop()
+                self.rx_read_since_poll = true;
+                self.timeout_int_pending = false;
+                if self.rx_fifo.is_empty() {
+                    return 0;
+                }
+                let byte = self.rx_fifo.pop();
+                if self.rx_fifo.is_empty() {
+                    self.lsr.remove(LineStsFlags::INPUT_FULL);
                 }
+                byte
             }
+            INT_EN_REG if dlab => (self.divisor >> 8) as u8,
+            INT_EN_REG => self.ier.bits(),
+            FIFO_CTRL_REG => self.read_iir(),
+            LINE_CTRL_REG => self.lcr.bits(),
+            MODEM_CTRL_REG => self.mcr.bits(),
             LINE_STATUS_REG => {
-                // check if the physical serial port has an available byte, and push it to FIFO.
-                let mut fifo = self.fifo.lock();
-                if !fifo.is_full() {
-                    if let Some(c) = console::console_getchar() {
-                        fifo.push(c);
-                    }
-                }
-                let mut lsr = LineStsFlags::OUTPUT_EMPTY;
-                if !fifo.is_empty() {
-                    lsr |= LineStsFlags::INPUT_FULL;
+                // check if the backend has available bytes, and push them to FIFO.
+                self.receive_from_backend();
+                let mut lsr = self.lsr;
+                if self.fifo_enabled() && lsr.intersects(LineStsFlags::ERRORS) {
+                    lsr |= LineStsFlags::FIFO_ERROR;
                 }
+                // errors are cleared on read
+                self.lsr.remove(LineStsFlags::ERRORS);
                 lsr.bits()
             }
-            INT_EN_REG | FIFO_CTRL_REG | LINE_CTRL_REG | MODEM_CTRL_REG | MODEM_STATUS_REG
-            | SCRATCH_REG => {
-                info!("Unimplemented serial port I/O read: {:#x}", port); // unimplemented
-                0
+            MODEM_STATUS_REG => {
+                let msr = self.msr;
+                self.msr.remove(ModemStsFlags::DELTAS);
+                msr.bits()
             }
+            SCRATCH_REG => self.scratch,
             _ => unreachable!(),
-        };
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        let dlab = self.lcr.contains(LineCtrlFlags::DIVISOR_LATCH_ACCESS);
+        match offset {
+            DATA_REG if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            DATA_REG => self.transmit(value),
+            INT_EN_REG if dlab => self.divisor = (self.divisor & 0xff) | (value as u16) << 8,
+            INT_EN_REG => {
+                let ier = IntEnFlags::from_bits_truncate(value);
+                if ier.contains(IntEnFlags::TRANSMITTER_EMPTY)
+                    && !self.ier.contains(IntEnFlags::TRANSMITTER_EMPTY)
+                {
+                    // THR is always empty, enabling the interrupt triggers it immediately
+                    self.thr_int_pending = true;
+                }
+                self.ier = ier;
+            }
+            FIFO_CTRL_REG => self.write_fcr(value),
+            LINE_CTRL_REG => self.lcr = LineCtrlFlags::from_bits_truncate(value),
+            MODEM_CTRL_REG => self.write_mcr(value),
+            LINE_STATUS_REG | MODEM_STATUS_REG => {} // ignore
+            SCRATCH_REG => self.scratch = value,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Receive input from the host, and emulate the character timeout: bytes below
+    /// the trigger level which are not read since the last poll.
+    fn poll(&mut self) {
+        self.receive_from_backend();
+        self.timeout_int_pending = self.fifo_enabled()
+            && !self.rx_fifo.is_empty()
+            && self.rx_fifo.len() < self.trigger_level()
+            && !self.rx_read_since_poll;
+        self.rx_read_since_poll = false;
+    }
+}
+
+pub struct Uart16550 {
+    port_base: u16,
+    irq: u8,
+    state: Mutex<UartState>,
+}
+
+impl PortIoDevice for Uart16550 {
+    fn port_range(&self) -> core::ops::Range<u16> {
+        self.port_base..self.port_base + 8
+    }
+
+    fn read(&self, port: u16, access_size: u8) -> AxResult<u32> {
+        if access_size != 1 {
+            error!("Invalid serial port I/O read size: {} != 1", access_size);
+            return Err(AxError::InvalidInput);
+        }
+        let mut state = self.state.lock();
+        let ret = state.read(port - self.port_base);
+        self.update_irq(&state);
         Ok(ret as u32)
     }
 
@@ -122,23 +466,39 @@ impl PortIoDevice for Uart16550 {
             error!("Invalid serial port I/O write size: {} != 1", access_size);
             return Err(AxError::InvalidInput);
         }
-        match port - self.port_base {
-            DATA_REG => console::putchar(value as u8),
-            INT_EN_REG | FIFO_CTRL_REG | LINE_CTRL_REG | MODEM_CTRL_REG | SCRATCH_REG => {
-                info!("Unimplemented serial port I/O write: {:#x}", port); // unimplemented
-            }
-            LINE_STATUS_REG => {} // ignore
-            _ => unreachable!(),
-        }
+        let mut state = self.state.lock();
+        state.write(port - self.port_base, value as u8);
+        self.update_irq(&state);
         Ok(())
     }
+
+    fn poll(&self) {
+        let mut state = self.state.lock();
+        state.poll();
+        self.update_irq(&state);
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock();
+        *state = UartState::new(state.backend.clone());
+        self.update_irq(&state);
+    }
+
+    fn shutdown(&self) {
+        self.state.lock().backend.flush();
+    }
 }
 
 impl Uart16550 {
-    pub const fn new(port_base: u16) -> Self {
+    pub fn new(port_base: u16, irq: u8, backend: Arc<dyn CharBackend>) -> Self {
         Self {
             port_base,
-            fifo: Mutex::new(Fifo::new()),
+            irq,
+            state: Mutex::new(UartState::new(backend)),
         }
     }
+
+    fn update_irq(&self, state: &UartState) {
+        super::set_shared_isa_irq(self.irq, self.port_base, state.irq_level());
+    }
 }
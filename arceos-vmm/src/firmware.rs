@@ -0,0 +1,124 @@
+//! Firmware started from the reset vector, e.g. SeaBIOS.
+//!
+//! The firmware ROM is mapped at the top of 4 GiB, and its last 128 KiB at most
+//! is aliased below 1 MiB. As on the i440FX, the PAM registers of the host bridge
+//! switch each segment of the alias between the ROM and the shadow RAM, which
+//! the firmware copies itself to. (Intel 82441FX datasheet, Section 3.2.18)
+
+use alloc::vec;
+use core::ops::Range;
+
+use axerrno::AxResult;
+use axvm::GuestPhysAddr;
+use memory_addr::align_up_4k;
+use page_table_entry::MappingFlags;
+use spin::Once;
+
+use crate::gpm::MapRegion;
+
+const MAX_32BIT_ADDR: GuestPhysAddr = 0x1_0000_0000;
+/// The legacy BIOS area controlled by the PAM registers.
+const BIOS_AREA: Range<GuestPhysAddr> = 0xc_0000..0x10_0000;
+/// Maximum size of the ROM alias below 1 MiB.
+const ISA_BIOS_MAX_SIZE: usize = 0x2_0000;
+
+struct Firmware {
+    /// The whole ROM, not mapped itself but aliased.
+    rom: MapRegion,
+    /// The shadow RAM of the BIOS area.
+    shadow: MapRegion,
+}
+
+impl Firmware {
+    /// Start of the ROM alias below 1 MiB.
+    fn isa_bios_start(&self) -> GuestPhysAddr {
+        BIOS_AREA.end - self.rom.size.min(ISA_BIOS_MAX_SIZE)
+    }
+}
+
+static FIRMWARE: Once<Firmware> = Once::new();
+
+/// Maps the firmware image `file` on the first call, and switches the BIOS
+/// area back to the ROM as after RESET.
+pub fn setup(file: &str) -> AxResult {
+    let firmware = match FIRMWARE.get() {
+        Some(firmware) => firmware,
+        None => {
+            let image = crate::read_file(file)?;
+            let rom = MapRegion::new_rom(MAX_32BIT_ADDR - align_up_4k(image.len()), &image)?;
+            info!(
+                "Firmware {} ({:#x} bytes) mapped at {:#x}",
+                file,
+                image.len(),
+                rom.start
+            );
+            let shadow_size = BIOS_AREA.end - BIOS_AREA.start;
+            let firmware = Firmware {
+                rom: rom.new_rom_alias(rom.start, 0, rom.size)?,
+                shadow: MapRegion::new_rom(BIOS_AREA.start, &vec![0; shadow_size])?,
+            };
+            crate::guest_phys_memory().remap_region(rom)?;
+            FIRMWARE.call_once(|| firmware)
+        }
+    };
+    let isa_bios = firmware.isa_bios_start()..BIOS_AREA.end;
+    update_pam(isa_bios, false, false)
+}
+
+/// Maps `range` of the BIOS area according to its PAM attributes: the ROM if
+/// both reads and writes are disabled, otherwise the shadow RAM, writable if
+/// writes are enabled. The range out of the ROM alias is always RAM.
+pub fn update_pam(range: Range<GuestPhysAddr>, read_enable: bool, write_enable: bool) -> AxResult {
+    let Some(firmware) = FIRMWARE.get() else {
+        return Ok(());
+    };
+    let start = range.start.max(firmware.isa_bios_start());
+    if start >= range.end {
+        return Ok(());
+    }
+    let size = range.end - start;
+    trace!(
+        "PAM {:#x}..{:#x}: read {}, write {}",
+        start,
+        range.end,
+        read_enable,
+        write_enable
+    );
+    let region = if read_enable || write_enable {
+        // write-only segments read the shadow RAM as well.
+        let mut region = firmware
+            .shadow
+            .new_rom_alias(start, start - BIOS_AREA.start, size)?;
+        if write_enable {
+            region.flags |= MappingFlags::WRITE;
+        }
+        region
+    } else {
+        let offset = firmware.rom.size - (BIOS_AREA.end - start);
+        firmware.rom.new_rom_alias(start, offset, size)?
+    };
+    crate::guest_phys_memory().remap_region(region)
+}
+
+/// Enables or disables writes to `range` of the BIOS area mapped to the shadow
+/// RAM, e.g. when the firmware locks itself after copied to the shadow RAM.
+pub fn protect_shadow(range: Range<GuestPhysAddr>, write_enable: bool) -> AxResult {
+    let Some(firmware) = FIRMWARE.get() else {
+        return Ok(());
+    };
+    let start = range.start.max(firmware.isa_bios_start());
+    if start >= range.end {
+        return Ok(());
+    }
+    trace!(
+        "PAM {:#x}..{:#x}: shadow RAM write {}",
+        start,
+        range.end,
+        write_enable
+    );
+    let mut flags = firmware.shadow.flags;
+    if write_enable {
+        flags |= MappingFlags::WRITE;
+    }
+    crate::guest_phys_memory().protect_region(start, range.end - start, flags)
+}
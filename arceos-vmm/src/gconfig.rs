@@ -1,6 +1,50 @@
 use axvm::GuestPhysAddr;
 
+use crate::boot::BootProtocol;
+use crate::device_emu::CharBackendConfig;
+
+// Defaults of the VM configuration, used if there is no `VM_CONFIG_FILE`.
+
+/// The VM configuration file on the file system, see `config/mod.rs`.
+pub const VM_CONFIG_FILE: &str = "vm.toml";
+
 pub const GUEST_PHYS_MEMORY_BASE: GuestPhysAddr = 0;
 pub const BIOS_ENTRY: GuestPhysAddr = 0x8000;
 pub const GUEST_ENTRY: GuestPhysAddr = 0x20_0000;
 pub const GUEST_PHYS_MEMORY_SIZE: usize = 0x100_0000; // 16M
+/// The maximum number of vCPUs supported.
+pub const MAX_VCPUS: usize = 1;
+pub const IOAPIC_BASE: GuestPhysAddr = 0xfec0_0000;
+pub const HPET_BASE: Option<GuestPhysAddr> = Some(0xfed0_0000);
+/// PCI Express ECAM base, start and end bus numbers, `None` if not present.
+pub const PCI_ECAM: Option<(GuestPhysAddr, u8, u8)> = None;
+/// Guest physical address of the ACPI tables, starting with the RSDP.
+pub const ACPI_TABLES_GPA: GuestPhysAddr = 0xe_0000;
+/// The BIOS entering raw Multiboot images, loaded at `BIOS_ENTRY`.
+pub const BIOS_IMAGE: &str = "rvm-bios.bin";
+/// The guest image and how it is booted.
+pub const GUEST_IMAGE: &str = "nimbos.bin";
+pub const BOOT_PROTOCOL: BootProtocol = BootProtocol::Multiboot;
+/// Guest physical address of the boot information passed to the guest.
+pub const BOOT_INFO_GPA: GuestPhysAddr = 0x1_0000;
+/// Kernel command line passed to the guest.
+pub const GUEST_CMDLINE: &str = "";
+/// The initramfs of the Linux boot protocol.
+pub const GUEST_INITRD: Option<&str> = None;
+/// Boot modules loaded from the file system, as `(file name, command line)`.
+pub const GUEST_BOOT_MODULES: &[(&str, &str)] = &[];
+pub const ENABLE_APIC_VIRT: bool = true; // use VMX APIC virtualization if supported
+pub const VM_NAME: &str = "vm0";
+/// I/O port bases and IRQs of COM1-COM4.
+pub const SERIAL_PORTS: [(u16, u8); 4] = [(0x3f8, 4), (0x2f8, 3), (0x3e8, 4), (0x2e8, 3)];
+/// Backends of COM1-COM4, `None` if the port is absent.
+pub const SERIAL_BACKENDS: [Option<CharBackendConfig>; 4] = [
+    Some(CharBackendConfig::Mux),
+    Some(CharBackendConfig::Mux),
+    Some(CharBackendConfig::Mux),
+    Some(CharBackendConfig::Mux),
+];
+/// Initial RTC time in seconds since the Unix epoch (2024-01-01 00:00:00 UTC).
+pub const RTC_BASE_TIME: u64 = 1_704_067_200;
+/// File to load and save the CMOS NVRAM bytes, `None` to not persist them.
+pub const RTC_NVRAM_FILE: Option<&str> = None;
@@ -1,17 +1,152 @@
-use alloc::collections::BTreeMap;
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
 use core::fmt::{Debug, Formatter, Result};
 
-use axerrno::{AxError, AxResult};
+use axerrno::{ax_err, AxError, AxResult};
+use axhal::mem::{phys_to_virt, virt_to_phys};
 use axhal::paging::{PageSize, PagingIfImpl};
-use axvm::{AxNestedPageTable, GuestPhysAddr, HostPhysAddr};
-use memory_addr::{is_aligned_4k, VirtAddr, PAGE_SIZE_4K as PAGE_SIZE};
+use axvm::{AxNestedPageTable, AxvmHal, GuestPhysAddr, HostPhysAddr, HostVirtAddr};
+use memory_addr::{align_down_4k, align_up_4k, is_aligned_4k, VirtAddr, PAGE_SIZE_4K as PAGE_SIZE};
 use page_table_entry::MappingFlags;
 
+use crate::hal::AxvmHalImpl;
+
 type NestedPageTable = AxNestedPageTable<PagingIfImpl>;
 
-#[derive(Debug)]
+/// Page sizes of the nested page table, from the largest.
+const PAGE_SIZES: [PageSize; 3] = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K];
+
+/// Numbers of the mapped pages of each size.
+#[derive(Debug, Default)]
+struct PageStats {
+    size_4k: usize,
+    size_2m: usize,
+    size_1g: usize,
+}
+
+impl PageStats {
+    fn count_mut(&mut self, page_size: PageSize) -> &mut usize {
+        match page_size {
+            PageSize::Size4K => &mut self.size_4k,
+            PageSize::Size2M => &mut self.size_2m,
+            PageSize::Size1G => &mut self.size_1g,
+        }
+    }
+}
+
+fn npt_map(
+    npt: &mut NestedPageTable,
+    stats: &mut PageStats,
+    gpa: GuestPhysAddr,
+    hpa: HostPhysAddr,
+    page_size: PageSize,
+    flags: MappingFlags,
+) -> AxResult {
+    // Here `VirtAddr` represents `GuestPhysAddr`, the physical address from the Guest's perspective.
+    npt.map(VirtAddr::from(gpa), hpa, page_size, flags)
+        .map_err(|err| {
+            warn!("NestedPageTable map error {:?}", err);
+            AxError::BadState
+        })?;
+    *stats.count_mut(page_size) += 1;
+    Ok(())
+}
+
+/// Unmaps the page containing `gpa`, returns its size.
+fn npt_unmap(
+    npt: &mut NestedPageTable,
+    stats: &mut PageStats,
+    gpa: GuestPhysAddr,
+) -> AxResult<PageSize> {
+    let (_, page_size) = npt.unmap(VirtAddr::from(gpa)).map_err(|err| {
+        warn!("NestedPageTable unmap error {:?}", err);
+        AxError::BadState
+    })?;
+    *stats.count_mut(page_size) -= 1;
+    Ok(page_size)
+}
+
+/// Errors of guest physical memory accesses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestMemoryError {
+    /// The address is not in any region.
+    NotMapped(GuestPhysAddr),
+    /// The address is in a device (MMIO) region, which must be emulated.
+    Mmio(GuestPhysAddr),
+    /// The page of a lazily allocated region is not allocated yet.
+    NotAllocated(GuestPhysAddr),
+    /// Failed to allocate the page of a lazily allocated region.
+    NoMemory,
+}
+
+pub type GuestMemoryResult<T = ()> = core::result::Result<T, GuestMemoryError>;
+
+impl From<GuestMemoryError> for AxError {
+    fn from(err: GuestMemoryError) -> Self {
+        warn!("Guest memory access error: {:#x?}", err);
+        match err {
+            GuestMemoryError::NoMemory => AxError::NoMemory,
+            _ => AxError::BadAddress,
+        }
+    }
+}
+
+/// Allocates `size` bytes of host memory for the guest memory at `gpa`, returns
+/// the host virtual address.
+fn alloc_host_memory(gpa: GuestPhysAddr, size: usize) -> AxResult<usize> {
+    // align the host memory like `gpa` so that huge pages can be used, fall
+    // back to smaller alignments if such memory is not available
+    PAGE_SIZES
+        .into_iter()
+        .map(usize::from)
+        .filter(|&align| gpa % align == 0 && size >= align)
+        .find_map(|align| {
+            axalloc::global_allocator()
+                .alloc_pages(size / PAGE_SIZE, align)
+                .ok()
+        })
+        .ok_or_else(|| {
+            warn!("Failed to allocate {:#x} bytes of guest memory", size);
+            AxError::NoMemory
+        })
+}
+
+/// Host memory holding a ROM image, shared by the regions aliasing the ROM and
+/// freed when the last of them is dropped.
+struct RomImage {
+    vaddr: usize,
+    size: usize,
+}
+
+impl Drop for RomImage {
+    fn drop(&mut self) {
+        axalloc::global_allocator().dealloc_pages(self.vaddr, self.size / PAGE_SIZE);
+    }
+}
+
+/// How guest physical addresses of a region are translated to host physical
+/// addresses, `hpa = gpa - offset` for all variants except `Lazy`.
 enum Mapper {
+    /// Host memory owned by others, e.g. passthrough MMIO.
     Offset(usize),
+    /// Guest RAM allocated from the host, freed when the region is dropped.
+    Alloc(usize),
+    /// Guest RAM allocated page by page on the first access, the frames are
+    /// indexed by the guest page address and freed when the region is dropped.
+    Lazy(BTreeMap<GuestPhysAddr, HostPhysAddr>),
+    /// Firmware image shared by its aliases, guest writes to it are discarded
+    /// unless the region is writable, e.g. the shadow RAM of the BIOS area.
+    Rom(Arc<RomImage>, usize),
+}
+
+impl Debug for Mapper {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            Self::Offset(off) => f.debug_tuple("Offset").field(off).finish(),
+            Self::Alloc(off) => f.debug_tuple("Alloc").field(off).finish(),
+            Self::Lazy(frames) => write!(f, "Lazy({} pages allocated)", frames.len()),
+            Self::Rom(image, off) => write!(f, "Rom({:#x} bytes, {:#x})", image.size, off),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -48,6 +183,114 @@ impl MapRegion {
         }
     }
 
+    /// Allocates zeroed host memory of `size` bytes as guest RAM at `start_gpa`.
+    pub fn new_alloc(start_gpa: GuestPhysAddr, size: usize, flags: MappingFlags) -> AxResult<Self> {
+        if !is_aligned_4k(start_gpa) || !is_aligned_4k(size) || size == 0 {
+            return ax_err!(InvalidInput, "guest RAM region is not 4K aligned");
+        }
+        let vaddr = alloc_host_memory(start_gpa, size)?;
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, size) };
+        let start_hpa = virt_to_phys(HostVirtAddr::from(vaddr));
+        Ok(Self {
+            start: start_gpa,
+            size,
+            flags,
+            mapper: Mapper::Alloc(start_gpa.wrapping_sub(start_hpa.as_usize())),
+        })
+    }
+
+    /// Reserves guest RAM of `size` bytes at `start_gpa`, whose zeroed frames are
+    /// allocated on the first access.
+    pub fn new_lazy(start_gpa: GuestPhysAddr, size: usize, flags: MappingFlags) -> AxResult<Self> {
+        if !is_aligned_4k(start_gpa) || !is_aligned_4k(size) || size == 0 {
+            return ax_err!(InvalidInput, "guest RAM region is not 4K aligned");
+        }
+        Ok(Self {
+            start: start_gpa,
+            size,
+            flags,
+            mapper: Mapper::Lazy(BTreeMap::new()),
+        })
+    }
+
+    /// Copies the firmware `image` to host memory as a read-only ROM at
+    /// `start_gpa`, the size is rounded up to 4K and the rest is filled with 0xff.
+    pub fn new_rom(start_gpa: GuestPhysAddr, image: &[u8]) -> AxResult<Self> {
+        let size = align_up_4k(image.len());
+        if !is_aligned_4k(start_gpa) || size == 0 {
+            return ax_err!(InvalidInput, "ROM region is not 4K aligned");
+        }
+        let vaddr = alloc_host_memory(start_gpa, size)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(image.as_ptr(), vaddr as *mut u8, image.len());
+            core::ptr::write_bytes((vaddr + image.len()) as *mut u8, 0xff, size - image.len());
+        }
+        let start_hpa = virt_to_phys(HostVirtAddr::from(vaddr));
+        Ok(Self {
+            start: start_gpa,
+            size,
+            flags: MappingFlags::READ | MappingFlags::EXECUTE,
+            mapper: Mapper::Rom(
+                Arc::new(RomImage { vaddr, size }),
+                start_gpa.wrapping_sub(start_hpa.as_usize()),
+            ),
+        })
+    }
+
+    /// Maps the part at `offset` of size `size` of this ROM again at `start_gpa`,
+    /// e.g. the reset vector at the top of 4 GiB and the legacy BIOS area.
+    pub fn new_rom_alias(
+        &self,
+        start_gpa: GuestPhysAddr,
+        offset: usize,
+        size: usize,
+    ) -> AxResult<Self> {
+        let Mapper::Rom(image, _) = &self.mapper else {
+            return ax_err!(InvalidInput, "not a ROM region");
+        };
+        if !is_aligned_4k(start_gpa) || !is_aligned_4k(offset) || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "ROM alias is not 4K aligned");
+        }
+        if size == 0 || offset + size > self.size {
+            return ax_err!(InvalidInput, "ROM alias is out of the ROM");
+        }
+        let hpa = self.target(self.start + offset).unwrap();
+        Ok(Self {
+            start: start_gpa,
+            size,
+            flags: self.flags,
+            mapper: Mapper::Rom(image.clone(), start_gpa.wrapping_sub(hpa.as_usize())),
+        })
+    }
+
+    /// Whether the region maps a ROM image.
+    pub fn is_rom(&self) -> bool {
+        matches!(self.mapper, Mapper::Rom(..))
+    }
+
+    fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        (self.start..self.start + self.size).contains(&gpa)
+    }
+
+    /// Splits the region at `at`, returns the part after it.
+    fn split_off(&mut self, at: GuestPhysAddr) -> Self {
+        assert!(is_aligned_4k(at) && self.contains(at) && at != self.start);
+        let mapper = match &mut self.mapper {
+            Mapper::Offset(off) => Mapper::Offset(*off),
+            Mapper::Alloc(off) => Mapper::Alloc(*off),
+            Mapper::Lazy(frames) => Mapper::Lazy(frames.split_off(&at)),
+            Mapper::Rom(image, off) => Mapper::Rom(image.clone(), *off),
+        };
+        let upper_size = self.start + self.size - at;
+        self.size -= upper_size;
+        Self {
+            start: at,
+            size: upper_size,
+            flags: self.flags,
+            mapper,
+        }
+    }
+
     fn is_overlap_with(&self, other: &Self) -> bool {
         let s0 = self.start;
         let e0 = s0 + self.size;
@@ -56,44 +299,127 @@ impl MapRegion {
         !(e0 <= s1 || e1 <= s0)
     }
 
-    fn target(&self, gpa: GuestPhysAddr) -> HostPhysAddr {
-        match self.mapper {
-            Mapper::Offset(off) => HostPhysAddr::from(gpa.wrapping_sub(off)),
+    /// The host physical address of `gpa`, `None` if the page is not allocated yet.
+    fn target(&self, gpa: GuestPhysAddr) -> Option<HostPhysAddr> {
+        match &self.mapper {
+            Mapper::Offset(off) | Mapper::Alloc(off) | Mapper::Rom(_, off) => {
+                Some(HostPhysAddr::from(gpa.wrapping_sub(*off)))
+            }
+            Mapper::Lazy(frames) => frames
+                .get(&align_down_4k(gpa))
+                .map(|&frame| frame + (gpa - align_down_4k(gpa))),
+        }
+    }
+
+    /// Allocates and maps a zeroed frame for the page containing `gpa` of a
+    /// lazily allocated region if not yet, returns the host physical address.
+    fn populate(
+        &mut self,
+        npt: &mut NestedPageTable,
+        stats: &mut PageStats,
+        gpa: GuestPhysAddr,
+    ) -> AxResult<HostPhysAddr> {
+        if let Some(hpa) = self.target(gpa) {
+            return Ok(hpa);
         }
+        let Mapper::Lazy(frames) = &mut self.mapper else {
+            unreachable!()
+        };
+        let page = align_down_4k(gpa);
+        let frame = AxvmHalImpl::alloc_page().ok_or_else(|| {
+            warn!("Failed to allocate guest RAM page {:#x}", page);
+            AxError::NoMemory
+        })?;
+        unsafe { core::ptr::write_bytes(phys_to_virt(frame).as_mut_ptr(), 0, PAGE_SIZE) };
+        frames.insert(page, frame);
+        trace!("Guest RAM page {:#x} allocated at {:#x}", page, frame);
+        npt_map(npt, stats, page, frame, PageSize::Size4K, self.flags)?;
+        Ok(frame + (gpa - page))
     }
 
-    fn map_to(&self, npt: &mut NestedPageTable) -> AxResult {
+    /// Maps the region with the largest pages allowed by the alignment of both
+    /// guest and host addresses.
+    fn map_to(&self, npt: &mut NestedPageTable, stats: &mut PageStats) -> AxResult {
+        if let Mapper::Lazy(frames) = &self.mapper {
+            for (&gpa, &frame) in frames {
+                npt_map(npt, stats, gpa, frame, PageSize::Size4K, self.flags)?;
+            }
+            return Ok(());
+        }
+        let max_page_size = usize::from(axvm::max_nested_page_size());
         let mut start = self.start;
         let end = start + self.size;
         debug!("map_to() {:#x?}", self);
         while start < end {
-            let target = self.target(start);
-            // Here `VirtAddr` represents `GuestPhysAddr`, the physical address from the Guest's perspective.
-            npt.map(VirtAddr::from(start), target, PageSize::Size4K, self.flags)
+            let target = self.target(start).unwrap();
+            let page_size = PAGE_SIZES
+                .into_iter()
+                .find(|&page_size| {
+                    let size = usize::from(page_size);
+                    size <= max_page_size
+                        && start % size == 0
+                        && target.as_usize() % size == 0
+                        && start + size <= end
+                })
+                .unwrap();
+            npt_map(npt, stats, start, target, page_size, self.flags)?;
+            start += usize::from(page_size);
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of the region and its mapped pages.
+    fn protect_to(&mut self, npt: &mut NestedPageTable, flags: MappingFlags) -> AxResult {
+        let update = |npt: &mut NestedPageTable, gpa: GuestPhysAddr| {
+            npt.update(VirtAddr::from(gpa), None, Some(flags))
                 .map_err(|err| {
-                    warn!("NestedPageTable map error {:?}", err);
+                    warn!("NestedPageTable update error {:?}", err);
                     AxError::BadState
-                })?;
-            start += PAGE_SIZE;
+                })
+        };
+        self.flags = flags;
+        if let Mapper::Lazy(frames) = &self.mapper {
+            for &gpa in frames.keys() {
+                update(npt, gpa)?;
+            }
+            return Ok(());
+        }
+        let mut start = self.start;
+        while start < self.start + self.size {
+            start += usize::from(update(npt, start)?);
         }
         Ok(())
     }
 
-    fn unmap_to(&self, npt: &mut NestedPageTable) -> AxResult {
+    fn unmap_to(&self, npt: &mut NestedPageTable, stats: &mut PageStats) -> AxResult {
+        if let Mapper::Lazy(frames) = &self.mapper {
+            for &gpa in frames.keys() {
+                npt_unmap(npt, stats, gpa)?;
+            }
+            return Ok(());
+        }
         let mut start = self.start;
         let end = start + self.size;
         while start < end {
-            // Here `VirtAddr` represents `GuestPhysAddr`, the physical address from the Guest's perspective.
-            npt.unmap(VirtAddr::from(start)).map_err(|err| {
-                warn!("NestedPageTable unmap error {:?}", err);
-                AxError::BadState
-            })?;
-            start += PAGE_SIZE;
+            start += usize::from(npt_unmap(npt, stats, start)?);
         }
         Ok(())
     }
 }
 
+impl Drop for MapRegion {
+    fn drop(&mut self) {
+        match &self.mapper {
+            Mapper::Offset(_) | Mapper::Rom(..) => {}
+            Mapper::Alloc(_) => {
+                let vaddr = phys_to_virt(self.target(self.start).unwrap());
+                axalloc::global_allocator().dealloc_pages(vaddr.as_usize(), self.size / PAGE_SIZE);
+            }
+            Mapper::Lazy(frames) => frames.values().for_each(|&f| AxvmHalImpl::dealloc_page(f)),
+        }
+    }
+}
+
 impl Debug for MapRegion {
     fn fmt(&self, f: &mut Formatter) -> Result {
         f.debug_struct("MapRegion")
@@ -114,6 +440,15 @@ impl From<GuestMemoryRegion> for MapRegion {
 pub struct GuestPhysMemorySet {
     regions: BTreeMap<GuestPhysAddr, MapRegion>,
     npt: NestedPageTable,
+    page_stats: PageStats,
+    /// CPUs running the VM, one bit per CPU.
+    active_cpus: u64,
+    /// CPUs that may cache stale translations of the nested page table, and
+    /// must flush them before the next VM entry.
+    tlb_flush_pending: u64,
+    /// Unmapped regions, whose memory is freed after all CPUs flush the stale
+    /// translations to it.
+    stale_regions: Vec<MapRegion>,
 }
 
 impl GuestPhysMemorySet {
@@ -124,6 +459,10 @@ impl GuestPhysMemorySet {
                 AxError::NoMemory
             })?,
             regions: BTreeMap::new(),
+            page_stats: PageStats::default(),
+            active_cpus: 0,
+            tlb_flush_pending: 0,
+            stale_regions: Vec::new(),
         })
     }
 
@@ -158,16 +497,252 @@ impl GuestPhysMemorySet {
             );
             return Err(AxError::InvalidInput);
         }
-        region.map_to(&mut self.npt)?;
+        region.map_to(&mut self.npt, &mut self.page_stats)?;
         self.regions.insert(region.start, region);
         Ok(())
     }
 
+    /// The region containing `gpa`, which must not be a device region.
+    fn ram_region(&self, gpa: GuestPhysAddr) -> GuestMemoryResult<&MapRegion> {
+        match self.regions.range(..=gpa).next_back() {
+            Some((_, r)) if r.contains(gpa) && r.flags.contains(MappingFlags::DEVICE) => {
+                Err(GuestMemoryError::Mmio(gpa))
+            }
+            Some((_, r)) if r.contains(gpa) => Ok(r),
+            _ => Err(GuestMemoryError::NotMapped(gpa)),
+        }
+    }
+
+    fn ram_region_mut(
+        regions: &mut BTreeMap<GuestPhysAddr, MapRegion>,
+        gpa: GuestPhysAddr,
+    ) -> GuestMemoryResult<&mut MapRegion> {
+        match regions.range_mut(..=gpa).next_back() {
+            Some((_, r)) if r.contains(gpa) && r.flags.contains(MappingFlags::DEVICE) => {
+                Err(GuestMemoryError::Mmio(gpa))
+            }
+            Some((_, r)) if r.contains(gpa) => Ok(r),
+            _ => Err(GuestMemoryError::NotMapped(gpa)),
+        }
+    }
+
+    /// The length of the host-contiguous chunk at `gpa` of region `r`, at most `len`.
+    fn chunk_len(r: &MapRegion, gpa: GuestPhysAddr, len: usize) -> usize {
+        let end = match r.mapper {
+            Mapper::Lazy(_) => align_down_4k(gpa) + PAGE_SIZE,
+            _ => r.start + r.size,
+        };
+        len.min(end - gpa)
+    }
+
+    /// Translates `gpa` of guest RAM to the host physical address.
+    pub fn translate(&self, gpa: GuestPhysAddr) -> GuestMemoryResult<HostPhysAddr> {
+        let r = self.ram_region(gpa)?;
+        r.target(gpa).ok_or(GuestMemoryError::NotAllocated(gpa))
+    }
+
+    /// Reads guest RAM at `gpa` into `buf`, the range may span multiple regions.
+    /// Pages of lazily allocated regions read as zeros until allocated.
+    pub fn read_gpa(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> GuestMemoryResult {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let cur = gpa + offset;
+            let r = self.ram_region(cur)?;
+            let len = Self::chunk_len(r, cur, buf.len() - offset);
+            let dst = &mut buf[offset..offset + len];
+            match r.target(cur) {
+                Some(hpa) => unsafe {
+                    let src = phys_to_virt(hpa).as_ptr();
+                    core::ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), len);
+                },
+                None => dst.fill(0),
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to guest RAM at `gpa`, the range may span multiple regions.
+    /// Pages of lazily allocated regions are allocated if not yet. Writes to
+    /// read-only ROM regions are discarded, like the writes of the guest.
+    pub fn write_gpa(&mut self, gpa: GuestPhysAddr, data: &[u8]) -> GuestMemoryResult {
+        let mut offset = 0;
+        while offset < data.len() {
+            let cur = gpa + offset;
+            let r = Self::ram_region_mut(&mut self.regions, cur)?;
+            let len = Self::chunk_len(r, cur, data.len() - offset);
+            if r.is_rom() && !r.flags.contains(MappingFlags::WRITE) {
+                offset += len;
+                continue;
+            }
+            let hpa = r
+                .populate(&mut self.npt, &mut self.page_stats, cur)
+                .map_err(|_| GuestMemoryError::NoMemory)?;
+            unsafe {
+                let dst = phys_to_virt(hpa).as_mut_ptr();
+                core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), dst, len);
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Handles a nested page fault at `gpa` by allocating the page if it is in a
+    /// lazily allocated region and the access is allowed. Returns whether the
+    /// guest can be resumed.
+    pub fn handle_page_fault(
+        &mut self,
+        gpa: GuestPhysAddr,
+        access: MappingFlags,
+    ) -> AxResult<bool> {
+        if self.translate(gpa) != Err(GuestMemoryError::NotAllocated(gpa)) {
+            return Ok(false);
+        }
+        let r = Self::ram_region_mut(&mut self.regions, gpa)?;
+        if !r.flags.contains(access) {
+            return Ok(false);
+        }
+        r.populate(&mut self.npt, &mut self.page_stats, gpa)?;
+        Ok(true)
+    }
+
+    /// Whether `gpa` is in a ROM region, whose writes are discarded.
+    pub fn is_rom(&self, gpa: GuestPhysAddr) -> bool {
+        self.ram_region(gpa).is_ok_and(|r| r.is_rom())
+    }
+
+    /// Records that the VM runs on CPU `cpu_id`, whose cached translations are
+    /// flushed after the nested page table is modified.
+    pub fn add_active_cpu(&mut self, cpu_id: usize) {
+        self.active_cpus |= 1 << cpu_id;
+    }
+
+    /// Flushes the cached translations of CPU `cpu_id` by `flush` if requested,
+    /// should be called before every VM entry. The unmapped regions are freed
+    /// after the last CPU flushes.
+    pub fn check_tlb_flush(&mut self, cpu_id: usize, flush: impl FnOnce() -> AxResult) -> AxResult {
+        if self.tlb_flush_pending & (1 << cpu_id) != 0 {
+            flush()?;
+            self.tlb_flush_pending &= !(1 << cpu_id);
+            self.free_stale_regions();
+        }
+        Ok(())
+    }
+
+    /// Requests all CPUs running the VM to flush their cached translations,
+    /// after present entries of the nested page table are changed.
+    fn flush_tlb(&mut self) {
+        self.tlb_flush_pending |= self.active_cpus;
+        self.free_stale_regions();
+    }
+
+    /// Frees the unmapped regions if no CPU may still access them.
+    fn free_stale_regions(&mut self) {
+        if self.tlb_flush_pending == 0 {
+            self.stale_regions.clear();
+        }
+    }
+
+    /// Splits the region and the huge pages at `gpa`, so that the parts on
+    /// either side can be unmapped or protected separately.
+    fn split_at(&mut self, gpa: GuestPhysAddr) -> AxResult {
+        let Some((_, r)) = self.regions.range_mut(..gpa).next_back() else {
+            return Ok(());
+        };
+        if !r.contains(gpa) {
+            return Ok(());
+        }
+        let upper = r.split_off(gpa);
+        self.regions.insert(gpa, upper);
+        self.split_huge_pages(gpa)
+    }
+
+    /// Splits at both ends of `gpa..gpa + size`, returns the start addresses of
+    /// the regions inside.
+    fn split_range(&mut self, gpa: GuestPhysAddr, size: usize) -> AxResult<Vec<GuestPhysAddr>> {
+        if !is_aligned_4k(gpa) || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "GPA range is not 4K aligned");
+        }
+        self.split_at(gpa)?;
+        self.split_at(gpa + size)?;
+        Ok(self
+            .regions
+            .range(gpa..gpa + size)
+            .map(|(&start, _)| start)
+            .collect())
+    }
+
+    /// Unmaps the regions in `gpa..gpa + size`, the regions crossing the
+    /// boundaries are split. Memory allocated for the regions is freed after
+    /// all CPUs running the VM flush their cached translations.
+    pub fn unmap_region(&mut self, gpa: GuestPhysAddr, size: usize) -> AxResult {
+        for start in self.split_range(gpa, size)? {
+            let region = self.regions.remove(&start).unwrap();
+            region.unmap_to(&mut self.npt, &mut self.page_stats)?;
+            self.stale_regions.push(region);
+        }
+        self.flush_tlb();
+        Ok(())
+    }
+
+    /// Changes the access flags of the regions in `gpa..gpa + size`, the regions
+    /// crossing the boundaries are split. The `DEVICE` flag is kept.
+    pub fn protect_region(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> AxResult {
+        for start in self.split_range(gpa, size)? {
+            let region = self.regions.get_mut(&start).unwrap();
+            let flags = (flags - MappingFlags::DEVICE) | (region.flags & MappingFlags::DEVICE);
+            region.protect_to(&mut self.npt, flags)?;
+        }
+        self.flush_tlb();
+        Ok(())
+    }
+
+    /// Replaces the mappings in the range of `region` with it.
+    pub fn remap_region(&mut self, region: MapRegion) -> AxResult {
+        self.unmap_region(region.start, region.size)?;
+        self.map_region(region)
+    }
+
+    /// Splits the huge pages containing `gpa` until `gpa` is at a page boundary.
+    fn split_huge_pages(&mut self, gpa: GuestPhysAddr) -> AxResult {
+        while let Ok((_, flags, page_size)) = self.npt.query(VirtAddr::from(gpa)) {
+            let size = usize::from(page_size);
+            if gpa % size == 0 {
+                break;
+            }
+            let base = gpa - gpa % size;
+            let (hpa, ..) = self.npt.query(VirtAddr::from(base)).unwrap();
+            let sub_page_size = match page_size {
+                PageSize::Size1G => PageSize::Size2M,
+                _ => PageSize::Size4K,
+            };
+            npt_unmap(&mut self.npt, &mut self.page_stats, base)?;
+            for off in (0..size).step_by(sub_page_size.into()) {
+                let (npt, stats) = (&mut self.npt, &mut self.page_stats);
+                npt_map(npt, stats, base + off, hpa + off, sub_page_size, flags)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterates over the mapped regions in ascending order of address.
+    pub fn iter(&self) -> impl Iterator<Item = &MapRegion> {
+        self.regions.values()
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
-            region.unmap_to(&mut self.npt).unwrap();
+            region
+                .unmap_to(&mut self.npt, &mut self.page_stats)
+                .unwrap();
         }
         self.regions.clear();
+        self.flush_tlb();
     }
 }
 
@@ -182,6 +757,7 @@ impl Debug for GuestPhysMemorySet {
     fn fmt(&self, f: &mut Formatter) -> Result {
         f.debug_struct("GuestPhysMemorySet")
             .field("page_table_root", &self.nest_page_table_root())
+            .field("page_stats", &self.page_stats)
             .field("regions", &self.regions)
             .finish()
     }
@@ -11,104 +11,162 @@ extern crate alloc;
 #[macro_use]
 extern crate log;
 
+mod acpi;
+mod boot;
+mod config;
 mod device_emu;
+mod firmware;
 mod gconfig;
 mod gpm;
 mod hal;
+mod mmio;
 mod vmexit;
 
+use alloc::vec::Vec;
+
 use axerrno::{AxError, AxResult};
-use axhal::mem::virt_to_phys;
-use axvm::{AxvmPerCpu, GuestPhysAddr, HostPhysAddr, HostVirtAddr};
+use axvm::{AxvmPerCpu, GuestPhysAddr};
 use page_table_entry::MappingFlags;
+use spin::{Mutex, MutexGuard, Once};
 
+use self::boot::{BootProtocol, BootState, EntryMode};
+use self::config::vm_config;
+use self::device_emu::LifecycleEvent;
 use self::gconfig::*;
-use self::gpm::{GuestMemoryRegion, GuestPhysMemorySet};
+use self::gpm::{GuestMemoryRegion, GuestPhysMemorySet, MapRegion};
 use self::hal::AxvmHalImpl;
 
-#[repr(align(4096))]
-struct AlignedMemory<const LEN: usize>([u8; LEN]);
+static GUEST_PHYS_MEMORY: Once<Mutex<GuestPhysMemorySet>> = Once::new();
+
+fn guest_phys_memory() -> MutexGuard<'static, GuestPhysMemorySet> {
+    GUEST_PHYS_MEMORY
+        .get()
+        .expect("Guest physical memory is not set up")
+        .lock()
+}
 
-static mut GUEST_PHYS_MEMORY: AlignedMemory<GUEST_PHYS_MEMORY_SIZE> =
-    AlignedMemory([0; GUEST_PHYS_MEMORY_SIZE]);
+/// Loads the file into guest memory at `load_gpa`, returns the file size.
+fn load_guest_image_from_file_system(file_name: &str, load_gpa: GuestPhysAddr) -> AxResult<usize> {
+    let image = read_file(file_name)?;
+    guest_phys_memory().write_gpa(load_gpa, &image)?;
+    Ok(image.len())
+}
 
-fn gpa_as_mut_ptr(guest_paddr: GuestPhysAddr) -> *mut u8 {
-    let offset = unsafe { core::ptr::addr_of!(GUEST_PHYS_MEMORY) as *const _ as usize };
-    let host_vaddr = guest_paddr + offset;
-    host_vaddr as *mut u8
+/// Builds the ACPI tables in guest memory, returns a copy of the RSDP.
+fn setup_acpi_tables() -> AxResult<[u8; acpi::RSDP_SIZE]> {
+    let config = vm_config();
+    let platform = acpi::AcpiPlatform::from_config(config);
+    let tables_gpa = config.platform.acpi_tables;
+    let tables = acpi::build_acpi_tables(&platform, tables_gpa)?;
+    guest_phys_memory().write_gpa(tables_gpa, &tables)?;
+    Ok(tables[..acpi::RSDP_SIZE].try_into().unwrap())
 }
 
-fn load_guest_image_from_file_system(file_name: &str, load_gpa: GuestPhysAddr) -> AxResult {
-    use std::io::{BufReader, Read};
-    let file = std::fs::File::open(file_name).map_err(|err| {
-        warn!(
-            "Failed to open {}, err {:?}, please check your disk.img",
-            file_name, err
-        );
+fn read_file(file_name: &str) -> AxResult<Vec<u8>> {
+    std::fs::read(file_name).map_err(|err| {
+        warn!("Failed to read {}, err {:?}", file_name, err);
         AxError::NotFound
-    })?;
-    let buffer = unsafe {
-        core::slice::from_raw_parts_mut(
-            gpa_as_mut_ptr(load_gpa),
-            file.metadata()
-                .map_err(|err| {
-                    warn!(
-                        "Failed to get metadate of file {}, err {:?}",
-                        file_name, err
-                    );
-                    AxError::Io
-                })?
-                .size() as usize,
-        )
-    };
-    let mut file = BufReader::new(file);
-    file.read_exact(buffer).map_err(|err| {
-        warn!("Failed to read from file {}, err {:?}", file_name, err);
-        AxError::Io
-    })?;
-    Ok(())
+    })
 }
 
-fn setup_gpm() -> AxResult<GuestPhysMemorySet> {
-    // copy BIOS and guest images from file system
-    load_guest_image_from_file_system("rvm-bios.bin", BIOS_ENTRY)?;
-    load_guest_image_from_file_system("nimbos.bin", GUEST_ENTRY)?;
+/// Builds the ACPI tables, loads the guest image and boot modules, and builds
+/// the boot information according to the boot protocol in the VM configuration.
+fn setup_guest_boot() -> AxResult<BootState> {
+    let config = &vm_config().boot;
+    let map = boot::memory_map(&guest_phys_memory());
+    let modules: Vec<_> = config
+        .modules
+        .iter()
+        .map(|(file, cmdline)| (file.as_str(), cmdline.as_str()))
+        .collect();
+    match config.protocol {
+        BootProtocol::Multiboot => {
+            setup_acpi_tables()?;
+            let image = read_file(&config.image)?;
+            let (entry, mode, kernel_end) = if boot::is_elf(&image) {
+                // ELF kernels are entered directly at `e_entry`
+                let (entry, kernel_end) = boot::load_elf(&map, &image)?;
+                (entry, EntryMode::FlatProtected, kernel_end)
+            } else {
+                // raw binaries are entered through the BIOS
+                let (bios, bios_addr) = &config.bios;
+                load_guest_image_from_file_system(bios, *bios_addr)?;
+                boot::write_guest(&map, config.load_addr, &image)?;
+                let kernel_end = config.load_addr + image.len();
+                (config.load_addr, EntryMode::Real, kernel_end)
+            };
+            let modules = boot::load_boot_modules(&map, kernel_end, &modules)?;
+            let mbi =
+                boot::setup_multiboot_info(&map, &modules, &config.cmdline, config.info_addr)?;
+            Ok(BootState::multiboot(
+                entry,
+                mode,
+                boot::MULTIBOOT_BOOTLOADER_MAGIC,
+                mbi,
+            ))
+        }
+        BootProtocol::Multiboot2 => {
+            let rsdp = setup_acpi_tables()?;
+            let image = read_file(&config.image)?;
+            let header = boot::Multiboot2Header::parse(&image)?;
+            let (entry, kernel_end) = boot::load_multiboot2_image(&map, &image, &header)?;
+            let modules = boot::load_boot_modules(&map, kernel_end, &modules)?;
+            let mbi = boot::setup_multiboot2_info(
+                &map,
+                &modules,
+                &config.cmdline,
+                &rsdp,
+                config.info_addr,
+            )?;
+            Ok(BootState::multiboot(
+                entry,
+                EntryMode::FlatProtected,
+                boot::MULTIBOOT2_BOOTLOADER_MAGIC,
+                mbi,
+            ))
+        }
+        BootProtocol::Linux => {
+            setup_acpi_tables()?;
+            let image = read_file(&config.image)?;
+            let linux = boot::LinuxImage::parse(&image)?;
+            let initrd = config.initrd.as_deref().map(read_file).transpose()?;
+            boot::load_linux(
+                &map,
+                &linux,
+                initrd.as_deref(),
+                &config.cmdline,
+                vm_config().platform.acpi_tables,
+                config.info_addr,
+            )
+        }
+        BootProtocol::Firmware => {
+            // the firmware builds the ACPI tables and loads the guest itself.
+            firmware::setup(&config.bios.0)?;
+            Ok(BootState::reset())
+        }
+    }
+}
 
+fn setup_gpm() -> AxResult<GuestPhysMemorySet> {
     // create nested page table and add mapping
     let mut gpm = GuestPhysMemorySet::new()?;
-    let guest_memory_regions = [
-        GuestMemoryRegion {
-            // RAM
-            gpa: GUEST_PHYS_MEMORY_BASE,
-            hpa: virt_to_phys(HostVirtAddr::from(
-                gpa_as_mut_ptr(GUEST_PHYS_MEMORY_BASE) as usize
-            )),
-            size: GUEST_PHYS_MEMORY_SIZE,
-            flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE,
-        },
-        GuestMemoryRegion {
-            // IO APIC
-            gpa: 0xfec0_0000,
-            hpa: HostPhysAddr::from(0xfec0_0000),
-            size: 0x1000,
-            flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
-        },
-        GuestMemoryRegion {
-            // HPET
-            gpa: 0xfed0_0000,
-            hpa: HostPhysAddr::from(0xfed0_0000),
-            size: 0x1000,
-            flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
-        },
-        GuestMemoryRegion {
-            // Local APIC
-            gpa: 0xfee0_0000,
-            hpa: HostPhysAddr::from(0xfee0_0000),
-            size: 0x1000,
-            flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
-        },
-    ];
-    for r in guest_memory_regions.into_iter() {
+    let config = vm_config();
+    for r in &config.memory {
+        let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE;
+        let region = if r.lazy {
+            MapRegion::new_lazy(r.gpa, r.size, flags)?
+        } else {
+            MapRegion::new_alloc(r.gpa, r.size, flags)?
+        };
+        gpm.map_region(region)?;
+    }
+    let passthrough_regions = config.passthrough.iter().map(|r| GuestMemoryRegion {
+        gpa: r.gpa,
+        hpa: r.hpa,
+        size: r.size,
+        flags: MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+    });
+    for r in passthrough_regions {
         trace!("{:#x?}", r);
         gpm.map_region(r.into())?;
     }
@@ -120,6 +178,8 @@ fn main() {
     println!("Starting virtualization...");
     info!("Hardware support: {:?}", axvm::has_hardware_support());
 
+    config::init(VM_CONFIG_FILE).expect("Failed to load VM config");
+
     let mut percpu = AxvmPerCpu::<AxvmHalImpl>::new(0);
     percpu
         .hardware_enable()
@@ -127,13 +187,49 @@ fn main() {
 
     let gpm = setup_gpm().expect("Failed to set guest physical memory set");
     debug!("{:#x?}", gpm);
-    let mut vcpu = percpu
-        .create_vcpu(GUEST_ENTRY, gpm.nest_page_table_root())
-        .expect("Failed to create vcpu");
-
-    debug!("{:#x?}", vcpu);
-
-    println!("Running guest...");
+    let npt_root = gpm.nest_page_table_root();
+    GUEST_PHYS_MEMORY.call_once(|| Mutex::new(gpm));
+    guest_phys_memory().add_active_cpu(axhal::cpu::this_cpu_id());
+
+    loop {
+        let boot = setup_guest_boot().expect("Failed to set up guest boot");
+        let mut vcpu = percpu
+            .create_vcpu(boot.entry, npt_root)
+            .expect("Failed to create vcpu");
+        match boot.mode {
+            EntryMode::Reset => vcpu.set_reset_state(),
+            EntryMode::Real => Ok(()),
+            EntryMode::FlatProtected => vcpu.set_flat_protected_mode(boot.entry),
+            EntryMode::Long {
+                page_table,
+                gdt,
+                code_sel,
+                data_sel,
+            } => vcpu.set_long_mode(boot.entry, page_table, gdt, code_sel, data_sel),
+        }
+        .expect("Failed to set vcpu entry mode");
+        *vcpu.regs_mut() = boot.regs;
+        if vm_config().apic_virt {
+            device_emu::VirtLocalApic::enable_virtualization(&mut vcpu)
+                .expect("Failed to enable APIC virtualization");
+        }
+
+        debug!("{:#x?}", vcpu);
+
+        println!("Running guest...");
+
+        vcpu.run();
+
+        match device_emu::take_lifecycle_event() {
+            Some(LifecycleEvent::Reset) => {
+                println!("Resetting guest...");
+                device_emu::all_virt_devices().reset();
+                device_emu::VirtLocalApic::reset_all();
+            }
+            _ => break,
+        }
+    }
 
-    vcpu.run();
+    device_emu::all_virt_devices().shutdown();
+    println!("Guest shut down");
 }
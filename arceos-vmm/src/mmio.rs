@@ -0,0 +1,479 @@
+//! Emulation of guest instructions that access emulated memory-mapped I/O regions.
+//!
+//! The faulting instruction is fetched from guest memory and decoded, only the
+//! `MOV` family (`MOV`, `MOVZX` and `MOVSX`) is supported, which covers the way
+//! almost all drivers access device registers.
+
+use axerrno::{ax_err, ax_err_type, AxResult};
+use axvm::{AxvmVcpu, GuestPhysAddr, GuestVirtAddr};
+use memory_addr::PAGE_SIZE_4K as PAGE_SIZE;
+
+use crate::hal::AxvmHalImpl;
+
+type Vcpu = AxvmVcpu<AxvmHalImpl>;
+
+/// Maximum length of an x86 instruction.
+const MAX_INSTR_LEN: usize = 15;
+
+/// The code size of the guest, determined by `CR0.PE`, `IA32_EFER.LMA`, `CS.L` and `CS.D`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CpuMode {
+    Bits16,
+    Bits32,
+    Bits64,
+}
+
+impl CpuMode {
+    fn of(vcpu: &Vcpu) -> Self {
+        const CR0_PE: usize = 1 << 0;
+        const EFER_LMA: u64 = 1 << 10;
+        const CS_AR_L: u32 = 1 << 13;
+        const CS_AR_DB: u32 = 1 << 14;
+        let cs_ar = vcpu.cs_access_rights();
+        if vcpu.cr0() & CR0_PE == 0 {
+            Self::Bits16
+        } else if vcpu.efer() & EFER_LMA != 0 && cs_ar & CS_AR_L != 0 {
+            Self::Bits64
+        } else if cs_ar & CS_AR_DB != 0 {
+            Self::Bits32
+        } else {
+            Self::Bits16
+        }
+    }
+}
+
+/// A general-purpose register operand.
+#[derive(Debug, Clone, Copy)]
+struct RegOperand {
+    /// Register index in the instruction encoding order.
+    index: u8,
+    /// Bits 8..16 of the register (`AH`, `CH`, `DH` or `BH`).
+    high_byte: bool,
+}
+
+impl RegOperand {
+    fn new(index: u8, size: u8, has_rex: bool) -> Self {
+        // Without a REX prefix, the 8-bit registers 4..8 are `AH`, `CH`, `DH` and `BH`.
+        if size == 1 && !has_rex && (4..8).contains(&index) {
+            Self {
+                index: index - 4,
+                high_byte: true,
+            }
+        } else {
+            Self {
+                index,
+                high_byte: false,
+            }
+        }
+    }
+
+    fn read(&self, vcpu: &Vcpu) -> u64 {
+        let value = if self.index == 4 {
+            vcpu.stack_pointer() as u64
+        } else {
+            vcpu.regs().get_reg_of_index(self.index)
+        };
+        if self.high_byte {
+            (value >> 8) & 0xff
+        } else {
+            value
+        }
+    }
+
+    fn write(&self, vcpu: &mut Vcpu, size: u8, value: u64) {
+        let old = if self.index == 4 {
+            vcpu.stack_pointer() as u64
+        } else {
+            vcpu.regs().get_reg_of_index(self.index)
+        };
+        // SDM Vol. 1, Section 3.4.1.1: 32-bit operands are zero-extended to 64 bits, 8-bit
+        // and 16-bit operands keep the upper bits of the destination register unchanged.
+        let new = if self.high_byte {
+            (old & !0xff00) | ((value & 0xff) << 8)
+        } else {
+            match size {
+                1 => (old & !0xff) | (value & 0xff),
+                2 => (old & !0xffff) | (value & 0xffff),
+                4 => value & 0xffff_ffff,
+                _ => value,
+            }
+        };
+        if self.index == 4 {
+            vcpu.set_stack_pointer(new as usize);
+        } else {
+            vcpu.regs_mut().set_reg_of_index(self.index, new);
+        }
+    }
+}
+
+/// The memory operation of an MMIO instruction.
+#[derive(Debug)]
+enum MmioOp {
+    /// Load from memory to a register, zero- or sign-extended to `dst_size` bytes.
+    Load {
+        dst: RegOperand,
+        dst_size: u8,
+        sign_extend: bool,
+    },
+    /// Store a register to memory.
+    StoreReg(RegOperand),
+    /// Store an immediate to memory.
+    StoreImm(u64),
+}
+
+/// A decoded instruction that accesses an MMIO region.
+#[derive(Debug)]
+pub struct MmioInstr {
+    /// Length of the instruction in bytes.
+    pub len: u8,
+    /// Size of the memory access in bytes.
+    pub access_size: u8,
+    op: MmioOp,
+}
+
+/// Cursor over the instruction bytes.
+struct InstrReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> InstrReader<'a> {
+    fn peek_u8(&self) -> AxResult<u8> {
+        self.bytes
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| ax_err_type!(InvalidData, "truncated instruction"))
+    }
+
+    fn read_u8(&mut self) -> AxResult<u8> {
+        let b = self.peek_u8()?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    /// Read a little-endian immediate of `size` bytes.
+    fn read_imm(&mut self, size: u8) -> AxResult<u64> {
+        let mut value = 0;
+        for i in 0..size {
+            value |= (self.read_u8()? as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+
+    /// Skip the SIB byte and the displacement after a ModRM byte with memory operand.
+    fn skip_modrm_memory(&mut self, md: u8, rm: u8, addr_size: u8) -> AxResult {
+        let disp_len = if addr_size == 2 {
+            match md {
+                0 if rm == 6 => 2,
+                0 => 0,
+                1 => 1,
+                _ => 2,
+            }
+        } else {
+            let mut sib_base_disp = 0;
+            if rm == 4 {
+                let sib = self.read_u8()?;
+                if md == 0 && sib & 0b111 == 5 {
+                    sib_base_disp = 4; // no base register, disp32 only
+                }
+            }
+            match md {
+                0 if rm == 5 => 4, // disp32, or RIP-relative in 64-bit mode
+                0 => sib_base_disp,
+                1 => 1,
+                _ => 4,
+            }
+        };
+        self.pos += disp_len;
+        if self.pos > self.bytes.len() {
+            return ax_err!(InvalidData, "truncated instruction");
+        }
+        Ok(())
+    }
+}
+
+impl MmioInstr {
+    /// Fetch and decode the instruction at the current guest `RIP`.
+    pub fn decode(vcpu: &Vcpu) -> AxResult<Self> {
+        let mode = CpuMode::of(vcpu);
+        let rip = if mode == CpuMode::Bits64 {
+            vcpu.rip()
+        } else {
+            vcpu.cs_base() + vcpu.rip()
+        };
+        let mut buf = [0; MAX_INSTR_LEN];
+        let len = fetch_instr(vcpu, rip, &mut buf)?;
+        Self::decode_bytes(&buf[..len], mode).map_err(|err| {
+            warn!(
+                "Failed to decode MMIO instruction @ {:#x}: {:02x?}",
+                rip,
+                &buf[..len]
+            );
+            err
+        })
+    }
+
+    fn decode_bytes(bytes: &[u8], mode: CpuMode) -> AxResult<Self> {
+        let mut reader = InstrReader { bytes, pos: 0 };
+
+        // Legacy prefixes.
+        let mut opsize_override = false;
+        let mut addrsize_override = false;
+        loop {
+            match reader.peek_u8()? {
+                0x66 => opsize_override = true,
+                0x67 => addrsize_override = true,
+                0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 => {} // segment overrides, LOCK
+                _ => break,
+            }
+            reader.pos += 1;
+        }
+        // REX prefix, only in 64-bit mode.
+        let mut rex = 0;
+        if mode == CpuMode::Bits64 && reader.peek_u8()? & 0xf0 == 0x40 {
+            rex = reader.read_u8()?;
+        }
+        let has_rex = rex != 0;
+        let rex_w = rex & 0b1000 != 0;
+        let rex_r = rex & 0b0100 != 0;
+
+        let opsize = match mode {
+            CpuMode::Bits64 if rex_w => 8,
+            CpuMode::Bits16 if opsize_override => 4,
+            CpuMode::Bits16 => 2,
+            _ if opsize_override => 2,
+            _ => 4,
+        };
+        let addr_size = match mode {
+            CpuMode::Bits64 if addrsize_override => 4,
+            CpuMode::Bits64 => 8,
+            CpuMode::Bits32 if addrsize_override => 2,
+            CpuMode::Bits32 => 4,
+            CpuMode::Bits16 if addrsize_override => 4,
+            CpuMode::Bits16 => 2,
+        };
+
+        let mut opcode = reader.read_u8()?;
+        let two_byte = opcode == 0x0f;
+        if two_byte {
+            opcode = reader.read_u8()?;
+        }
+
+        let modrm = reader.read_u8()?;
+        let md = modrm >> 6;
+        let reg = ((modrm >> 3) & 0b111) | if rex_r { 0b1000 } else { 0 };
+        let rm = modrm & 0b111;
+        if md == 0b11 {
+            return ax_err!(InvalidData, "MMIO instruction without memory operand");
+        }
+        reader.skip_modrm_memory(md, rm, addr_size)?;
+
+        let (access_size, op) = match (two_byte, opcode) {
+            // MOV r/m8, r8
+            (false, 0x88) => (1, MmioOp::StoreReg(RegOperand::new(reg, 1, has_rex))),
+            // MOV r/m16/32/64, r16/32/64
+            (false, 0x89) => (
+                opsize,
+                MmioOp::StoreReg(RegOperand::new(reg, opsize, has_rex)),
+            ),
+            // MOV r8, r/m8
+            (false, 0x8a) => (
+                1,
+                MmioOp::Load {
+                    dst: RegOperand::new(reg, 1, has_rex),
+                    dst_size: 1,
+                    sign_extend: false,
+                },
+            ),
+            // MOV r16/32/64, r/m16/32/64
+            (false, 0x8b) => (
+                opsize,
+                MmioOp::Load {
+                    dst: RegOperand::new(reg, opsize, has_rex),
+                    dst_size: opsize,
+                    sign_extend: false,
+                },
+            ),
+            // MOV r/m8, imm8
+            (false, 0xc6) if reg & 0b111 == 0 => (1, MmioOp::StoreImm(reader.read_imm(1)?)),
+            // MOV r/m16/32/64, imm16/32 (sign-extended to 64 bits with REX.W)
+            (false, 0xc7) if reg & 0b111 == 0 => {
+                let imm = if opsize == 8 {
+                    reader.read_imm(4)? as u32 as i32 as i64 as u64
+                } else {
+                    reader.read_imm(opsize)?
+                };
+                (opsize, MmioOp::StoreImm(imm))
+            }
+            // MOVZX/MOVSX r16/32/64, r/m8 or r/m16
+            (true, 0xb6 | 0xb7 | 0xbe | 0xbf) => (
+                if opcode & 1 == 0 { 1 } else { 2 },
+                MmioOp::Load {
+                    dst: RegOperand::new(reg, opsize, has_rex),
+                    dst_size: opsize,
+                    sign_extend: opcode >= 0xbe,
+                },
+            ),
+            _ => return ax_err!(Unsupported, "unsupported MMIO instruction"),
+        };
+
+        Ok(Self {
+            len: reader.pos as u8,
+            access_size,
+            op,
+        })
+    }
+}
+
+/// Emulate the MMIO instruction at the current guest `RIP` with the given
+/// device read/write handlers, then skip the instruction.
+///
+/// `read` and `write` receive the access size in bytes.
+pub fn emulate_mmio<R, W>(vcpu: &mut Vcpu, read: R, write: W) -> AxResult
+where
+    R: FnOnce(&mut Vcpu, u8) -> AxResult<u64>,
+    W: FnOnce(&mut Vcpu, u8, u64) -> AxResult,
+{
+    let instr = MmioInstr::decode(vcpu)?;
+    trace!("MMIO instruction: {:x?}", instr);
+    let mask = size_mask(instr.access_size);
+    match instr.op {
+        MmioOp::Load {
+            dst,
+            dst_size,
+            sign_extend,
+        } => {
+            let mut value = read(vcpu, instr.access_size)? & mask;
+            if sign_extend {
+                let shift = 64 - instr.access_size as u32 * 8;
+                value = (((value << shift) as i64) >> shift) as u64;
+            }
+            dst.write(vcpu, dst_size, value);
+        }
+        MmioOp::StoreReg(src) => {
+            let value = src.read(vcpu) & mask;
+            write(vcpu, instr.access_size, value)?;
+        }
+        MmioOp::StoreImm(imm) => write(vcpu, instr.access_size, imm & mask)?,
+    }
+    vcpu.advance_rip(instr.len)
+}
+
+const fn size_mask(size: u8) -> u64 {
+    if size >= 8 {
+        u64::MAX
+    } else {
+        (1 << (size * 8)) - 1
+    }
+}
+
+/// Fetch at most [`MAX_INSTR_LEN`] instruction bytes from the guest linear
+/// address `gla`, returns the number of bytes fetched.
+fn fetch_instr(vcpu: &Vcpu, gla: GuestVirtAddr, buf: &mut [u8]) -> AxResult<usize> {
+    let mut fetched = 0;
+    while fetched < buf.len() {
+        let addr = gla + fetched;
+        let gpa = match gla_to_gpa(vcpu, addr) {
+            Ok(gpa) => gpa,
+            // the instruction may end before the next unmapped page
+            Err(_) if fetched > 0 => break,
+            Err(err) => return Err(err),
+        };
+        let chunk = (buf.len() - fetched).min(PAGE_SIZE - addr % PAGE_SIZE);
+        if read_guest_phys(gpa, &mut buf[fetched..fetched + chunk]).is_err() {
+            if fetched > 0 {
+                break;
+            }
+            return ax_err!(BadAddress, "instruction is not in guest RAM");
+        }
+        fetched += chunk;
+    }
+    Ok(fetched)
+}
+
+/// Translate a guest linear address to a guest physical address by walking
+/// the guest page tables. (SDM Vol. 3A, Chapter 4)
+pub fn gla_to_gpa(vcpu: &Vcpu, gla: GuestVirtAddr) -> AxResult<GuestPhysAddr> {
+    const CR0_PG: usize = 1 << 31;
+    const CR4_PSE: usize = 1 << 4;
+    const CR4_PAE: usize = 1 << 5;
+    const CR4_LA57: usize = 1 << 12;
+    const EFER_LMA: u64 = 1 << 10;
+
+    let cr0 = vcpu.cr0();
+    if cr0 & CR0_PG == 0 {
+        return Ok(gla);
+    }
+    let cr3 = vcpu.cr3();
+    let cr4 = vcpu.cr4();
+    if vcpu.efer() & EFER_LMA != 0 {
+        // 4-level or 5-level paging
+        let levels = if cr4 & CR4_LA57 != 0 { 5 } else { 4 };
+        walk_page_table_64(cr3 & PTE_PHYS_ADDR_MASK as usize, gla, levels)
+    } else if cr4 & CR4_PAE != 0 {
+        // PAE paging
+        let pdpte = read_guest_u64((cr3 & 0xffff_ffe0) + ((gla >> 30) & 0b11) * 8)?;
+        if pdpte & PTE_PRESENT == 0 {
+            return ax_err!(BadAddress, "guest page not mapped");
+        }
+        walk_page_table_64((pdpte & PTE_PHYS_ADDR_MASK) as usize, gla, 2)
+    } else {
+        // 32-bit paging
+        let pde = read_guest_u32((cr3 & 0xffff_f000) + ((gla >> 22) & 0x3ff) * 4)? as u64;
+        if pde & PTE_PRESENT == 0 {
+            return ax_err!(BadAddress, "guest page not mapped");
+        }
+        if pde & PTE_HUGE != 0 && cr4 & CR4_PSE != 0 {
+            return Ok((pde as usize & 0xffc0_0000) | (gla & 0x3f_ffff));
+        }
+        let pte = read_guest_u32((pde as usize & 0xffff_f000) + ((gla >> 12) & 0x3ff) * 4)?;
+        if pte as u64 & PTE_PRESENT == 0 {
+            return ax_err!(BadAddress, "guest page not mapped");
+        }
+        Ok((pte as usize & 0xffff_f000) | (gla & 0xfff))
+    }
+}
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_HUGE: u64 = 1 << 7;
+const PTE_PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // bits 12..52
+
+/// Walk `levels` levels of 64-bit paging structures with 512 entries per table.
+fn walk_page_table_64(
+    mut table: GuestPhysAddr,
+    gla: GuestVirtAddr,
+    levels: usize,
+) -> AxResult<GuestPhysAddr> {
+    for level in (0..levels).rev() {
+        let shift = 12 + 9 * level;
+        let entry = read_guest_u64(table + ((gla >> shift) & 0x1ff) * 8)?;
+        if entry & PTE_PRESENT == 0 {
+            return ax_err!(BadAddress, "guest page not mapped");
+        }
+        // PS bit is only valid in PDE (2M) and PDPTE (1G).
+        if level == 0 || (level <= 2 && entry & PTE_HUGE != 0) {
+            let page_offset_mask = (1 << shift) - 1;
+            let paddr = (entry & PTE_PHYS_ADDR_MASK) as usize & !page_offset_mask;
+            return Ok(paddr | (gla & page_offset_mask));
+        }
+        table = (entry & PTE_PHYS_ADDR_MASK) as usize;
+    }
+    unreachable!()
+}
+
+/// Read guest RAM at `gpa` into `buf`.
+fn read_guest_phys(gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+    Ok(crate::guest_phys_memory().read_gpa(gpa, buf)?)
+}
+
+fn read_guest_u32(gpa: GuestPhysAddr) -> AxResult<u32> {
+    let mut buf = [0; 4];
+    read_guest_phys(gpa, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_guest_u64(gpa: GuestPhysAddr) -> AxResult<u64> {
+    let mut buf = [0; 8];
+    read_guest_phys(gpa, &mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
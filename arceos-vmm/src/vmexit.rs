@@ -1,8 +1,13 @@
-use super::device_emu::{self, VirtLocalApic};
+use super::config::vm_config;
+use super::device_emu::{self, ApicMode, PortIoDevice, VirtLocalApic};
 use super::hal::AxvmHalImpl;
+use super::mmio;
+use alloc::sync::Arc;
+
 use axerrno::{AxError, AxResult};
-use axvm::arch::{VmxExitInfo, VmxExitReason};
+use axvm::arch::{VmxExitInfo, VmxExitReason, VmxIoExitInfo};
 use axvm::AxvmVcpu;
+use page_table_entry::MappingFlags;
 
 type Vcpu = AxvmVcpu<AxvmHalImpl>;
 
@@ -28,15 +33,22 @@ fn handle_cpuid(vcpu: &mut Vcpu) -> AxResult {
     const VENDOR_STR: &[u8; 12] = b"ARCEOSARCEOS";
     let vendor_regs = unsafe { &*(VENDOR_STR.as_ptr() as *const [u32; 3]) };
 
+    let apic_mode = VirtLocalApic::apic_mode(vcpu);
     let regs = vcpu.regs_mut();
     let function = regs.rax as u32;
-    let res = match function {
+    let mut res = match function {
         LEAF_FEATURE_INFO => {
             const FEATURE_VMX: u32 = 1 << 5;
+            const FEATURE_X2APIC: u32 = 1 << 21;
+            const FEATURE_TSC_DEADLINE: u32 = 1 << 24;
             const FEATURE_HYPERVISOR: u32 = 1 << 31;
+            const FEATURE_APIC: u32 = 1 << 9;
             let mut res = cpuid!(regs.rax, regs.rcx);
             res.ecx &= !FEATURE_VMX;
-            res.ecx |= FEATURE_HYPERVISOR;
+            res.ecx |= FEATURE_X2APIC | FEATURE_TSC_DEADLINE | FEATURE_HYPERVISOR;
+            if apic_mode == ApicMode::Disabled {
+                res.edx &= !FEATURE_APIC; // SDM Vol. 3A, Section 10.4.3
+            }
             res
         }
         LEAF_HYPERVISOR_INFO => CpuIdResult {
@@ -53,6 +65,13 @@ fn handle_cpuid(vcpu: &mut Vcpu) -> AxResult {
         },
         _ => cpuid!(regs.rax, regs.rcx),
     };
+    if let Some(o) = vm_config().cpuid_override(function, regs.rcx as u32) {
+        let [eax, ebx, ecx, edx] = o.regs;
+        res.eax = eax.unwrap_or(res.eax);
+        res.ebx = ebx.unwrap_or(res.ebx);
+        res.ecx = ecx.unwrap_or(res.ecx);
+        res.edx = edx.unwrap_or(res.edx);
+    }
 
     debug!(
         "VM exit: CPUID({:#x}, {:#x}): {:?}",
@@ -84,48 +103,126 @@ fn handle_io_instruction(vcpu: &mut Vcpu, exit_info: &VmxExitInfo) -> AxResult {
         exit_info.guest_rip,
         io_info,
     );
-    if io_info.is_string {
-        error!("INS/OUTS instructions are not supported!");
-        return Err(AxError::Unsupported);
+    let dev = device_emu::all_virt_devices().find_port_io_device(io_info.port);
+    if dev.is_none() {
+        // unassigned ports are open bus, as probed by firmware.
+        warn!(
+            "Unassigned I/O port {:#x} access @ {:#x}: {:#x?}",
+            io_info.port, exit_info.guest_rip, io_info
+        );
     }
-    if io_info.is_repeat {
-        error!("REP prefixed I/O instructions are not supported!");
-        return Err(AxError::Unsupported);
+
+    if io_info.is_string {
+        handle_string_io(vcpu, &io_info, dev)?;
+    } else if io_info.is_in {
+        let value = port_read(dev, io_info.port, io_info.access_size)?;
+        let rax = &mut vcpu.regs_mut().rax;
+        // SDM Vol. 1, Section 3.4.1.1:
+        // * 32-bit operands generate a 32-bit result, zero-extended to a 64-bit result in the
+        //   destination general-purpose register.
+        // * 8-bit and 16-bit operands generate an 8-bit or 16-bit result. The upper 56 bits or
+        //   48 bits (respectively) of the destination general-purpose register are not modified
+        //   by the operation.
+        match io_info.access_size {
+            1 => *rax = (*rax & !0xff) | (value & 0xff) as u64,
+            2 => *rax = (*rax & !0xffff) | (value & 0xffff) as u64,
+            4 => *rax = value as u64,
+            _ => unreachable!(),
+        }
+    } else {
+        let rax = vcpu.regs().rax;
+        let value = match io_info.access_size {
+            1 => rax & 0xff,
+            2 => rax & 0xffff,
+            4 => rax,
+            _ => unreachable!(),
+        } as u32;
+        port_write(dev, io_info.port, io_info.access_size, value)?;
     }
+    vcpu.advance_rip(exit_info.exit_instruction_length as _)?;
+    Ok(())
+}
 
-    if let Some(dev) = device_emu::all_virt_devices().find_port_io_device(io_info.port) {
+fn port_read(dev: Option<&Arc<dyn PortIoDevice>>, port: u16, access_size: u8) -> AxResult<u32> {
+    dev.map_or(Ok(u32::MAX), |dev| dev.read(port, access_size))
+}
+
+fn port_write(
+    dev: Option<&Arc<dyn PortIoDevice>>,
+    port: u16,
+    access_size: u8,
+    value: u32,
+) -> AxResult {
+    dev.map_or(Ok(()), |dev| dev.write(port, access_size, value))
+}
+
+/// Emulates INS/OUTS, repeated `RCX` times if REP prefixed. The index register
+/// moves backward if `RFLAGS.DF` is set. (SDM Vol. 2B, INS/INSB/INSW/INSD)
+fn handle_string_io(
+    vcpu: &mut Vcpu,
+    io_info: &VmxIoExitInfo,
+    dev: Option<&Arc<dyn PortIoDevice>>,
+) -> AxResult {
+    const RFLAGS_DF: usize = 1 << 10;
+    let (gla, address_size) = vcpu.string_io_operand()?;
+    let addr_mask = match address_size {
+        2 => 0xffff,
+        4 => 0xffff_ffff,
+        _ => u64::MAX,
+    };
+    let count = if io_info.is_repeat {
+        vcpu.regs().rcx & addr_mask
+    } else {
+        1
+    };
+    let size = io_info.access_size as usize;
+    let backward = vcpu.rflags() & RFLAGS_DF != 0;
+    for i in 0..count as usize {
+        let gla = if backward {
+            gla.wrapping_sub(i * size)
+        } else {
+            gla.wrapping_add(i * size)
+        };
+        let gpa = mmio::gla_to_gpa(vcpu, gla)?;
         if io_info.is_in {
-            let value = dev.read(io_info.port, io_info.access_size)?;
-            let rax = &mut vcpu.regs_mut().rax;
-            // SDM Vol. 1, Section 3.4.1.1:
-            // * 32-bit operands generate a 32-bit result, zero-extended to a 64-bit result in the
-            //   destination general-purpose register.
-            // * 8-bit and 16-bit operands generate an 8-bit or 16-bit result. The upper 56 bits or
-            //   48 bits (respectively) of the destination general-purpose register are not modified
-            //   by the operation.
-            match io_info.access_size {
-                1 => *rax = (*rax & !0xff) | (value & 0xff) as u64,
-                2 => *rax = (*rax & !0xffff) | (value & 0xffff) as u64,
-                4 => *rax = value as u64,
-                _ => unreachable!(),
-            }
+            let value = port_read(dev, io_info.port, io_info.access_size)?;
+            crate::guest_phys_memory().write_gpa(gpa, &value.to_le_bytes()[..size])?;
         } else {
-            let rax = vcpu.regs().rax;
-            let value = match io_info.access_size {
-                1 => rax & 0xff,
-                2 => rax & 0xffff,
-                4 => rax,
-                _ => unreachable!(),
-            } as u32;
-            dev.write(io_info.port, io_info.access_size, value)?;
+            let mut buf = [0; 4];
+            crate::guest_phys_memory().read_gpa(gpa, &mut buf[..size])?;
+            port_write(
+                dev,
+                io_info.port,
+                io_info.access_size,
+                u32::from_le_bytes(buf),
+            )?;
         }
+    }
+
+    // 16-bit registers keep the upper bits, 32-bit results are zero-extended.
+    let update = |reg: &mut u64, value: u64| {
+        *reg = if address_size == 2 {
+            (*reg & !0xffff) | (value & 0xffff)
+        } else {
+            value & addr_mask
+        };
+    };
+    let delta = count * size as u64;
+    let regs = vcpu.regs_mut();
+    let index = if io_info.is_in {
+        &mut regs.rdi
     } else {
-        panic!(
-            "Unsupported I/O port {:#x} access: {:#x?}",
-            io_info.port, io_info
-        )
+        &mut regs.rsi
+    };
+    let value = if backward {
+        index.wrapping_sub(delta)
+    } else {
+        index.wrapping_add(delta)
+    };
+    update(index, value);
+    if io_info.is_repeat {
+        update(&mut regs.rcx, 0);
     }
-    vcpu.advance_rip(exit_info.exit_instruction_length as _)?;
     Ok(())
 }
 
@@ -134,21 +231,26 @@ fn handle_msr_read(vcpu: &mut Vcpu) -> AxResult {
 
     use x86::msr::*;
     let res = if msr == IA32_APIC_BASE {
-        let mut apic_base = unsafe { rdmsr(IA32_APIC_BASE) };
-        apic_base |= 1 << 11 | 1 << 10; // enable xAPIC and x2APIC
-        Ok(apic_base)
+        Ok(VirtLocalApic::apic_base(vcpu))
     } else if VirtLocalApic::msr_range().contains(&msr) {
         VirtLocalApic::rdmsr(vcpu, msr)
+    } else if msr == IA32_TSC_DEADLINE {
+        Ok(vcpu.apic_timer_mut().tsc_deadline())
     } else {
         Err(AxError::Unsupported)
     };
 
-    if let Ok(value) = res {
-        debug!("VM exit: RDMSR({:#x}) -> {:#x}", msr, value);
-        vcpu.regs_mut().rax = value & 0xffff_ffff;
-        vcpu.regs_mut().rdx = value >> 32;
-    } else {
-        panic!("Failed to handle RDMSR({:#x}): {:?}", msr, res);
+    match res {
+        Ok(value) => {
+            debug!("VM exit: RDMSR({:#x}) -> {:#x}", msr, value);
+            vcpu.regs_mut().rax = value & 0xffff_ffff;
+            vcpu.regs_mut().rdx = value >> 32;
+        }
+        Err(AxError::InvalidInput) => {
+            warn!("Invalid RDMSR({:#x}), inject #GP", msr);
+            return inject_gp(vcpu);
+        }
+        Err(_) => panic!("Failed to handle RDMSR({:#x}): {:?}", msr, res),
     }
     vcpu.advance_rip(VM_EXIT_INSTR_LEN_RDMSR)?;
     Ok(())
@@ -161,25 +263,67 @@ fn handle_msr_write(vcpu: &mut Vcpu) -> AxResult {
 
     use x86::msr::*;
     let res = if msr == IA32_APIC_BASE {
-        Ok(()) // ignore
+        VirtLocalApic::set_apic_base(vcpu, value)
     } else if VirtLocalApic::msr_range().contains(&msr) {
         VirtLocalApic::wrmsr(vcpu, msr, value)
+    } else if msr == IA32_TSC_DEADLINE {
+        vcpu.apic_timer_mut().set_tsc_deadline(value)
+    } else if msr == IA32_TIME_STAMP_COUNTER {
+        vcpu.set_guest_tsc(value)
     } else {
         Err(AxError::Unsupported)
     };
 
-    if res.is_err() {
-        panic!(
+    match res {
+        Ok(()) => {}
+        Err(AxError::InvalidInput) => {
+            warn!("Invalid WRMSR({:#x}) <- {:#x}, inject #GP", msr, value);
+            return inject_gp(vcpu);
+        }
+        Err(_) => panic!(
             "Failed to handle WRMSR({:#x}) <- {:#x}: {:?}",
             msr, value, res
-        );
+        ),
     }
     vcpu.advance_rip(VM_EXIT_INSTR_LEN_WRMSR)?;
     Ok(())
 }
 
-fn handle_ept_violation(vcpu: &Vcpu, guest_rip: usize) -> AxResult {
+/// Inject a general-protection exception (#GP(0)) without advancing `RIP`,
+/// as the hardware does for invalid MSR accesses.
+fn inject_gp(vcpu: &mut Vcpu) -> AxResult {
+    vcpu.inject_event(x86::irq::GENERAL_PROTECTION_FAULT_VECTOR, Some(0));
+    Ok(())
+}
+
+fn handle_ept_violation(vcpu: &mut Vcpu, guest_rip: usize) -> AxResult {
     let fault_info = vcpu.nested_page_fault_info()?;
+    let gpa = fault_info.fault_guest_paddr;
+    if VirtLocalApic::mmio_range(vcpu).contains(&gpa) {
+        return mmio::emulate_mmio(
+            vcpu,
+            |vcpu, size| VirtLocalApic::mmio_read(vcpu, gpa, size),
+            |vcpu, size, value| VirtLocalApic::mmio_write(vcpu, gpa, size, value),
+        );
+    }
+    if crate::guest_phys_memory().handle_page_fault(gpa, fault_info.access_flags)? {
+        return Ok(());
+    }
+    if fault_info.access_flags.contains(MappingFlags::WRITE)
+        && crate::guest_phys_memory().is_rom(gpa)
+    {
+        // writes to ROM are discarded, but the instruction must be skipped.
+        trace!("Discarding ROM write @ {:#x}", gpa);
+        return mmio::emulate_mmio(
+            vcpu,
+            |_, size| {
+                let mut buf = [0; 8];
+                crate::guest_phys_memory().read_gpa(gpa, &mut buf[..size as usize])?;
+                Ok(u64::from_le_bytes(buf))
+            },
+            |_, _, _| Ok(()),
+        );
+    }
     panic!(
         "VM exit: EPT violation @ {:#x}, fault_paddr={:#x}, access_flags=({:?})",
         guest_rip, fault_info.fault_guest_paddr, fault_info.access_flags
@@ -193,6 +337,7 @@ pub fn vmexit_handler(vcpu: &mut Vcpu) -> AxResult {
     if exit_info.entry_failure {
         panic!("VM entry failed: {:#x?}", exit_info);
     }
+    VirtLocalApic::sync_from_virt_apic(vcpu);
 
     let res = match exit_info.exit_reason {
         VmxExitReason::EXTERNAL_INTERRUPT => handle_external_interrupt(vcpu),
@@ -203,6 +348,8 @@ pub fn vmexit_handler(vcpu: &mut Vcpu) -> AxResult {
         VmxExitReason::MSR_READ => handle_msr_read(vcpu),
         VmxExitReason::MSR_WRITE => handle_msr_write(vcpu),
         VmxExitReason::EPT_VIOLATION => handle_ept_violation(vcpu, exit_info.guest_rip),
+        // the local APIC states have been synced from the virtual-APIC page.
+        VmxExitReason::TPR_BELOW_THRESHOLD | VmxExitReason::VIRTUALIZED_EOI => Ok(()),
         _ => panic!(
             "Unhandled VM-Exit reason {:?}:\n{:#x?}",
             exit_info.exit_reason, vcpu
@@ -216,5 +363,14 @@ pub fn vmexit_handler(vcpu: &mut Vcpu) -> AxResult {
         );
     }
 
-    Ok(())
+    device_emu::all_virt_devices().poll();
+    // the nested page table may be changed since the last VM entry.
+    crate::guest_phys_memory()
+        .check_tlb_flush(axhal::cpu::this_cpu_id(), || vcpu.flush_nested_tlb())?;
+    if device_emu::has_lifecycle_event() {
+        // return to the VMM to shut down or reset the VM.
+        vcpu.request_stop();
+        return Ok(());
+    }
+    VirtLocalApic::check_interrupts(vcpu)
 }
@@ -1,6 +1,7 @@
 use bit_field::BitField;
 use core::marker::PhantomData;
 
+use super::tsc;
 use crate::AxvmHal;
 use axerrno::{ax_err, AxResult};
 
@@ -27,6 +28,8 @@ pub struct ApicTimer<H: AxvmHal> {
     initial_count: u32,
     last_start_ns: u64,
     deadline_ns: u64,
+    tsc_deadline: u64,
+    tsc_offset: u64,
     _phantom: PhantomData<H>,
 }
 
@@ -38,6 +41,8 @@ impl<H: AxvmHal> ApicTimer<H> {
             initial_count: 0,
             last_start_ns: 0,
             deadline_ns: 0,
+            tsc_deadline: 0,
+            tsc_offset: 0,
             _phantom: PhantomData,
         }
     }
@@ -51,6 +56,7 @@ impl<H: AxvmHal> ApicTimer<H> {
                 self.deadline_ns += self.interval_ns();
             } else {
                 self.deadline_ns = 0;
+                self.tsc_deadline = 0;
             }
             !self.is_masked()
         } else {
@@ -69,6 +75,12 @@ impl<H: AxvmHal> ApicTimer<H> {
         timer_mode == TimerMode::Periodic as _
     }
 
+    /// Whether the timer mode is TSC-deadline.
+    pub const fn is_tsc_deadline(&self) -> bool {
+        let timer_mode = (self.lvt_timer_bits >> 17) & 0b11;
+        timer_mode == TimerMode::TscDeadline as _
+    }
+
     /// The timer interrupt vector number.
     pub const fn vector(&self) -> u8 {
         (self.lvt_timer_bits & 0xff) as u8
@@ -90,8 +102,22 @@ impl<H: AxvmHal> ApicTimer<H> {
         self.initial_count
     }
 
+    /// IA32_TSC_DEADLINE MSR. (SDM Vol. 3A, Section 10.5.4.1)
+    ///
+    /// Returns 0 if the timer is not in TSC-deadline mode, or is disarmed.
+    pub const fn tsc_deadline(&self) -> u64 {
+        if self.is_tsc_deadline() {
+            self.tsc_deadline
+        } else {
+            0
+        }
+    }
+
     /// Current Count Register.
     pub fn current_counter(&self) -> u32 {
+        if self.is_tsc_deadline() {
+            return 0; // the current-count register always reads 0 in TSC-deadline mode
+        }
         let elapsed_ns = H::current_time_nanos() - self.last_start_ns;
         let elapsed_cycles = (elapsed_ns / APIC_CYCLE_NANOS) >> self.divide_shift;
         if self.is_periodic() {
@@ -106,23 +132,53 @@ impl<H: AxvmHal> ApicTimer<H> {
     /// Set LVT Timer Register.
     pub fn set_lvt_timer(&mut self, bits: u32) -> AxResult {
         let timer_mode = bits.get_bits(17..19);
-        if timer_mode == TimerMode::TscDeadline as _ {
-            return ax_err!(Unsupported); // TSC deadline mode was not supported
-        } else if timer_mode == 0b11 {
+        if timer_mode == 0b11 {
             return ax_err!(InvalidInput); // reserved
         }
+        let was_tsc_deadline = self.is_tsc_deadline();
         self.lvt_timer_bits = bits;
-        self.start_timer();
+        if self.is_tsc_deadline() {
+            if !was_tsc_deadline {
+                // SDM Vol. 3A, Section 10.5.4.1: the timer is disarmed on mode transitions.
+                self.tsc_deadline = 0;
+                self.deadline_ns = 0;
+            }
+        } else {
+            self.tsc_deadline = 0;
+            self.start_timer();
+        }
         Ok(())
     }
 
     /// Set Initial Count Register.
     pub fn set_initial_count(&mut self, initial: u32) -> AxResult {
+        if self.is_tsc_deadline() {
+            return Ok(()); // ignored in TSC-deadline mode
+        }
         self.initial_count = initial;
         self.start_timer();
         Ok(())
     }
 
+    /// Set IA32_TSC_DEADLINE MSR, `deadline` is in the guest TSC domain. Writing 0
+    /// disarms the timer, and writes are ignored if not in TSC-deadline mode.
+    pub fn set_tsc_deadline(&mut self, deadline: u64) -> AxResult {
+        if self.is_tsc_deadline() {
+            self.tsc_deadline = deadline;
+            self.start_tsc_deadline_timer();
+        }
+        Ok(())
+    }
+
+    /// Set the offset between the guest TSC and the host TSC, as the
+    /// `TSC offset` field in VMCS.
+    pub(crate) fn set_tsc_offset(&mut self, offset: u64) {
+        self.tsc_offset = offset;
+        if self.is_tsc_deadline() {
+            self.start_tsc_deadline_timer();
+        }
+    }
+
     /// Set Divide Configuration Register.
     pub fn set_divide(&mut self, dcr: u32) -> AxResult {
         let shift = (dcr & 0b11) | ((dcr & 0b1000) >> 1);
@@ -135,6 +191,22 @@ impl<H: AxvmHal> ApicTimer<H> {
         (self.initial_count as u64 * APIC_CYCLE_NANOS) << self.divide_shift
     }
 
+    /// Convert the guest TSC deadline to the host time in nanoseconds.
+    fn start_tsc_deadline_timer(&mut self) {
+        if self.tsc_deadline == 0 {
+            self.deadline_ns = 0;
+            return;
+        }
+        let now_ns = H::current_time_nanos();
+        let guest_tsc = tsc::rdtsc().wrapping_add(self.tsc_offset);
+        let remaining_ns = if self.tsc_deadline > guest_tsc {
+            tsc::ticks_to_nanos::<H>(self.tsc_deadline - guest_tsc)
+        } else {
+            0 // already expired, fire on the next check
+        };
+        self.deadline_ns = now_ns.saturating_add(remaining_ns).max(1);
+    }
+
     fn start_timer(&mut self) {
         if self.initial_count != 0 {
             self.last_start_ns = H::current_time_nanos();
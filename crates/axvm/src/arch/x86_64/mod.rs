@@ -1,5 +1,6 @@
 mod lapic;
 pub(crate) mod msr;
+pub(crate) mod tsc;
 
 #[macro_use]
 pub(crate) mod regs;
@@ -9,10 +10,11 @@ cfg_if::cfg_if! {
         mod vmx;
         use vmx as vender;
         pub use vmx::{VmxExitInfo, VmxExitReason, VmxInterruptInfo, VmxIoExitInfo};
+        pub use vmx::{ApicVirtFeatures, PostedInterruptDesc, VirtApicPage, POSTED_INTERRUPT_VECTOR};
     }
 }
 
-pub(crate) use vender::{has_hardware_support, ArchPerCpuState};
+pub(crate) use vender::{has_hardware_support, max_nested_page_size, ArchPerCpuState};
 
 pub use lapic::ApicTimer;
 pub use regs::GeneralRegisters;
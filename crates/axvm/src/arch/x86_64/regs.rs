@@ -20,6 +20,56 @@ pub struct GeneralRegisters {
     pub r15: u64,
 }
 
+impl GeneralRegisters {
+    /// Get the value of the register with the given index in the instruction
+    /// encoding order (`RAX`, `RCX`, `RDX`, `RBX`, `RSP`, `RBP`, `RSI`, ...).
+    ///
+    /// `RSP` (index 4) is not saved here, read it from the VMCS instead.
+    pub fn get_reg_of_index(&self, index: u8) -> u64 {
+        match index {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            _ => panic!("Illegal general register index {}", index),
+        }
+    }
+
+    /// Set the value of the register with the given index in the instruction
+    /// encoding order. See [`GeneralRegisters::get_reg_of_index`].
+    pub fn set_reg_of_index(&mut self, index: u8, value: u64) {
+        match index {
+            0 => self.rax = value,
+            1 => self.rcx = value,
+            2 => self.rdx = value,
+            3 => self.rbx = value,
+            5 => self.rbp = value,
+            6 => self.rsi = value,
+            7 => self.rdi = value,
+            8 => self.r8 = value,
+            9 => self.r9 = value,
+            10 => self.r10 = value,
+            11 => self.r11 = value,
+            12 => self.r12 = value,
+            13 => self.r13 = value,
+            14 => self.r14 = value,
+            15 => self.r15 = value,
+            _ => panic!("Illegal general register index {}", index),
+        }
+    }
+}
+
 macro_rules! save_regs_to_stack {
     () => {
         "
@@ -63,3 +113,30 @@ macro_rules! restore_regs_from_stack {
         pop r15"
     };
 }
+
+/// Also keeps the host stack 16-byte aligned for the VM exit handler.
+macro_rules! save_host_callee_saved_regs {
+    () => {
+        "
+        push rbp
+        push rbx
+        push r12
+        push r13
+        push r14
+        push r15
+        sub rsp, 8"
+    };
+}
+
+macro_rules! restore_host_callee_saved_regs {
+    () => {
+        "
+        add rsp, 8
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop rbx
+        pop rbp"
+    };
+}
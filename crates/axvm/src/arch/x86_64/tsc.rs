@@ -0,0 +1,61 @@
+//! Time Stamp Counter. (SDM Vol. 3B, Section 18.17)
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use raw_cpuid::CpuId;
+
+use crate::AxvmHal;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+static TSC_FREQ_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Read the host time stamp counter.
+pub(crate) fn rdtsc() -> u64 {
+    unsafe { x86::time::rdtsc() }
+}
+
+/// The frequency of the host TSC in Hz.
+///
+/// It's enumerated by CPUID leaf 0x15 or 0x16 if possible, otherwise measured
+/// against [`AxvmHal::current_time_nanos`] on the first call.
+pub(crate) fn tsc_frequency_hz<H: AxvmHal>() -> u64 {
+    let freq = TSC_FREQ_HZ.load(Ordering::Relaxed);
+    if freq != 0 {
+        return freq;
+    }
+    let cpuid = CpuId::new();
+    let freq = cpuid
+        .get_tsc_info()
+        .and_then(|info| info.tsc_frequency())
+        .or_else(|| {
+            cpuid
+                .get_processor_frequency_info()
+                .map(|info| info.processor_base_frequency() as u64 * 1_000_000)
+                .filter(|&freq| freq != 0)
+        })
+        .unwrap_or_else(calibrate::<H>);
+    info!("[AxVM] TSC frequency: {} Hz", freq);
+    TSC_FREQ_HZ.store(freq, Ordering::Relaxed);
+    freq
+}
+
+/// Convert a number of TSC ticks to nanoseconds.
+pub(crate) fn ticks_to_nanos<H: AxvmHal>(ticks: u64) -> u64 {
+    let nanos = ticks as u128 * NANOS_PER_SEC / tsc_frequency_hz::<H>() as u128;
+    nanos.min(u64::MAX as u128) as u64
+}
+
+/// Measure the TSC frequency by spinning for 10 ms.
+fn calibrate<H: AxvmHal>() -> u64 {
+    const CALIBRATE_NANOS: u64 = 10_000_000;
+    let start_ns = H::current_time_nanos();
+    let start_tsc = rdtsc();
+    let mut end_ns = start_ns;
+    while end_ns - start_ns < CALIBRATE_NANOS {
+        core::hint::spin_loop();
+        end_ns = H::current_time_nanos();
+    }
+    let end_tsc = rdtsc();
+    ((end_tsc - start_tsc) as u128 * NANOS_PER_SEC / (end_ns - start_ns) as u128) as u64
+}
@@ -1,9 +1,10 @@
 use core::{convert::TryFrom, fmt};
 
 use bit_field::BitField;
-use page_table::{PageTable64, PagingMetaData};
+use page_table::{PageSize, PageTable64, PagingMetaData};
 use page_table_entry::{GenericPTE, MappingFlags};
 
+use crate::arch::msr::Msr;
 use crate::HostPhysAddr;
 
 bitflags::bitflags! {
@@ -171,3 +172,15 @@ impl const PagingMetaData for ExtendedPageTableMetadata {
 
 /// The VMX extended page table. (SDM Vol. 3C, Section 29.3)
 pub type ExtendedPageTable<I> = PageTable64<ExtendedPageTableMetadata, EPTEntry, I>;
+
+/// The largest EPT page size supported by the processor. (SDM Vol. 3D, Appendix A.10)
+pub fn max_page_size() -> PageSize {
+    let cap = Msr::IA32_VMX_EPT_VPID_CAP.read();
+    if cap.get_bit(17) {
+        PageSize::Size1G
+    } else if cap.get_bit(16) {
+        PageSize::Size2M
+    } else {
+        PageSize::Size4K
+    }
+}
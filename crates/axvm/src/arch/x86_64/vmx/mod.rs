@@ -15,7 +15,12 @@ use crate::hal::AxvmHal;
 use axerrno::{ax_err, ax_err_type, AxResult};
 
 pub use self::definitions::VmxExitReason;
-pub use self::ept::ExtendedPageTable as X64NestedPageTable;
+pub use self::ept::{
+    max_page_size as max_nested_page_size, ExtendedPageTable as X64NestedPageTable,
+};
+pub use self::structs::{
+    ApicVirtFeatures, PostedInterruptDesc, VirtApicPage, POSTED_INTERRUPT_VECTOR,
+};
 pub use self::vcpu::VmxVcpu as AxvmVcpu;
 pub use self::vmcs::{VmxExitInfo, VmxInterruptInfo, VmxIoExitInfo};
 pub use self::VmxPerCpuState as ArchPerCpuState;
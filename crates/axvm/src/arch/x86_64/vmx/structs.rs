@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use bit_field::BitField;
 use bitflags::bitflags;
 
@@ -96,6 +98,122 @@ impl<H: AxvmHal> MsrBitmap<H> {
     }
 }
 
+/// Virtual-APIC page in 4K size. (SDM Vol. 3C, Section 29.1)
+#[derive(Debug)]
+pub struct VirtApicPage<H: AxvmHal> {
+    frame: PhysFrame<H>,
+}
+
+impl<H: AxvmHal> VirtApicPage<H> {
+    pub fn new() -> AxResult<Self> {
+        Ok(Self {
+            frame: PhysFrame::alloc_zero()?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        self.frame.start_paddr()
+    }
+
+    /// Read the APIC register at index `reg` (the MMIO offset divided by 16).
+    pub fn read(&self, reg: u32) -> u32 {
+        let ptr = unsafe { self.frame.as_mut_ptr().add(reg as usize * 16) } as *const u32;
+        unsafe { ptr.read_volatile() }
+    }
+
+    /// Write the APIC register at index `reg` (the MMIO offset divided by 16).
+    pub fn write(&mut self, reg: u32, value: u32) {
+        let ptr = unsafe { self.frame.as_mut_ptr().add(reg as usize * 16) } as *mut u32;
+        unsafe { ptr.write_volatile(value) }
+    }
+
+    /// Write a 64-bit register at index `reg`, as the x2APIC ICR.
+    pub fn write_u64(&mut self, reg: u32, value: u64) {
+        let ptr = unsafe { self.frame.as_mut_ptr().add(reg as usize * 16) } as *mut u64;
+        unsafe { ptr.write_volatile(value) }
+    }
+}
+
+/// Host interrupt vector to notify a logical processor of posted interrupts.
+pub const POSTED_INTERRUPT_VECTOR: u8 = 0xf2;
+
+/// Posted-interrupt descriptor. (SDM Vol. 3C, Section 29.6, Table 29-1)
+#[derive(Debug, Default)]
+#[repr(C, align(64))]
+pub struct PostedInterruptDesc {
+    /// Posted-interrupt requests, one bit for each vector.
+    pir: [AtomicU64; 4],
+    /// Bit 0 is the outstanding notification (ON) bit.
+    control: AtomicU64,
+    _reserved: [u64; 3],
+}
+
+impl PostedInterruptDesc {
+    /// Post an interrupt request, returns true if a notification needs to be
+    /// sent, i.e. the ON bit was clear.
+    pub fn post(&self, vector: u8) -> bool {
+        self.pir[vector as usize / 64].fetch_or(1 << (vector % 64), Ordering::SeqCst);
+        self.control.fetch_or(1, Ordering::SeqCst) & 1 == 0
+    }
+
+    /// Take out all posted interrupt requests, and clear the ON bit.
+    pub fn take_requests(&self) -> [u64; 4] {
+        self.control.fetch_and(!1, Ordering::SeqCst);
+        core::array::from_fn(|i| self.pir[i].swap(0, Ordering::SeqCst))
+    }
+}
+
+bitflags! {
+    /// APIC virtualization features of VMX. (SDM Vol. 3C, Chapter 29)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ApicVirtFeatures: u32 {
+        /// Use TPR shadow, `CR8` and the x2APIC `TPR` MSR access the virtual-APIC page.
+        const TPR_SHADOW = 1 << 0;
+        /// APIC-register virtualization, x2APIC MSR reads access the virtual-APIC page.
+        const APIC_REGISTER_VIRT = 1 << 1;
+        /// Virtual-interrupt delivery, the processor evaluates and delivers
+        /// virtual interrupts, and virtualizes EOI and self-IPI writes.
+        const VIRT_INTR_DELIVERY = 1 << 2;
+        /// Posted-interrupt processing.
+        const POSTED_INTERRUPTS = 1 << 3;
+    }
+}
+
+impl ApicVirtFeatures {
+    /// Features supported by the processor, probed from the VMX capability MSRs.
+    /// (SDM Vol. 3D, Appendix A.3)
+    pub fn supported() -> Self {
+        use super::vmcs::controls::*;
+        let allowed1 = |msr: Msr| (msr.read() >> 32) as u32;
+        let cpu_ctrl = PrimaryControls::from_bits_truncate(allowed1(Msr::IA32_VMX_PROCBASED_CTLS));
+        let cpu_ctrl2 = if cpu_ctrl.contains(PrimaryControls::SECONDARY_CONTROLS) {
+            SecondaryControls::from_bits_truncate(allowed1(Msr::IA32_VMX_PROCBASED_CTLS2))
+        } else {
+            SecondaryControls::empty()
+        };
+        let pin_ctrl =
+            PinbasedControls::from_bits_truncate(allowed1(Msr::IA32_VMX_TRUE_PINBASED_CTLS));
+
+        let mut features = Self::empty();
+        if !cpu_ctrl.contains(PrimaryControls::USE_TPR_SHADOW)
+            || !cpu_ctrl2.contains(SecondaryControls::VIRTUALIZE_X2APIC)
+        {
+            return features;
+        }
+        features |= Self::TPR_SHADOW;
+        if cpu_ctrl2.contains(SecondaryControls::VIRTUALIZE_APIC_REGISTER) {
+            features |= Self::APIC_REGISTER_VIRT;
+        }
+        if cpu_ctrl2.contains(SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY) {
+            features |= Self::VIRT_INTR_DELIVERY;
+            if pin_ctrl.contains(PinbasedControls::POSTED_INTERRUPTS) {
+                features |= Self::POSTED_INTERRUPTS;
+            }
+        }
+        features
+    }
+}
+
 /// Reporting Register of Basic VMX Capabilities. (SDM Vol. 3D, Appendix A.1)
 #[derive(Debug)]
 pub struct VmxBasic {
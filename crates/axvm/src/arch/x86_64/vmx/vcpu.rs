@@ -9,14 +9,17 @@ use x86::segmentation::SegmentSelector;
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
 
 use super::as_axerr;
-use super::structs::{MsrBitmap, VmxRegion};
+use super::structs::{
+    ApicVirtFeatures, MsrBitmap, PostedInterruptDesc, VirtApicPage, VmxRegion,
+    POSTED_INTERRUPT_VECTOR,
+};
 use super::vmcs::{
-    self, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16, VmcsGuest32, VmcsGuest64,
-    VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
+    self, VmcsControl16, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16, VmcsGuest32,
+    VmcsGuest64, VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
 };
 use super::VmxPerCpuState;
-use crate::arch::{msr::Msr, ApicTimer, GeneralRegisters};
-use crate::{AxvmHal, GuestPhysAddr, HostPhysAddr, NestedPageFaultInfo};
+use crate::arch::{msr::Msr, tsc, ApicTimer, GeneralRegisters};
+use crate::{AxvmHal, GuestPhysAddr, HostPhysAddr, HostVirtAddr, NestedPageFaultInfo};
 use axerrno::AxResult;
 
 /// A virtual CPU within a guest.
@@ -24,25 +27,36 @@ use axerrno::AxResult;
 pub struct VmxVcpu<H: AxvmHal> {
     guest_regs: GeneralRegisters,
     host_stack_top: u64,
+    vcpu_id: usize,
+    launched: bool,
+    stop_requested: bool,
     vmcs: VmxRegion<H>,
     msr_bitmap: MsrBitmap<H>,
     apic_timer: ApicTimer<H>,
     pending_events: VecDeque<(u8, Option<u32>)>,
+    apic_virt: ApicVirtFeatures,
+    virt_apic_page: Option<VirtApicPage<H>>,
 }
 
 impl<H: AxvmHal> VmxVcpu<H> {
     pub(crate) fn new(
         percpu: &VmxPerCpuState<H>,
+        vcpu_id: usize,
         entry: GuestPhysAddr,
         ept_root: HostPhysAddr,
     ) -> AxResult<Self> {
         let mut vcpu = Self {
             guest_regs: GeneralRegisters::default(),
             host_stack_top: 0,
+            vcpu_id,
+            launched: false,
+            stop_requested: false,
             vmcs: VmxRegion::new(percpu.vmcs_revision_id, false)?,
             msr_bitmap: MsrBitmap::passthrough_all()?,
             apic_timer: ApicTimer::new(),
             pending_events: VecDeque::with_capacity(8),
+            apic_virt: ApicVirtFeatures::empty(),
+            virt_apic_page: None,
         };
         vcpu.setup_msr_bitmap()?;
         vcpu.setup_vmcs(entry, ept_root)?;
@@ -50,12 +64,32 @@ impl<H: AxvmHal> VmxVcpu<H> {
         Ok(vcpu)
     }
 
-    /// Run the guest, never return.
-    pub fn run(&mut self) -> ! {
+    /// Run the guest, returns after [`request_stop`](Self::request_stop) is
+    /// called in the VM exit handler.
+    pub fn run(&mut self) {
         VmcsHostNW::RSP
             .write(&self.host_stack_top as *const _ as usize)
             .unwrap();
-        unsafe { self.vmx_launch() }
+        self.stop_requested = false;
+        unsafe {
+            if self.launched {
+                self.vmx_resume()
+            } else {
+                self.launched = true;
+                self.vmx_launch()
+            }
+        }
+    }
+
+    /// Stop running the guest after the current VM exit is handled, making
+    /// [`run`](Self::run) return.
+    pub fn request_stop(&mut self) {
+        self.stop_requested = true;
+    }
+
+    /// The ID of this vCPU.
+    pub fn vcpu_id(&self) -> usize {
+        self.vcpu_id
     }
 
     /// Information for VM exits due to external interrupts.
@@ -68,6 +102,12 @@ impl<H: AxvmHal> VmxVcpu<H> {
         vmcs::io_exit_info()
     }
 
+    /// The guest linear address of the memory operand and the address size in
+    /// bytes, for VM exits due to INS/OUTS instructions.
+    pub fn string_io_operand(&self) -> AxResult<(usize, u8)> {
+        vmcs::string_io_operand()
+    }
+
     /// Basic information about VM exits.
     pub fn exit_info(&self) -> AxResult<vmcs::VmxExitInfo> {
         vmcs::exit_info()
@@ -78,6 +118,12 @@ impl<H: AxvmHal> VmxVcpu<H> {
         vmcs::ept_violation_info()
     }
 
+    /// Invalidate the cached nested page table translations of this vCPU on the
+    /// current CPU, must be called after the nested page table is modified.
+    pub fn flush_nested_tlb(&self) -> AxResult {
+        vmcs::flush_ept()
+    }
+
     /// Guest general-purpose registers.
     pub fn regs(&self) -> &GeneralRegisters {
         &self.guest_regs
@@ -98,6 +144,46 @@ impl<H: AxvmHal> VmxVcpu<H> {
         VmcsGuestNW::RSP.write(rsp).unwrap()
     }
 
+    /// Guest instruction pointer. (`RIP`)
+    pub fn rip(&self) -> usize {
+        VmcsGuestNW::RIP.read().unwrap()
+    }
+
+    /// Guest flags register. (`RFLAGS`)
+    pub fn rflags(&self) -> usize {
+        VmcsGuestNW::RFLAGS.read().unwrap()
+    }
+
+    /// Guest control register `CR0`.
+    pub fn cr0(&self) -> usize {
+        VmcsGuestNW::CR0.read().unwrap()
+    }
+
+    /// Guest control register `CR3`.
+    pub fn cr3(&self) -> usize {
+        VmcsGuestNW::CR3.read().unwrap()
+    }
+
+    /// Guest control register `CR4`.
+    pub fn cr4(&self) -> usize {
+        VmcsGuestNW::CR4.read().unwrap()
+    }
+
+    /// Guest `IA32_EFER` MSR.
+    pub fn efer(&self) -> u64 {
+        VmcsGuest64::IA32_EFER.read().unwrap()
+    }
+
+    /// Guest `CS` segment base address.
+    pub fn cs_base(&self) -> usize {
+        VmcsGuestNW::CS_BASE.read().unwrap()
+    }
+
+    /// Guest `CS` segment access rights. (SDM Vol. 3C, Section 24.4.1, Table 24-2)
+    pub fn cs_access_rights(&self) -> u32 {
+        VmcsGuest32::CS_ACCESS_RIGHTS.read().unwrap()
+    }
+
     /// Advance guest `RIP` by `instr_len` bytes.
     pub fn advance_rip(&mut self, instr_len: u8) -> AxResult {
         Ok(VmcsGuestNW::RIP
@@ -105,12 +191,126 @@ impl<H: AxvmHal> VmxVcpu<H> {
             .map_err(as_axerr)?)
     }
 
+    /// Put the guest in the state after power-on or RESET, fetching the first
+    /// instruction at the reset vector 0xFFFFFFF0 with `CS` selector 0xF000 and
+    /// base 0xFFFF0000 in real mode. (SDM Vol. 3A, Section 10.1.4)
+    pub fn set_reset_state(&mut self) -> AxResult {
+        VmcsGuest16::CS_SELECTOR.write(0xf000).map_err(as_axerr)?;
+        VmcsGuestNW::CS_BASE.write(0xffff_0000).map_err(as_axerr)?;
+        VmcsGuestNW::RIP.write(0xfff0).map_err(as_axerr)?;
+        VmcsGuestNW::RFLAGS.write(0x2).map_err(as_axerr)?;
+        Ok(())
+    }
+
+    /// Switch the guest to 32-bit protected mode with flat 4 GiB segments and
+    /// paging disabled, starting at `entry`. This is the machine state that a
+    /// Multiboot boot loader leaves. (Multiboot Specification, Section 3.2)
+    pub fn set_flat_protected_mode(&mut self, entry: GuestPhysAddr) -> AxResult {
+        let cr0 =
+            Cr0Flags::PROTECTED_MODE_ENABLE | Cr0Flags::EXTENSION_TYPE | Cr0Flags::NUMERIC_ERROR;
+        VmcsGuestNW::CR0.write(cr0.bits() as _).map_err(as_axerr)?;
+        // 32-bit, 4K granularity, present, code, exec/read, accessed
+        Self::set_flat_segments(0, 0, 0xc09b)?;
+        VmcsGuestNW::RIP.write(entry).map_err(as_axerr)?;
+        VmcsGuestNW::RFLAGS.write(0x2).map_err(as_axerr)?;
+        Ok(())
+    }
+
+    /// Switch the guest to 64-bit mode with paging enabled, starting at `entry`.
+    /// `cr3` is the root of the guest page table, which must identity map the
+    /// code, and `gdt` is the base and limit of a GDT in which `code_sel` and
+    /// `data_sel` are flat 64-bit code and data segments. (SDM Vol. 3A, Section 10.8.5)
+    pub fn set_long_mode(
+        &mut self,
+        entry: GuestPhysAddr,
+        cr3: GuestPhysAddr,
+        gdt: (GuestPhysAddr, u16),
+        code_sel: u16,
+        data_sel: u16,
+    ) -> AxResult {
+        use x86_64::registers::model_specific::EferFlags;
+        let cr0 = Cr0Flags::PROTECTED_MODE_ENABLE
+            | Cr0Flags::EXTENSION_TYPE
+            | Cr0Flags::NUMERIC_ERROR
+            | Cr0Flags::PAGING;
+        let cr4 = Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS | Cr4Flags::PHYSICAL_ADDRESS_EXTENSION;
+        let efer = EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE;
+        VmcsGuestNW::CR0.write(cr0.bits() as _).map_err(as_axerr)?;
+        VmcsGuestNW::CR4.write(cr4.bits() as _).map_err(as_axerr)?;
+        VmcsGuestNW::CR3.write(cr3).map_err(as_axerr)?;
+        VmcsGuest64::IA32_EFER
+            .write(efer.bits())
+            .map_err(as_axerr)?;
+        VmcsGuestNW::GDTR_BASE.write(gdt.0).map_err(as_axerr)?;
+        VmcsGuest32::GDTR_LIMIT
+            .write(gdt.1 as _)
+            .map_err(as_axerr)?;
+        // 64-bit, 4K granularity, present, code, exec/read, accessed
+        Self::set_flat_segments(code_sel, data_sel, 0xa09b)?;
+
+        use super::vmcs::controls::EntryControls as EntryCtrl;
+        vmcs::set_control(
+            VmcsControl32::VMENTRY_CONTROLS,
+            Msr::IA32_VMX_TRUE_ENTRY_CTLS,
+            VmcsControl32::VMENTRY_CONTROLS.read().map_err(as_axerr)?,
+            EntryCtrl::IA32E_MODE_GUEST.bits(),
+            0,
+        )?;
+        VmcsGuestNW::RIP.write(entry).map_err(as_axerr)?;
+        VmcsGuestNW::RFLAGS.write(0x2).map_err(as_axerr)?;
+        Ok(())
+    }
+
+    /// Set up flat 4 GiB segments, with `code_ar` as the access rights of `CS`.
+    fn set_flat_segments(code_sel: u16, data_sel: u16, code_ar: u32) -> AxResult {
+        macro_rules! set_flat_segment {
+            ($seg: ident, $selector: expr, $access_rights: expr) => {{
+                use VmcsGuest16::*;
+                use VmcsGuest32::*;
+                use VmcsGuestNW::*;
+                concat_idents!($seg, _SELECTOR)
+                    .write($selector)
+                    .map_err(as_axerr)?;
+                concat_idents!($seg, _BASE).write(0).map_err(as_axerr)?;
+                concat_idents!($seg, _LIMIT)
+                    .write(0xffff_ffff)
+                    .map_err(as_axerr)?;
+                concat_idents!($seg, _ACCESS_RIGHTS)
+                    .write($access_rights)
+                    .map_err(as_axerr)?;
+            }};
+        }
+
+        set_flat_segment!(CS, code_sel, code_ar);
+        // 32-bit, 4K granularity, present, data, read/write, accessed
+        set_flat_segment!(ES, data_sel, 0xc093);
+        set_flat_segment!(SS, data_sel, 0xc093);
+        set_flat_segment!(DS, data_sel, 0xc093);
+        set_flat_segment!(FS, data_sel, 0xc093);
+        set_flat_segment!(GS, data_sel, 0xc093);
+        Ok(())
+    }
+
     /// Add a virtual interrupt or exception to the pending events list,
     /// and try to inject it before later VM entries.
     pub fn inject_event(&mut self, vector: u8, err_code: Option<u32>) {
         self.pending_events.push_back((vector, err_code));
     }
 
+    /// Whether there are events waiting to be injected.
+    pub fn has_pending_events(&self) -> bool {
+        !self.pending_events.is_empty()
+    }
+
+    /// Whether the guest can accept external interrupts, i.e. `RFLAGS.IF` = 1
+    /// and there are no other blocking of interrupts.
+    pub fn allow_interrupt(&self) -> bool {
+        let rflags = VmcsGuestNW::RFLAGS.read().unwrap();
+        let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read().unwrap();
+        rflags as u64 & x86_64::registers::rflags::RFlags::INTERRUPT_FLAG.bits() != 0
+            && block_state == 0
+    }
+
     /// If enable, a VM exit occurs at the beginning of any instruction if
     /// `RFLAGS.IF` = 1 and there are no other blocking of interrupts.
     /// (see SDM, Vol. 3C, Section 24.4.2)
@@ -130,10 +330,215 @@ impl<H: AxvmHal> VmxVcpu<H> {
         Ok(())
     }
 
+    /// Returns the reference of [`ApicTimer`].
+    pub fn apic_timer(&self) -> &ApicTimer<H> {
+        &self.apic_timer
+    }
+
     /// Returns the mutable reference of [`ApicTimer`].
     pub fn apic_timer_mut(&mut self) -> &mut ApicTimer<H> {
         &mut self.apic_timer
     }
+
+    /// Guest time stamp counter, which is the host TSC plus the `TSC offset`
+    /// field in VMCS. (SDM Vol. 3C, Section 25.3)
+    pub fn guest_tsc(&self) -> AxResult<u64> {
+        let offset = VmcsControl64::TSC_OFFSET.read().map_err(as_axerr)?;
+        Ok(tsc::rdtsc().wrapping_add(offset))
+    }
+
+    /// Set the guest time stamp counter, as the guest writes IA32_TIME_STAMP_COUNTER.
+    pub fn set_guest_tsc(&mut self, value: u64) -> AxResult {
+        let offset = value.wrapping_sub(tsc::rdtsc());
+        VmcsControl64::TSC_OFFSET.write(offset).map_err(as_axerr)?;
+        self.apic_timer.set_tsc_offset(offset);
+        Ok(())
+    }
+
+    /// Enable the APIC virtualization features in `features` which are supported by
+    /// the processor, returns the enabled ones. The hypervisor should keep its local
+    /// APIC states in sync with the virtual-APIC page afterwards.
+    ///
+    /// If [`ApicVirtFeatures::VIRT_INTR_DELIVERY`] is enabled, pending interrupts are
+    /// delivered by setting RVI (see [`Self::set_guest_interrupt_status`]) instead of
+    /// [`Self::inject_event`]. Posted interrupts are enabled only if `pi_desc` is given.
+    pub fn enable_apic_virtualization(
+        &mut self,
+        features: ApicVirtFeatures,
+        pi_desc: Option<&'static PostedInterruptDesc>,
+    ) -> AxResult<ApicVirtFeatures> {
+        let mut features = features & ApicVirtFeatures::supported();
+        if !features.contains(ApicVirtFeatures::TPR_SHADOW) {
+            features = ApicVirtFeatures::empty(); // all others depend on TPR shadow
+        }
+        if !features.contains(ApicVirtFeatures::VIRT_INTR_DELIVERY) || pi_desc.is_none() {
+            features.remove(ApicVirtFeatures::POSTED_INTERRUPTS);
+        }
+        if features.is_empty() {
+            return Ok(features);
+        }
+
+        use super::vmcs::controls::*;
+        let page = VirtApicPage::new()?;
+        VmcsControl64::VIRT_APIC_ADDR
+            .write(page.phys_addr().as_usize() as _)
+            .map_err(as_axerr)?;
+        VmcsControl32::TPR_THRESHOLD.write(0).map_err(as_axerr)?;
+        // Use TPR shadow, MOV to/from CR8 access the virtual-APIC page.
+        vmcs::set_control(
+            VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
+            VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS
+                .read()
+                .map_err(as_axerr)?,
+            PrimaryControls::USE_TPR_SHADOW.bits(),
+            (PrimaryControls::CR8_LOAD_EXITING | PrimaryControls::CR8_STORE_EXITING).bits(),
+        )?;
+
+        let mut cpu_ctrl2 = SecondaryControls::empty();
+        if features.contains(ApicVirtFeatures::APIC_REGISTER_VIRT) {
+            cpu_ctrl2 |= SecondaryControls::VIRTUALIZE_APIC_REGISTER;
+        }
+        if features.contains(ApicVirtFeatures::VIRT_INTR_DELIVERY) {
+            cpu_ctrl2 |= SecondaryControls::VIRTUAL_INTERRUPT_DELIVERY;
+            VmcsGuest16::INTERRUPT_STATUS.write(0).map_err(as_axerr)?;
+            self.set_eoi_exit_bitmap([0; 4])?;
+        }
+        vmcs::set_control(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+                .read()
+                .map_err(as_axerr)?,
+            cpu_ctrl2.bits(),
+            0,
+        )?;
+
+        if let Some(desc) =
+            pi_desc.filter(|_| features.contains(ApicVirtFeatures::POSTED_INTERRUPTS))
+        {
+            let desc_paddr = H::virt_to_phys(HostVirtAddr::from(desc as *const _ as usize));
+            VmcsControl16::POSTED_INTERRUPT_NOTIFICATION_VECTOR
+                .write(POSTED_INTERRUPT_VECTOR as _)
+                .map_err(as_axerr)?;
+            VmcsControl64::POSTED_INTERRUPT_DESC_ADDR
+                .write(desc_paddr.as_usize() as _)
+                .map_err(as_axerr)?;
+            vmcs::set_control(
+                VmcsControl32::PINBASED_EXEC_CONTROLS,
+                Msr::IA32_VMX_TRUE_PINBASED_CTLS,
+                VmcsControl32::PINBASED_EXEC_CONTROLS
+                    .read()
+                    .map_err(as_axerr)?,
+                PinbasedControls::POSTED_INTERRUPTS.bits(),
+                0,
+            )?;
+        }
+
+        self.apic_virt = features;
+        self.virt_apic_page = Some(page);
+        info!("[AxVM] APIC virtualization enabled: {:?}", features);
+        Ok(features)
+    }
+
+    /// The enabled APIC virtualization features.
+    pub fn apic_virt_features(&self) -> ApicVirtFeatures {
+        self.apic_virt
+    }
+
+    /// The virtual-APIC page, if APIC virtualization is enabled.
+    pub fn virt_apic_page(&self) -> Option<&VirtApicPage<H>> {
+        self.virt_apic_page.as_ref()
+    }
+
+    /// The mutable virtual-APIC page, if APIC virtualization is enabled.
+    pub fn virt_apic_page_mut(&mut self) -> Option<&mut VirtApicPage<H>> {
+        self.virt_apic_page.as_mut()
+    }
+
+    /// Enable or disable the virtualization of x2APIC MSR accesses, should be called
+    /// as the guest enters or leaves x2APIC mode. (SDM Vol. 3C, Section 29.5)
+    pub fn set_x2apic_virtualization(&mut self, enable: bool) -> AxResult {
+        if self.apic_virt.is_empty() {
+            return Ok(());
+        }
+        let mut ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+            .read()
+            .map_err(as_axerr)?;
+        let bits = vmcs::controls::SecondaryControls::VIRTUALIZE_X2APIC.bits();
+        if enable {
+            ctrl |= bits
+        } else {
+            ctrl &= !bits
+        }
+        VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+            .write(ctrl)
+            .map_err(as_axerr)?;
+
+        const TPR: u32 = 0x808;
+        const EOI: u32 = 0x80b;
+        const SELF_IPI: u32 = 0x83f;
+        self.msr_bitmap.set_read_intercept(TPR, !enable);
+        self.msr_bitmap.set_write_intercept(TPR, !enable);
+        if self
+            .apic_virt
+            .contains(ApicVirtFeatures::APIC_REGISTER_VIRT)
+        {
+            // SDM Vol. 3C, Section 29.5: reads of these MSRs are virtualized if not
+            // intercepted. The current count register (0x839) is always emulated.
+            let virtualized_regs = [0x802, 0x803, 0x80a, 0x80d, 0x80f]
+                .into_iter()
+                .chain(0x810..=0x828)
+                .chain([0x82f, 0x830])
+                .chain(0x832..=0x838)
+                .chain([0x83e]);
+            for msr in virtualized_regs {
+                self.msr_bitmap.set_read_intercept(msr, !enable);
+            }
+        }
+        if self
+            .apic_virt
+            .contains(ApicVirtFeatures::VIRT_INTR_DELIVERY)
+        {
+            self.msr_bitmap.set_write_intercept(EOI, !enable);
+            self.msr_bitmap.set_write_intercept(SELF_IPI, !enable);
+        }
+        Ok(())
+    }
+
+    /// Set the guest interrupt status, which is composed of the requesting virtual
+    /// interrupt (RVI) and the servicing virtual interrupt (SVI). (SDM Vol. 3C, Section 29.1.1)
+    pub fn set_guest_interrupt_status(&mut self, rvi: u8, svi: u8) -> AxResult {
+        VmcsGuest16::INTERRUPT_STATUS
+            .write(rvi as u16 | (svi as u16) << 8)
+            .map_err(as_axerr)
+    }
+
+    /// Set the EOI-exit bitmap, virtualized EOIs of these vectors cause VM exits.
+    /// (SDM Vol. 3C, Section 24.6.8)
+    pub fn set_eoi_exit_bitmap(&mut self, bitmap: [u64; 4]) -> AxResult {
+        VmcsControl64::EOI_EXIT0
+            .write(bitmap[0])
+            .map_err(as_axerr)?;
+        VmcsControl64::EOI_EXIT1
+            .write(bitmap[1])
+            .map_err(as_axerr)?;
+        VmcsControl64::EOI_EXIT2
+            .write(bitmap[2])
+            .map_err(as_axerr)?;
+        VmcsControl64::EOI_EXIT3
+            .write(bitmap[3])
+            .map_err(as_axerr)?;
+        Ok(())
+    }
+
+    /// Set the TPR threshold, a VM exit occurs if the guest reduces `VTPR[7:4]`
+    /// below it. (SDM Vol. 3C, Section 29.1.2)
+    pub fn set_tpr_threshold(&mut self, threshold: u8) -> AxResult {
+        VmcsControl32::TPR_THRESHOLD
+            .write(threshold as u32 & 0xf)
+            .map_err(as_axerr)
+    }
 }
 
 // Implementation of private methods
@@ -148,6 +553,13 @@ impl<H: AxvmHal> VmxVcpu<H> {
             self.msr_bitmap.set_read_intercept(msr, true);
             self.msr_bitmap.set_write_intercept(msr, true);
         }
+        // Intercept IA32_TSC_DEADLINE MSR accesses
+        let msr = x86::msr::IA32_TSC_DEADLINE;
+        self.msr_bitmap.set_read_intercept(msr, true);
+        self.msr_bitmap.set_write_intercept(msr, true);
+        // Intercept IA32_TIME_STAMP_COUNTER MSR writes, reads use TSC offsetting
+        self.msr_bitmap
+            .set_write_intercept(x86::msr::IA32_TIME_STAMP_COUNTER, true);
         Ok(())
     }
 
@@ -335,14 +747,17 @@ impl<H: AxvmHal> VmxVcpu<H> {
             0,
         )?;
 
-        // Intercept all I/O instructions, use MSR bitmaps, activate secondary controls,
-        // disable CR3 load/store interception.
+        // Intercept all I/O instructions, use MSR bitmaps, use TSC offsetting, activate
+        // secondary controls, disable CR3 load/store interception.
         use PrimaryControls as CpuCtrl;
         vmcs::set_control(
             VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
             Msr::IA32_VMX_PROCBASED_CTLS.read() as u32,
-            (CpuCtrl::UNCOND_IO_EXITING | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SECONDARY_CONTROLS)
+            (CpuCtrl::UNCOND_IO_EXITING
+                | CpuCtrl::USE_MSR_BITMAPS
+                | CpuCtrl::USE_TSC_OFFSETTING
+                | CpuCtrl::SECONDARY_CONTROLS)
                 .bits(),
             (CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING).bits(),
         )?;
@@ -404,6 +819,7 @@ impl<H: AxvmHal> VmxVcpu<H> {
         VmcsControl32::EXCEPTION_BITMAP.write(0).map_err(as_axerr)?;
         VmcsControl64::IO_BITMAP_A_ADDR.write(0).map_err(as_axerr)?;
         VmcsControl64::IO_BITMAP_B_ADDR.write(0).map_err(as_axerr)?;
+        VmcsControl64::TSC_OFFSET.write(0).map_err(as_axerr)?;
         VmcsControl64::MSR_BITMAPS_ADDR
             .write(self.msr_bitmap.phys_addr().as_usize() as _)
             .map_err(as_axerr)?;
@@ -411,8 +827,9 @@ impl<H: AxvmHal> VmxVcpu<H> {
     }
 
     #[naked]
-    unsafe extern "C" fn vmx_launch(&mut self) -> ! {
+    unsafe extern "C" fn vmx_launch(&mut self) {
         asm!(
+            save_host_callee_saved_regs!(),
             "mov    [rdi + {host_stack_top}], rsp", // save current RSP to Vcpu::host_stack_top
             "mov    rsp, rdi",                      // set RSP to guest regs area
             restore_regs_from_stack!(),
@@ -424,6 +841,21 @@ impl<H: AxvmHal> VmxVcpu<H> {
         )
     }
 
+    #[naked]
+    unsafe extern "C" fn vmx_resume(&mut self) {
+        asm!(
+            save_host_callee_saved_regs!(),
+            "mov    [rdi + {host_stack_top}], rsp", // save current RSP to Vcpu::host_stack_top
+            "mov    rsp, rdi",                      // set RSP to guest regs area
+            restore_regs_from_stack!(),
+            "vmresume",
+            "jmp    {failed}",
+            host_stack_top = const size_of::<GeneralRegisters>(),
+            failed = sym Self::vmx_entry_failed,
+            options(noreturn),
+        )
+    }
+
     #[naked]
     unsafe extern "C" fn vmx_exit(&mut self) -> ! {
         asm!(
@@ -432,10 +864,15 @@ impl<H: AxvmHal> VmxVcpu<H> {
             "mov    rdi, rsp",                      // set the first arg to &Vcpu
             "mov    rsp, [rsp + {host_stack_top}]", // set RSP to Vcpu::host_stack_top
             "call   {vmexit_handler}",              // call vmexit_handler
+            "test   al, al",                        // stop if it returns false
+            "jz     1f",
             "mov    rsp, r15",                      // load temporary RSP from r15
             restore_regs_from_stack!(),
             "vmresume",
             "jmp    {failed}",
+            "1:",
+            restore_host_callee_saved_regs!(),
+            "ret",                                  // return from vmx_launch/vmx_resume
             host_stack_top = const size_of::<GeneralRegisters>(),
             vmexit_handler = sym Self::vmexit_handler,
             failed = sym Self::vmx_entry_failed,
@@ -447,14 +884,6 @@ impl<H: AxvmHal> VmxVcpu<H> {
         panic!("{}", vmcs::instruction_error().as_str())
     }
 
-    /// Whether the guest interrupts are blocked. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
-    fn allow_interrupt(&self) -> bool {
-        let rflags = VmcsGuestNW::RFLAGS.read().unwrap();
-        let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read().unwrap();
-        rflags as u64 & x86_64::registers::rflags::RFlags::INTERRUPT_FLAG.bits() != 0
-            && block_state == 0
-    }
-
     /// Try to inject a pending event before next VM entry.
     fn check_pending_events(&mut self) -> AxResult {
         if let Some(event) = self.pending_events.front() {
@@ -470,13 +899,14 @@ impl<H: AxvmHal> VmxVcpu<H> {
         Ok(())
     }
 
-    fn vmexit_handler(&mut self) {
+    /// Returns whether to continue running the guest.
+    extern "C" fn vmexit_handler(&mut self) -> bool {
         H::vmexit_handler(self);
-        // Check if there is an APIC timer interrupt
-        if self.apic_timer.check_interrupt() {
-            self.inject_event(self.apic_timer.vector(), None);
+        if self.stop_requested {
+            return false;
         }
         self.check_pending_events().unwrap();
+        true
     }
 }
 
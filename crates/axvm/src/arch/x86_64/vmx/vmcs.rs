@@ -600,6 +600,14 @@ pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> AxResult {
     Ok(())
 }
 
+/// Invalidates the cached EPT translations of the current EPT pointer.
+pub fn flush_ept() -> AxResult {
+    use super::instructions::{invept, InvEptType};
+    let eptp = VmcsControl64::EPTP.read().map_err(as_axerr)?;
+    unsafe { invept(InvEptType::SingleContext, eptp).map_err(as_axerr)? };
+    Ok(())
+}
+
 pub fn instruction_error() -> VmxInstructionError {
     VmcsReadOnly32::VM_INSTRUCTION_ERROR.read().unwrap().into()
 }
@@ -682,6 +690,22 @@ pub fn io_exit_info() -> AxResult<VmxIoExitInfo> {
     })
 }
 
+/// The guest linear address of the memory operand and the address size in bytes
+/// of the INS/OUTS instruction that caused the VM exit. (SDM Vol. 3C, Section 27.2.5)
+pub fn string_io_operand() -> AxResult<(usize, u8)> {
+    let linear_addr = VmcsReadOnlyNW::GUEST_LINEAR_ADDR.read().map_err(as_axerr)?;
+    let info = VmcsReadOnly32::VMEXIT_INSTRUCTION_INFO
+        .read()
+        .map_err(as_axerr)?;
+    let address_size = match info.get_bits(7..10) {
+        0 => 2,
+        1 => 4,
+        2 => 8,
+        _ => return ax_err!(BadState, "invalid address size of INS/OUTS"),
+    };
+    Ok((linear_addr, address_size))
+}
+
 pub fn ept_violation_info() -> AxResult<NestedPageFaultInfo> {
     // SDM Vol. 3C, Section 27.2.1, Table 27-7
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION
@@ -15,6 +15,7 @@ pub mod arch;
 
 use arch::ArchPerCpuState;
 use axerrno::{ax_err, AxResult};
+use page_table::PageSize;
 
 pub use arch::AxvmVcpu;
 pub use hal::AxvmHal;
@@ -26,9 +27,14 @@ pub fn has_hardware_support() -> bool {
     arch::has_hardware_support()
 }
 
+/// The largest page size of the nested page table supported by the hardware.
+pub fn max_nested_page_size() -> PageSize {
+    arch::max_nested_page_size()
+}
+
 /// Host per-CPU states to run the guest. All methods must be called on the corresponding CPU.
 pub struct AxvmPerCpu<H: AxvmHal> {
-    _cpu_id: usize,
+    cpu_id: usize,
     arch: ArchPerCpuState<H>,
 }
 
@@ -36,7 +42,7 @@ impl<H: AxvmHal> AxvmPerCpu<H> {
     /// Create an uninitialized instance.
     pub fn new(cpu_id: usize) -> Self {
         Self {
-            _cpu_id: cpu_id,
+            cpu_id,
             arch: ArchPerCpuState::new(),
         }
     }
@@ -57,7 +63,7 @@ impl<H: AxvmHal> AxvmPerCpu<H> {
     }
 
     /// Create a [`AxvmVcpu`], set the entry point to `entry`, set the nested
-    /// page table root to `npt_root`.
+    /// page table root to `npt_root`. The vCPU ID is the same as the CPU ID.
     pub fn create_vcpu(
         &self,
         entry: GuestPhysAddr,
@@ -66,7 +72,7 @@ impl<H: AxvmHal> AxvmPerCpu<H> {
         if !self.is_enabled() {
             ax_err!(BadState, "virtualization is not enabled")
         } else {
-            AxvmVcpu::new(&self.arch, entry, npt_root)
+            AxvmVcpu::new(&self.arch, self.cpu_id, entry, npt_root)
         }
     }
 }